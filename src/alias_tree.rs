@@ -1,13 +1,28 @@
+use std::ops::Range;
 use std::vec::Vec;
 
+// Masks are added to whole ranges lazily. Where a range covers everything
+// beneath a node, the mask is added to the node and left pending there,
+// rather than added to every item. Pending masks are pushed down to the
+// node's children when anything beneath it is changed on its own. Each
+// node's value includes its own pending mask (and any beneath it), but not
+// those of the nodes above it.
+//
+// A pending mask is a single T per node above the items (one byte for u8),
+// as trees over large devices are locked into memory. T::default() must be
+// the identity for |, meaning nothing is pending.
+
 pub struct AliasTree<T> {
     // size: usize,
     levels: Vec<Vec<T>>,
+    // Parallel to levels. Items (level 0) never have pending masks.
+    pending: Vec<Vec<T>>,
 }
 
-impl<T: Clone + std::ops::BitOr<Output=T> + std::ops::BitAnd<Output=T> + std::cmp::PartialEq> AliasTree<T> {
+impl<T: Clone + Default + std::ops::BitOr<Output=T> + std::ops::BitAnd<Output=T> + std::cmp::PartialEq> AliasTree<T> {
     pub fn new(size: usize, init: T) -> Self {
         let mut levels: Vec<Vec<T>> = Vec::new();
+        let mut pending: Vec<Vec<T>> = Vec::new();
 
         let mut level_size: usize = size;
         let mut level_init = init;
@@ -18,6 +33,7 @@ impl<T: Clone + std::ops::BitOr<Output=T> + std::ops::BitAnd<Output=T> + std::cm
                 level.push(level_init.clone());
             }
             levels.push(level);
+            pending.push(if pending.is_empty() {Vec::new()} else {vec![T::default(); level_size]});
             level_init = level_init.clone() | level_init.clone();
             level_size /= 2;
         }
@@ -25,6 +41,64 @@ impl<T: Clone + std::ops::BitOr<Output=T> + std::ops::BitAnd<Output=T> + std::cm
         AliasTree{
             // size,
             levels,
+            pending,
+        }
+    }
+
+    fn parent(&self, level: usize, index: usize) -> Option<(usize, usize)> {
+        if level + 1 < self.levels.len() && index / 2 < self.levels[level + 1].len() {
+            Some((level + 1, index / 2))
+        } else {
+            None
+        }
+    }
+
+    fn pending_at(&self, level: usize, index: usize) -> T {
+        if level == 0 {
+            T::default()
+        } else {
+            self.pending[level][index].clone()
+        }
+    }
+
+    /// The value of a node, including the masks pending above it.
+    fn value(&self, level: usize, index: usize) -> T {
+        let mut value = self.levels[level][index].clone();
+        let mut node = (level, index);
+        while let Some((parent_level, parent_index)) = self.parent(node.0, node.1) {
+            value = value | self.pending[parent_level][parent_index].clone();
+            node = (parent_level, parent_index);
+        }
+        value
+    }
+
+    fn apply_to_node(&mut self, level: usize, index: usize, mask: &T) {
+        self.levels[level][index] = self.levels[level][index].clone() | mask.clone();
+        if level > 0 {
+            self.pending[level][index] = self.pending[level][index].clone() | mask.clone();
+        }
+    }
+
+    fn push_down(&mut self, level: usize, index: usize) {
+        if level == 0 || self.pending[level][index] == T::default() {
+            return;
+        }
+        let mask = std::mem::take(&mut self.pending[level][index]);
+        self.apply_to_node(level - 1, index * 2, &mask);
+        self.apply_to_node(level - 1, index * 2 + 1, &mask);
+    }
+
+    /// Push down every mask pending above an item, so it can be changed on
+    /// its own.
+    fn expose(&mut self, index: usize) {
+        let mut path = Vec::new();
+        let mut node = (0, index);
+        while let Some(parent) = self.parent(node.0, node.1) {
+            path.push(parent);
+            node = parent;
+        }
+        for (level, index) in path.into_iter().rev() {
+            self.push_down(level, index);
         }
     }
 
@@ -45,29 +119,52 @@ impl<T: Clone + std::ops::BitOr<Output=T> + std::ops::BitAnd<Output=T> + std::cm
         }
     }
 
-    #[allow(dead_code)]
-    pub fn get(&self, index: usize) -> &T {
-        &self.levels[0][index]
-    }
-
     pub fn set(&mut self, index: usize, value: T) {
+        self.expose(index);
         self.levels[0][index] = value;
         self.merge_up(index);
     }
 
-    pub fn or_mask(&mut self, index: usize, value: T) -> &T {
-        self.levels[0][index] = self.levels[0][index].clone() | value;
-        self.merge_up(index);
-        &self.levels[0][index]
-    }
-
     #[allow(dead_code)]
     pub fn and_mask(&mut self, index: usize, value: T) -> &T {
+        self.expose(index);
         self.levels[0][index] = self.levels[0][index].clone() & value;
         self.merge_up(index);
         &self.levels[0][index]
     }
 
+    fn or_mask_range_at(&mut self, level: usize, index: usize, range: &Range<usize>, mask: &T) {
+        let first = index << level;
+        let end = (index + 1) << level;
+        if end <= range.start || range.end <= first {
+            return;
+        }
+        if range.start <= first && end <= range.end {
+            self.apply_to_node(level, index, mask);
+            return;
+        }
+        self.push_down(level, index);
+        self.or_mask_range_at(level - 1, index * 2, range, mask);
+        self.or_mask_range_at(level - 1, index * 2 + 1, range, mask);
+        self.levels[level][index] = self.levels[level - 1][index * 2].clone() | self.levels[level - 1][index * 2 + 1].clone();
+    }
+
+    /// Add a mask to every item in a range, in logarithmic time.
+    pub fn or_mask_range(&mut self, range: Range<usize>, mask: T) {
+        let range = range.start..range.end.min(self.levels[0].len());
+        if range.is_empty() {
+            return;
+        }
+        // The nodes without parents: the top one, and the last of any level
+        // with an odd number of nodes.
+        for level in 0..self.levels.len() {
+            let len = self.levels[level].len();
+            if level + 1 == self.levels.len() || len % 2 == 1 {
+                self.or_mask_range_at(level, len - 1, &range, &mask);
+            }
+        }
+    }
+
     // height = 0 is full detail.
     fn to_level_and_index(&self, index: usize, height: usize) -> (usize, usize) {
         let mut index = index;
@@ -83,9 +180,9 @@ impl<T: Clone + std::ops::BitOr<Output=T> + std::ops::BitAnd<Output=T> + std::cm
         (accepted_level, index)
     }
 
-    pub fn get_aliased(&self, index: usize, height: usize) -> &T {
+    pub fn get_aliased(&self, index: usize, height: usize) -> T {
         let (level, coarse_index) = self.to_level_and_index(index, height);
-        &self.levels[level][coarse_index]
+        self.value(level, coarse_index)
     }
 
     pub fn find_next<C: Fn(&T) -> bool>(&self, condition: C, start: usize) -> Option<usize> {
@@ -96,7 +193,20 @@ impl<T: Clone + std::ops::BitOr<Output=T> + std::ops::BitAnd<Output=T> + std::cm
         let mut level: usize = 0;
         let mut index: usize = start;
 
-        if condition(&self.levels[0][index]) {
+        // The masks pending above each node on the way up from start, so
+        // that nodes are only looked at once on the way back down.
+        let mut path = vec![(0, start)];
+        while let Some(parent) = self.parent(path[path.len() - 1].0, path[path.len() - 1].1) {
+            path.push(parent);
+        }
+        let mut above = vec![T::default(); path.len()];
+        for l in (0..(path.len() - 1)).rev() {
+            above[l] = above[l + 1].clone() | self.pending_at(path[l + 1].0, path[l + 1].1);
+        }
+        // Those above the node we're at.
+        let mut pending = T::default();
+
+        if condition(&(self.levels[0][index].clone() | above[0].clone())) {
             // We were already on a match
             return Some(index);
         }
@@ -119,7 +229,8 @@ impl<T: Clone + std::ops::BitOr<Output=T> + std::ops::BitAnd<Output=T> + std::cm
                         level -= 1;
                     }
                     if index < self.levels[level].len() {
-                        // There is a spill tree
+                        // There is a spill tree. Having no parent, nothing
+                        // is pending above it.
                         if condition(&self.levels[level][index]) {
                             // Match in this spill tree
                             seeking = false;
@@ -138,9 +249,10 @@ impl<T: Clone + std::ops::BitOr<Output=T> + std::ops::BitAnd<Output=T> + std::cm
                 // code is reached. (level max always triggers above.)
                 if index & 1 == 0 {
                     // was left sibling
-                    if condition(&self.levels[level][index|1]) {
+                    if condition(&(self.levels[level][index|1].clone() | above[level].clone())) {
                         // right sibling has match
                         index = index | 1;
+                        pending = above[level].clone();
                         seeking = false;
                     } else {
                         // no match in right child - go up.
@@ -158,9 +270,10 @@ impl<T: Clone + std::ops::BitOr<Output=T> + std::ops::BitAnd<Output=T> + std::cm
 
         // Refine down to the lowest suitable index.
         while level > 0 {
+            pending = pending | self.pending_at(level, index);
             level -= 1;
             index *= 2;
-            if !condition(&self.levels[level][index]) {
+            if !condition(&(self.levels[level][index].clone() | pending.clone())) {
                 // Left child didn't match, so must be right child
                 index = index | 1;
             }
@@ -183,28 +296,28 @@ mod tests {
         assert_eq!(alias_tree.levels[1].len(), 128);
         assert_eq!(alias_tree.levels[8].len(), 1);
 
-        assert_eq!(*alias_tree.get(0), 0);
-        assert_eq!(*alias_tree.get(1), 0);
-        assert_eq!(*alias_tree.get(16), 0);
-        assert_eq!(*alias_tree.get(255), 0);
+        assert_eq!(alias_tree.get_aliased(0, 0), 0);
+        assert_eq!(alias_tree.get_aliased(1, 0), 0);
+        assert_eq!(alias_tree.get_aliased(16, 0), 0);
+        assert_eq!(alias_tree.get_aliased(255, 0), 0);
 
         alias_tree.set(123, 1);
         alias_tree.set(200, 2);
 
-        assert_eq!(*alias_tree.get(100), 0);
-        assert_eq!(*alias_tree.get(123), 1);
-        assert_eq!(*alias_tree.get(200), 2);
-
-        assert_eq!(*alias_tree.get_aliased(100, 1), 0);
-        assert_eq!(*alias_tree.get_aliased(122, 0), 0);
-        assert_eq!(*alias_tree.get_aliased(123, 0), 1);
-        assert_eq!(*alias_tree.get_aliased(124, 0), 0);
-        assert_eq!(*alias_tree.get_aliased(123, 1), 1);
-        assert_eq!(*alias_tree.get_aliased(122, 1), 1);
-        assert_eq!(*alias_tree.get_aliased(124, 1), 0);
-        assert_eq!(*alias_tree.get_aliased(120, 1), 0);
-        assert_eq!(*alias_tree.get_aliased(120, 2), 1);
-        assert_eq!(*alias_tree.get_aliased(89, 8), 3);
+        assert_eq!(alias_tree.get_aliased(100, 0), 0);
+        assert_eq!(alias_tree.get_aliased(123, 0), 1);
+        assert_eq!(alias_tree.get_aliased(200, 0), 2);
+
+        assert_eq!(alias_tree.get_aliased(100, 1), 0);
+        assert_eq!(alias_tree.get_aliased(122, 0), 0);
+        assert_eq!(alias_tree.get_aliased(123, 0), 1);
+        assert_eq!(alias_tree.get_aliased(124, 0), 0);
+        assert_eq!(alias_tree.get_aliased(123, 1), 1);
+        assert_eq!(alias_tree.get_aliased(122, 1), 1);
+        assert_eq!(alias_tree.get_aliased(124, 1), 0);
+        assert_eq!(alias_tree.get_aliased(120, 1), 0);
+        assert_eq!(alias_tree.get_aliased(120, 2), 1);
+        assert_eq!(alias_tree.get_aliased(89, 8), 3);
     }
 
     #[test]
@@ -215,32 +328,31 @@ mod tests {
             alias_tree.set(i, 1 << i);
         }
         for i in 0..7 {
-            assert_eq!(*alias_tree.get(i), 1 << i);
-            assert_eq!(*alias_tree.get_aliased(i, 0), 1 << i);
+            assert_eq!(alias_tree.get_aliased(i, 0), 1 << i);
         }
-        assert_eq!(*alias_tree.get_aliased(0, 1), 0x03);
-        assert_eq!(*alias_tree.get_aliased(1, 1), 0x03);
-        assert_eq!(*alias_tree.get_aliased(2, 1), 0x0c);
-        assert_eq!(*alias_tree.get_aliased(3, 1), 0x0c);
-        assert_eq!(*alias_tree.get_aliased(4, 1), 0x30);
-        assert_eq!(*alias_tree.get_aliased(5, 1), 0x30);
-        assert_eq!(*alias_tree.get_aliased(6, 1), 0x40);
-
-        assert_eq!(*alias_tree.get_aliased(0, 2), 0x0f);
-        assert_eq!(*alias_tree.get_aliased(1, 2), 0x0f);
-        assert_eq!(*alias_tree.get_aliased(2, 2), 0x0f);
-        assert_eq!(*alias_tree.get_aliased(3, 2), 0x0f);
-        assert_eq!(*alias_tree.get_aliased(4, 2), 0x30);
-        assert_eq!(*alias_tree.get_aliased(5, 2), 0x30);
-        assert_eq!(*alias_tree.get_aliased(6, 2), 0x40);
-
-        assert_eq!(*alias_tree.get_aliased(0, 3), 0x0f);
-        assert_eq!(*alias_tree.get_aliased(1, 3), 0x0f);
-        assert_eq!(*alias_tree.get_aliased(2, 3), 0x0f);
-        assert_eq!(*alias_tree.get_aliased(3, 3), 0x0f);
-        assert_eq!(*alias_tree.get_aliased(4, 3), 0x30);
-        assert_eq!(*alias_tree.get_aliased(5, 3), 0x30);
-        assert_eq!(*alias_tree.get_aliased(6, 3), 0x40);
+        assert_eq!(alias_tree.get_aliased(0, 1), 0x03);
+        assert_eq!(alias_tree.get_aliased(1, 1), 0x03);
+        assert_eq!(alias_tree.get_aliased(2, 1), 0x0c);
+        assert_eq!(alias_tree.get_aliased(3, 1), 0x0c);
+        assert_eq!(alias_tree.get_aliased(4, 1), 0x30);
+        assert_eq!(alias_tree.get_aliased(5, 1), 0x30);
+        assert_eq!(alias_tree.get_aliased(6, 1), 0x40);
+
+        assert_eq!(alias_tree.get_aliased(0, 2), 0x0f);
+        assert_eq!(alias_tree.get_aliased(1, 2), 0x0f);
+        assert_eq!(alias_tree.get_aliased(2, 2), 0x0f);
+        assert_eq!(alias_tree.get_aliased(3, 2), 0x0f);
+        assert_eq!(alias_tree.get_aliased(4, 2), 0x30);
+        assert_eq!(alias_tree.get_aliased(5, 2), 0x30);
+        assert_eq!(alias_tree.get_aliased(6, 2), 0x40);
+
+        assert_eq!(alias_tree.get_aliased(0, 3), 0x0f);
+        assert_eq!(alias_tree.get_aliased(1, 3), 0x0f);
+        assert_eq!(alias_tree.get_aliased(2, 3), 0x0f);
+        assert_eq!(alias_tree.get_aliased(3, 3), 0x0f);
+        assert_eq!(alias_tree.get_aliased(4, 3), 0x30);
+        assert_eq!(alias_tree.get_aliased(5, 3), 0x30);
+        assert_eq!(alias_tree.get_aliased(6, 3), 0x40);
     }
 
     #[test]
@@ -303,4 +415,50 @@ mod tests {
         let mut alias_tree: AliasTree<u8> = AliasTree::new(8, 0);
         assert_eq!(alias_tree.find_next(|x|{*x!=0}, 0), None);
     }
+    #[test]
+    fn test_ranges() {
+        // Compare against a plain list, through a (repeatable) jumble of
+        // overlapping changes.
+        let size = 23;
+        let mut alias_tree: AliasTree<u8> = AliasTree::new(size, 0);
+        let mut expected: Vec<u8> = vec![0; size];
+        let mut random: u32 = 1;
+        let mut next = |limit: usize| -> usize {
+            random = random.wrapping_mul(1103515245).wrapping_add(12345);
+            (random >> 16) as usize % limit
+        };
+        for _ in 0..500 {
+            let start = next(size);
+            let end = start + next(size - start) + 1;
+            let value = 1 << next(8);
+            match next(4) {
+                0 | 1 => {
+                    alias_tree.or_mask_range(start..end, value);
+                    expected[start..end].iter_mut().for_each(|x| {*x |= value});
+                },
+                2 => {
+                    alias_tree.set(start, value);
+                    expected[start] = value;
+                },
+                _ => {
+                    for i in start..end {
+                        alias_tree.set(i, 0);
+                    }
+                    expected[start..end].iter_mut().for_each(|x| {*x = 0});
+                },
+            }
+            for i in 0..size {
+                assert_eq!(alias_tree.get_aliased(i, 0), expected[i]);
+                assert_eq!(alias_tree.find_next(|x|{*x!=0}, i), (i..size).find(|j| {expected[*j] != 0}));
+            }
+            for height in 1..5 {
+                for i in 0..size {
+                    let (level, index) = alias_tree.to_level_and_index(i, height);
+                    let first = index << level;
+                    let merged = expected[first..(first + (1 << level))].iter().fold(0, |x, y| {x | y});
+                    assert_eq!(alias_tree.get_aliased(i, height), merged);
+                }
+            }
+        }
+    }
 }
@@ -1,11 +1,26 @@
 use std::path::{Path,PathBuf};
 use std::fs::File;
-use std::io::{Write,Seek,SeekFrom};
-use crate::chunk::Chunk;
+use std::io::{Seek,SeekFrom};
+use std::os::unix::fs::{FileExt,FileTypeExt};
+use std::os::unix::io::AsRawFd;
+use libc::c_ulong;
+use crate::chunk::{Chunk,ChunkContent};
+
+// From linux/fs.h (not exported by the libc crate).
+const BLKDISCARD: c_ulong = 0x1277;
+const BLKZEROOUT: c_ulong = 0x127f;
 
 pub struct BackupFile {
     path: PathBuf,
     file: File,
+    is_block_device: bool,
+}
+
+fn is_block_device(file: &File) -> bool {
+    match file.metadata() {
+        Ok(metadata) => metadata.file_type().is_block_device(),
+        Err(_) => false,
+    }
 }
 
 impl BackupFile {
@@ -23,6 +38,7 @@ impl BackupFile {
 
         Ok(Self{
             path: path.to_path_buf(),
+            is_block_device: is_block_device(&file),
             file,
         })
     }
@@ -44,13 +60,77 @@ impl BackupFile {
 
         Ok(Self {
             path: path.to_path_buf(),
+            is_block_device: is_block_device(&file),
             file,
         })
     }
 
+    /// Write a chunk, or a run of them, with a single I/O where possible.
     pub fn write_chunk(&mut self, chunk: Chunk) {
-        self.file.seek(SeekFrom::Start(chunk.offset)).expect("Backup seek failed");
-        self.file.write_all(&chunk.data).expect("Write to backup failed");
+        match chunk.content {
+            ChunkContent::Data(data) => {
+                self.file.write_all_at(&data, chunk.offset).expect("Write to backup failed");
+            },
+            ChunkContent::Discarded(length) => {
+                // Block devices may not read back zeros after a discard, but
+                // neither may the source, so that is no less consistent.
+                let done =
+                    if self.is_block_device {
+                        self.block_range_ioctl(BLKDISCARD, chunk.offset, length)
+                            || self.block_range_ioctl(BLKZEROOUT, chunk.offset, length)
+                    } else {
+                        self.punch_hole(chunk.offset, length)
+                    };
+                if !done {
+                    self.write_zeros(chunk.offset, length);
+                }
+            },
+            ChunkContent::Zeroed(length) => {
+                let done =
+                    if self.is_block_device {
+                        self.block_range_ioctl(BLKZEROOUT, chunk.offset, length)
+                    } else {
+                        self.punch_hole(chunk.offset, length)
+                    };
+                if !done {
+                    self.write_zeros(chunk.offset, length);
+                }
+            },
+        }
+    }
+
+    /// Deallocate a range of a regular file, which then reads back as zeros.
+    /// Returns false if the filesystem doesn't support this.
+    fn punch_hole(&self, offset: u64, length: usize) -> bool {
+        let result = unsafe {
+            libc::fallocate(self.file.as_raw_fd(), libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE, offset as libc::off_t, length as libc::off_t)
+        };
+        result == 0
+    }
+
+    /// Issue a BLKDISCARD or BLKZEROOUT ioctl on a block device. Returns
+    /// false if the device rejects it (e.g. it doesn't support discard).
+    fn block_range_ioctl(&self, request: c_ulong, offset: u64, length: usize) -> bool {
+        let range: [u64; 2] = [offset, length as u64];
+        let result = unsafe {
+            libc::ioctl(self.file.as_raw_fd(), request as _, range.as_ptr())
+        };
+        result == 0
+    }
+
+    fn write_zeros(&mut self, offset: u64, length: usize) {
+        let zeros: Vec<u8> = vec![0; length];
+        self.file.write_all_at(&zeros, offset).expect("Write to backup failed");
+    }
+
+    /// The most the backup can hold, if it can't grow (i.e. it's a block
+    /// device). Regular files grow as needed.
+    pub fn get_capacity(&mut self) -> Option<u64> {
+        if self.is_block_device {
+            Some(self.file.seek(SeekFrom::End(0)).expect("Could not determine backup device size"))
+        } else {
+            None
+        }
     }
 
     pub fn get_path(&self) -> &Path {
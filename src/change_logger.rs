@@ -1,12 +1,117 @@
 use std::cell::Cell;
-use std::collections::{HashMap,BTreeSet};
+use std::collections::{HashMap,HashSet,BTreeMap,BTreeSet};
+use std::ops::Range;
 use std::sync::mpsc::{Receiver,Sender};
 use std::sync::{Arc,Barrier};
+use std::sync::atomic::{AtomicUsize,Ordering};
 use std::ffi::CString;
+use std::path::{Path,PathBuf};
+use std::time::{Duration,Instant};
 use libc::{c_char,c_int,c_void,ssize_t,size_t};
-use crate::device::Device;
+use crate::device::{Device,Segment,SECTOR_SIZE};
 use crate::quick_io::{append_to_file_at_path,slurp_file_at_path,fd_poll_read};
-use crate::control::{Config,Manifest};
+use crate::control::{Config,Job,JobError,Manifest,TraceBackend,TraceReader};
+use crate::trace_state;
+
+mod ring_buffer;
+mod bio_queue;
+mod filter;
+mod recording;
+mod buffer_sizer;
+mod dirty_bitmap;
+mod nbd_server;
+
+/// A request from the copier to the change logger.
+pub enum Control {
+    /// Report every event traced so far, then wait on the barrier.
+    Sync(Arc<Barrier>),
+    /// A job's device has been resized. Report every event traced so far,
+    /// switch to the new geometry, then wait on the barrier. The traced
+    /// whole disk devices must not have changed.
+    UpdateDevice {
+        device_number: usize,
+        device: Device,
+        segments: Vec<Segment>,
+        barrier: Arc<Barrier>,
+    },
+    /// Stop sources which serve writes from making any more, report every
+    /// write already made, then wait on the barrier.
+    HoldWrites(Arc<Barrier>),
+    /// Let sources which serve writes make them again.
+    ReleaseWrites,
+}
+
+/// How a chunk has been changed.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ChangeKind {
+    /// Arbitrary data has been written.
+    Write,
+    /// The whole chunk has been discarded (e.g. TRIM).
+    Discard,
+    /// The whole chunk has been zeroed (REQ_OP_WRITE_ZEROES). Note that the
+    /// kernel's tracers report write zeroes operations as ordinary writes,
+    /// so tracing alone never produces this.
+    WriteZeroes,
+}
+
+/// A notification sent from the change logger to the copier.
+pub enum Change {
+    /// A run of chunks has been changed.
+    Chunks(ChunkRange),
+    /// The kernel has dropped the given number of trace events, so any
+    /// chunk on any traced device may have been written to.
+    EventsLost(u64),
+    /// The per-CPU trace buffers have been resized to this many KB.
+    BufferSize(usize),
+    /// A sync's barrier wasn't passed in time, so changes made before the
+    /// sync may not have been reported yet.
+    BarrierFailed,
+    /// A job's changes can't be found, so the job has failed.
+    JobFailed(usize, JobError),
+}
+
+/// A run of changed chunks on one job's device.
+#[derive(Clone,Debug,PartialEq)]
+pub struct ChunkRange {
+    pub device_number: usize,
+    pub chunks: Range<usize>,
+    pub kind: ChangeKind,
+}
+
+/// Something which can tell the change logger which chunks have changed,
+/// such as the kernel's tracing, or a recording of it.
+pub trait ChangeSource {
+    /// Return the chunks changed by the next event, waiting up to about 1ms
+    /// for one. Returns None if there was nothing to read.
+    fn try_read(&mut self) -> Option<Vec<ChunkRange>>;
+    /// Return the number of events lost since the last check. Any chunk may
+    /// have been changed by them.
+    fn check_lost(&mut self) -> u64 {
+        0
+    }
+    /// A job's device has been resized (see Control::UpdateDevice).
+    fn update_device(&mut self, _device_number: usize, _device: &Device, _segments: Vec<Segment>) {}
+    /// Place a barrier after every change which has already happened, so
+    /// that once passed_barrier() is true, try_read has returned all of
+    /// them. Returns false if the source doesn't need one, having returned
+    /// every change once try_read has nothing left to return, or an error
+    /// if it needs one but couldn't place it.
+    fn place_barrier(&mut self) -> Result<bool,String> {
+        Ok(false)
+    }
+    fn passed_barrier(&self) -> bool {
+        true
+    }
+    /// For sources which make the writes themselves (rather than seeing
+    /// them happen), stop or start making them. Once held, every write has
+    /// been returned by try_read.
+    fn hold_writes(&mut self, _hold: bool) {}
+    /// Called periodically after check_lost. Returns the new per-CPU buffer
+    /// size in KB, if the source has just resized its buffers.
+    fn adjust_buffer_size(&mut self) -> Option<usize> {
+        None
+    }
+}
 
 trait WarnIfErr {
     fn warn_if_err(&self);
@@ -42,6 +147,20 @@ const MAGIC_NATIVE_ENDIAN: u32 = 0x65617400;
 const MAGIC_REVERSE_ENDIAN: u32 = 0x00746165;
 const SUPPORTED_VERSION: u8 = 0x07;
 
+// Categories (upper 16 bits of BlkEvent::action) and actions (lower 16 bits)
+// from the kernel's blktrace_api.h.
+const BLK_TC_WRITE: u32 = 1 << 1;
+const BLK_TC_DISCARD: u32 = 1 << 13;
+const BLK_TA_QUEUE: u32 = 1;
+
+/// Something read from the trace.
+#[derive(Debug)]
+enum TraceEvent {
+    Blk(BlkEvent),
+    /// Text written to trace_marker.
+    Marker(String),
+}
+
 impl BlkEvent {
     fn try_read_from_file(trace_pipe_fd: c_int) -> Option<BlkEvent> {
         let event_size = ::std::mem::size_of::<BlkEvent>();
@@ -117,6 +236,556 @@ impl BlkEvent {
 }
 
 
+/// Watches the per-CPU ring buffer statistics for events which were
+/// overwritten or dropped before we could read them.
+struct TraceLossMonitor {
+    stats_paths: Vec<PathBuf>,
+    lost: Vec<u64>,
+    // As of the last check.
+    cpu_stats: Vec<buffer_sizer::CpuStats>,
+}
+
+// Distinguishes instances created by successive runs in the same process.
+static NEXT_INSTANCE_NUMBER: AtomicUsize = AtomicUsize::new(0);
+
+// How often to check the ring buffer statistics outside of syncs.
+const TRACE_LOSS_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+// Distinguishes the markers of successive barriers.
+static NEXT_BARRIER_NUMBER: AtomicUsize = AtomicUsize::new(0);
+
+// How long to wait for barrier markers to come back before giving up on
+// them, and telling the copier that the sync didn't catch everything.
+const BARRIER_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl TraceLossMonitor {
+    /// Record the current loss counters for all CPUs, so that only events
+    /// lost from now on are reported.
+    fn new(trace_root: &Path) -> Self {
+        let mut stats_paths: Vec<PathBuf> = Vec::new();
+        let per_cpu_dir = std::fs::read_dir(trace_root.join("per_cpu")).expect("Could not list per-CPU tracing directories");
+        for entry in per_cpu_dir {
+            let entry = entry.expect("Could not list per-CPU tracing directories");
+            if entry.file_name().to_string_lossy().starts_with("cpu") {
+                stats_paths.push(entry.path().join("stats"));
+            }
+        }
+        let lost = stats_paths.iter().map(|path| {parse_lost_event_count(&Self::read_stats(path))}).collect();
+        Self {
+            stats_paths,
+            lost,
+            cpu_stats: Vec::new(),
+        }
+    }
+
+    fn read_stats(path: &Path) -> String {
+        // Not slurped, as this gets read frequently and would flood the log.
+        match std::fs::read_to_string(path) {
+            Ok(stats) => stats,
+            Err(e) => {
+                eprintln!("Warning: could not read trace statistics from '{}': {:?}", path.display(), e);
+                String::new()
+            },
+        }
+    }
+
+    /// Return the number of events lost since the last check.
+    fn check(&mut self) -> u64 {
+        let mut newly_lost = 0;
+        self.cpu_stats.clear();
+        for (path, lost) in self.stats_paths.iter().zip(self.lost.iter_mut()) {
+            let stats = Self::read_stats(path);
+            self.cpu_stats.push(buffer_sizer::parse_cpu_stats(&stats));
+            let now_lost = parse_lost_event_count(&stats);
+            // Counters only go backwards if the buffer was reset by someone else,
+            // which we can't account for anyway.
+            newly_lost += now_lost.saturating_sub(*lost);
+            *lost = now_lost;
+        }
+        newly_lost
+    }
+}
+
+/// Sum the counters in a per-CPU trace `stats` file which represent events
+/// that never made it to a reader.
+fn parse_lost_event_count(stats: &str) -> u64 {
+    let mut lost = 0;
+    for line in stats.lines() {
+        let mut parts = line.splitn(2, ':');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        match key {
+            "overrun" | "commit overrun" | "dropped events" => {
+                lost += value.parse::<u64>().unwrap_or(0);
+            },
+            _ => {},
+        }
+    }
+    lost
+}
+
+
+/// Where blk events are being read from.
+enum EventReader {
+    Pipe(c_int),
+    Raw(ring_buffer::RawReader),
+    Text(bio_queue::TextReader),
+}
+
+impl EventReader {
+    fn try_read(&self) -> Option<TraceEvent> {
+        match self {
+            EventReader::Pipe(trace_pipe_fd) => BlkEvent::try_read_from_file(*trace_pipe_fd).map(TraceEvent::Blk),
+            EventReader::Raw(raw_reader) => raw_reader.try_read(),
+            EventReader::Text(text_reader) => text_reader.try_read(),
+        }
+    }
+}
+
+/// Open the trace pipe, and flush anything in it first so we know we're
+/// only going to get blk data.
+fn open_trace_pipe(trace_root: &Path) -> c_int {
+    let trace_pipe_fd = unsafe {
+        libc::open(CString::new(trace_root.join("trace_pipe").to_str().unwrap()).unwrap().as_ptr(), libc::O_RDONLY | libc::O_NONBLOCK)
+    };
+    if trace_pipe_fd < 0 {
+        panic!("Could not open trace pipe");
+    }
+
+    {
+        let trace_pipe_file = unsafe{
+            libc::fdopen(trace_pipe_fd, b"rb\0".as_ptr() as *const c_char)
+        };
+        // I'm assuming the stream can't ever half-write a data structure.
+        unsafe{
+            while libc::fgetc(trace_pipe_file) >= 0 {
+            };
+        };
+    }
+    trace_pipe_fd
+}
+
+fn open_event_reader(config: &Config, trace_root: &Path) -> EventReader {
+    let use_raw =
+        match config.trace_reader {
+            TraceReader::Auto => ring_buffer::RawReader::is_supported(trace_root),
+            TraceReader::Pipe => false,
+            TraceReader::PerCpuRaw => true,
+        };
+    if use_raw {
+        match ring_buffer::RawReader::open(trace_root) {
+            Ok(raw_reader) => {
+                eprintln!("Reading trace events from per-CPU raw pipes");
+                return EventReader::Raw(raw_reader);
+            },
+            Err(e) => {
+                eprintln!("Warning: could not use per-CPU raw pipes ({}). Falling back to trace_pipe.", e);
+            },
+        }
+    }
+    EventReader::Pipe(open_trace_pipe(trace_root))
+}
+
+/// The CPU numbers of every per-CPU trace buffer.
+fn list_trace_cpus(trace_root: &Path) -> Vec<usize> {
+    let mut cpus: Vec<usize> = match std::fs::read_dir(trace_root.join("per_cpu")) {
+        Ok(entries) => entries.flatten().filter_map(|entry| {entry.file_name().to_string_lossy().strip_prefix("cpu")?.parse().ok()}).collect(),
+        Err(_) => Vec::new(),
+    };
+    cpus.sort_unstable();
+    cpus
+}
+
+/// Write a marker into each CPU's trace buffer, by writing it to trace_marker
+/// while running on each CPU in turn. Returns how many were written. Fails if
+/// we can't run on one of the CPUs (e.g. an offline one), as events may still
+/// be waiting in its buffer.
+fn write_marker_on_cpus(trace_marker_path: &Path, cpus: &[usize], marker: &str) -> Result<usize,String> {
+    let set_size = std::mem::size_of::<libc::cpu_set_t>();
+    let mut original_affinity: libc::cpu_set_t = unsafe {std::mem::zeroed()};
+    if unsafe {libc::sched_getaffinity(0, set_size, &mut original_affinity)} != 0 {
+        return Err(String::from("Could not get CPU affinity"));
+    }
+    let mut written = 0;
+    let mut result = Ok(());
+    for cpu in cpus {
+        let mut affinity: libc::cpu_set_t = unsafe {std::mem::zeroed()};
+        unsafe {libc::CPU_SET(*cpu, &mut affinity)};
+        // The kernel has moved us onto the CPU by the time this returns.
+        if unsafe {libc::sched_setaffinity(0, set_size, &affinity)} != 0 {
+            result = Err(format!("Could not run on CPU {}: {}", cpu, std::io::Error::last_os_error()));
+            break;
+        }
+        result = append_to_file_at_path(trace_marker_path, format!("{}\n", marker).as_bytes());
+        if result.is_err() {
+            break;
+        }
+        written += 1;
+    }
+    if unsafe {libc::sched_setaffinity(0, set_size, &original_affinity)} != 0 {
+        eprintln!("Warning: could not restore CPU affinity");
+    }
+    result.map(|_| {written})
+}
+
+
+/// Index each job's segments by the event device they're traced on.
+fn build_segment_map(trace_segments: &[Vec<Segment>]) -> HashMap<u32, Vec<(usize, Segment)>> {
+    let mut segment_map: HashMap<u32, Vec<(usize, Segment)>> = HashMap::new();
+    for (i, segments) in trace_segments.iter().enumerate() {
+        for segment in segments {
+            segment_map.entry(segment.traced.event_dev).or_default().push((i, segment.clone()));
+        }
+    }
+    segment_map
+}
+
+/// The half-open range of sectors a blk event changes, and how. Returns
+/// None for events which don't change anything.
+fn decode_change(event: &BlkEvent) -> Option<(u64, u64, ChangeKind)> {
+    let category = event.action >> 16;
+    let action = event.action & 0xffff;
+    let bytes: u64 = event.bytes as u64;
+
+    if category & BLK_TC_WRITE == 0 {
+        // Was not a write operation (discards are also writes), so we don't care.
+        return None;
+    }
+    if action != BLK_TA_QUEUE {
+        // Was not a QUEUE action.
+        return None;
+    }
+    if bytes == 0 {
+        // There is no data location associated, so skip.
+        return None;
+    }
+    let kind =
+        if category & BLK_TC_DISCARD != 0 {
+            ChangeKind::Discard
+        } else {
+            ChangeKind::Write
+        };
+    Some((event.sector, event.sector + bytes.div_ceil(SECTOR_SIZE), kind))
+}
+
+/// The chunks of a job's device changed by a change to a range of its
+/// bytes.
+fn byte_range_changes(device_number: usize, first_byte: u64, end_byte: u64, chunk_size: u64, device_bytes: u64, kind: ChangeKind) -> Vec<ChunkRange> {
+    let mut ranges = Vec::new();
+    let first_chunk: usize = (first_byte / chunk_size) as usize;
+    let end_chunk: usize = end_byte.div_ceil(chunk_size) as usize;
+    let mut push = |chunks: Range<usize>, kind: ChangeKind| {
+        if !chunks.is_empty() {
+            ranges.push(ChunkRange {device_number, chunks, kind});
+        }
+    };
+    if kind == ChangeKind::Write {
+        push(first_chunk..end_chunk, kind);
+        return ranges;
+    }
+    // Only whole chunks can be discarded. Partially covered chunks at
+    // either end still need their data copying.
+    let covered = |index: usize| {
+        let chunk_start: u64 = index as u64 * chunk_size;
+        let chunk_end: u64 = std::cmp::min(chunk_start + chunk_size, device_bytes);
+        first_byte <= chunk_start && end_byte >= chunk_end
+    };
+    let whole_start = if covered(first_chunk) {first_chunk} else {first_chunk + 1};
+    let whole_end = if covered(end_chunk - 1) {end_chunk} else {end_chunk - 1};
+    if whole_start >= whole_end {
+        push(first_chunk..end_chunk, ChangeKind::Write);
+    } else {
+        push(first_chunk..whole_start, ChangeKind::Write);
+        push(whole_start..whole_end, kind);
+        push(whole_end..end_chunk, ChangeKind::Write);
+    }
+    ranges
+}
+
+/// Maps sectors on traced devices to chunks on jobs' devices.
+struct EventMapper {
+    chunk_sizes: Vec<u64>,
+    device_sizes: Vec<u64>,
+    segment_map: HashMap<u32, Vec<(usize, Segment)>>,
+}
+
+impl EventMapper {
+    fn new(manifest: &Manifest, devices: &[Device], trace_segments: &[Vec<Segment>]) -> Self {
+        Self {
+            chunk_sizes: manifest.jobs.iter().map(|job| {job.chunk_size as u64}).collect(),
+            device_sizes: devices.iter().map(|device| {device.get_size()}).collect(),
+            segment_map: build_segment_map(trace_segments),
+        }
+    }
+
+    fn map(&self, event_dev: u32, start_sector: u64, end_sector: u64, kind: ChangeKind) -> Vec<ChunkRange> {
+        let mut ranges = Vec::new();
+        let segments = match self.segment_map.get(&event_dev) {
+            Some(x) => x,
+            None => return ranges,
+        };
+        // The traced device may hold several jobs' devices (e.g. a whole
+        // disk AND its partitions), and the parts of the event outside each
+        // are clipped off by its mapping.
+        for (device_number, segment) in segments {
+            let chunk_size = self.chunk_sizes[*device_number];
+            let device_bytes = self.device_sizes[*device_number];
+            for (first_sector, last_sector) in segment.map_range(start_sector, end_sector) {
+                ranges.extend(byte_range_changes(*device_number, first_sector * SECTOR_SIZE, last_sector * SECTOR_SIZE, chunk_size, device_bytes, kind));
+            }
+        }
+        ranges
+    }
+
+    fn update_device(&mut self, device_number: usize, device: &Device, segments: Vec<Segment>) {
+        self.device_sizes[device_number] = device.get_size();
+        let mut updated_segments: Vec<Vec<Segment>> = vec![Vec::new(); self.device_sizes.len()];
+        for (number, segment) in self.segment_map.values().flatten() {
+            if *number != device_number {
+                updated_segments[*number].push(segment.clone());
+            }
+        }
+        updated_segments[device_number] = segments;
+        self.segment_map = build_segment_map(&updated_segments);
+    }
+}
+
+/// Changes traced live from the kernel.
+struct TracedChanges {
+    event_reader: EventReader,
+    trace_loss_monitor: TraceLossMonitor,
+    event_filter: filter::EventFilter,
+    mapper: EventMapper,
+    recorder: Option<recording::EventRecorder>,
+    use_blk_tracer: bool,
+    trace_root: PathBuf,
+    cpus: Vec<usize>,
+    // The current barrier's marker, and how many copies of it are unread.
+    barrier: Option<(String, usize)>,
+    buffer_sizer: Option<buffer_sizer::BufferSizer>,
+}
+
+impl ChangeSource for TracedChanges {
+    fn try_read(&mut self) -> Option<Vec<ChunkRange>> {
+        let event = match self.event_reader.try_read()? {
+            TraceEvent::Blk(event) => event,
+            TraceEvent::Marker(marker) => {
+                if let Some((barrier_marker, unread)) = &mut self.barrier {
+                    if marker == *barrier_marker && *unread > 0 {
+                        *unread -= 1;
+                    }
+                }
+                return Some(Vec::new());
+            },
+        };
+        let (start_sector, end_sector, kind) = match decode_change(&event) {
+            Some(x) => x,
+            None => return Some(Vec::new()),
+        };
+        if self.event_filter.should_ignore(event.pid, event.device, start_sector, end_sector) {
+            return Some(Vec::new());
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record_event(&event);
+        }
+        Some(self.mapper.map(event.device, start_sector, end_sector, kind))
+    }
+
+    fn check_lost(&mut self) -> u64 {
+        let lost = self.trace_loss_monitor.check();
+        if let Some(recorder) = &mut self.recorder {
+            if lost > 0 {
+                recorder.record_lost(lost);
+            }
+            recorder.flush();
+        }
+        lost
+    }
+
+    fn update_device(&mut self, device_number: usize, device: &Device, segments: Vec<Segment>) {
+        if self.use_blk_tracer {
+            for segment in &segments {
+                append_to_file_at_path(&segment.traced.sys_dev_path.join("trace/end_lba"), format!("{}\n", segment.traced.end_sector).as_bytes()).warn_if_err();
+            }
+        }
+        self.mapper.update_device(device_number, device, segments);
+    }
+
+    fn place_barrier(&mut self) -> Result<bool,String> {
+        if let EventReader::Pipe(_) = self.event_reader {
+            // Markers have no binary form, so never come out of the pipe.
+            // None are needed though: the pipe merges every CPU's buffer
+            // into one stream, so once it has been read until empty, every
+            // event traced before then has been read.
+            return Ok(false);
+        }
+        let marker = format!("trackup-barrier-{}-{}", std::process::id(), NEXT_BARRIER_NUMBER.fetch_add(1, Ordering::Relaxed));
+        match write_marker_on_cpus(&self.trace_root.join("trace_marker"), &self.cpus, &marker) {
+            Ok(written) => {
+                self.barrier = Some((marker, written));
+                Ok(true)
+            },
+            Err(e) => {
+                // Any copies already written are read and ignored.
+                self.barrier = None;
+                Err(format!("Could not place trace barrier: {}", e))
+            },
+        }
+    }
+
+    fn passed_barrier(&self) -> bool {
+        !matches!(&self.barrier, Some((_, unread)) if *unread > 0)
+    }
+
+    fn adjust_buffer_size(&mut self) -> Option<usize> {
+        let buffer_sizer = self.buffer_sizer.as_mut()?;
+        let size = buffer_sizer.observe(&self.trace_loss_monitor.cpu_stats, Instant::now())?;
+        match append_to_file_at_path(&self.trace_root.join("buffer_size_kb"), format!("{}\n", size).as_bytes()) {
+            Ok(()) => {
+                eprintln!("Resized trace buffers to {} KB per CPU", size);
+                Some(size)
+            },
+            Err(e) => {
+                eprintln!("Warning: could not resize trace buffers: {}", e);
+                buffer_sizer.resize_failed();
+                None
+            },
+        }
+    }
+}
+
+/// Used when nothing needs tracing.
+struct NoChanges;
+
+impl ChangeSource for NoChanges {
+    fn try_read(&mut self) -> Option<Vec<ChunkRange>> {
+        std::thread::sleep(Duration::from_millis(1));
+        None
+    }
+}
+
+/// Changes from several sources, for different jobs.
+struct CombinedChanges<'s> {
+    sources: Vec<&'s mut dyn ChangeSource>,
+    // Where to start reading next, so that a busy source can't starve the
+    // others.
+    next: usize,
+}
+
+impl ChangeSource for CombinedChanges<'_> {
+    fn try_read(&mut self) -> Option<Vec<ChunkRange>> {
+        for _ in 0..self.sources.len() {
+            let index = self.next;
+            self.next = (self.next + 1) % self.sources.len();
+            if let Some(ranges) = self.sources[index].try_read() {
+                return Some(ranges);
+            }
+        }
+        None
+    }
+
+    fn check_lost(&mut self) -> u64 {
+        self.sources.iter_mut().map(|source| {source.check_lost()}).sum()
+    }
+
+    fn update_device(&mut self, device_number: usize, device: &Device, segments: Vec<Segment>) {
+        for source in &mut self.sources {
+            source.update_device(device_number, device, segments.clone());
+        }
+    }
+
+    fn place_barrier(&mut self) -> Result<bool,String> {
+        let mut result = Ok(false);
+        for source in &mut self.sources {
+            match (source.place_barrier(), &mut result) {
+                (Ok(placed), Ok(any_placed)) => *any_placed |= placed,
+                (Err(e), Ok(_)) => result = Err(e),
+                (_, Err(_)) => {},
+            }
+        }
+        result
+    }
+
+    fn passed_barrier(&self) -> bool {
+        self.sources.iter().all(|source| {source.passed_barrier()})
+    }
+
+    fn hold_writes(&mut self, hold: bool) {
+        for source in &mut self.sources {
+            source.hold_writes(hold);
+        }
+    }
+
+    fn adjust_buffer_size(&mut self) -> Option<usize> {
+        self.sources.iter_mut().filter_map(|source| {source.adjust_buffer_size()}).last()
+    }
+}
+
+/// Changes replayed from a recording made by TracedChanges.
+struct ReplayedChanges {
+    replay: recording::EventReplay,
+    mapper: EventMapper,
+    lost: u64,
+}
+
+impl ChangeSource for ReplayedChanges {
+    fn try_read(&mut self) -> Option<Vec<ChunkRange>> {
+        match self.replay.try_read()? {
+            recording::Recorded::Event(event) => {
+                Some(match decode_change(&event) {
+                    Some((start_sector, end_sector, kind)) => self.mapper.map(event.device, start_sector, end_sector, kind),
+                    None => Vec::new(),
+                })
+            },
+            recording::Recorded::Lost(lost) => {
+                self.lost += lost;
+                Some(Vec::new())
+            },
+        }
+    }
+
+    fn check_lost(&mut self) -> u64 {
+        std::mem::take(&mut self.lost)
+    }
+
+    fn update_device(&mut self, device_number: usize, device: &Device, segments: Vec<Segment>) {
+        self.mapper.update_device(device_number, device, segments);
+    }
+}
+
+/// Resolve which tracing mechanism to use. The blk tracer is preferred
+/// when available, as it has been tested for longer, unless we're tracing
+/// in an instance, which the blk tracer can't do.
+fn choose_backend(config: &Config) -> TraceBackend {
+    match config.trace_backend {
+        TraceBackend::Auto if config.trace_instance => TraceBackend::BlockBioQueue,
+        TraceBackend::Auto => {
+            let available_tracers = slurp_file_at_path(&config.tracing_path.join("available_tracers")).unwrap_or_default();
+            let has_blk_tracer = String::from_utf8_lossy(&available_tracers).split_whitespace().any(|tracer| {tracer == "blk"});
+            if has_blk_tracer {
+                TraceBackend::BlkTracer
+            } else {
+                TraceBackend::BlockBioQueue
+            }
+        },
+        backend => backend,
+    }
+}
+
+/// Read an event filter so that it can be restored later. An event with no
+/// filter reads back as "none", which must be restored by writing "0".
+fn read_event_filter(path: &Path) -> Vec<u8> {
+    let filter = slurp_file_at_path(path).unwrap();
+    if filter.trim_ascii() == b"none" {
+        b"0\n".to_vec()
+    } else {
+        filter
+    }
+}
+
+
 // RAII-based do something then undo it.
 struct DoUndo<'u> {
     undoer: Option<Box<dyn FnOnce() + 'u>>,
@@ -137,79 +806,185 @@ impl<'u> Drop for DoUndo<'u> {
 }
 
 
-pub fn run(config: &Config, manifest: &Manifest, devices: &Vec<Device>, log_channel: Sender<(usize, usize)>, sync_barrier_channel: Receiver<Arc<Barrier>>) {
-    let mut device_map: HashMap<u32, HashMap<&Device, usize>> = HashMap::new();
-    for (i, device) in devices.iter().enumerate() {
-        let base_device_event_dev = device.get_base_device().event_dev;
-        if !device_map.contains_key(&base_device_event_dev) {
-            device_map.insert(base_device_event_dev, HashMap::new());
+/// `trace_segments` gives, for each job's device, the whole disk devices to
+/// trace and how to map their sectors back onto the job's device.
+pub fn run(config: &Config, manifest: &Manifest, devices: &[Device], trace_segments: &[Vec<Segment>], log_channel: Sender<Change>, control_channel: Receiver<Control>) {
+    if devices.iter().collect::<HashSet<&Device>>().len() != devices.len() {
+        panic!("Duplicate device found");
+    }
+    let mapper = EventMapper::new(manifest, devices, trace_segments);
+
+    // Sources of changes for particular jobs, which aren't traced.
+    let mut job_sources: Vec<Box<dyn ChangeSource>> = Vec::new();
+    // Jobs whose changes come from QEMU, grouped by virtual machine.
+    let mut qemu_jobs: BTreeMap<&Path, Vec<(usize, &Job)>> = BTreeMap::new();
+    for (device_number, job) in manifest.jobs.iter().enumerate() {
+        if let Some(qemu) = &job.qemu {
+            qemu_jobs.entry(&qemu.qmp_socket).or_default().push((device_number, job));
+        }
+        if let Some(server) = &job.nbd_server {
+            match nbd_server::NbdServerChanges::new(device_number, job, server) {
+                Ok(x) => job_sources.push(Box::new(x)),
+                Err(e) => {
+                    let error = JobError::Untraceable(format!("could not start NBD server: {}", e));
+                    if log_channel.send(Change::JobFailed(device_number, error)).is_err() {
+                        return;
+                    }
+                },
+            }
         }
-        if let Some(_) = device_map.get_mut(&base_device_event_dev).unwrap().insert(device, i) {
-            panic!("Duplicate device found");
+    }
+    for (qmp_socket, jobs) in qemu_jobs {
+        match dirty_bitmap::DirtyBitmapChanges::new(qmp_socket, &jobs) {
+            Ok(x) => job_sources.push(Box::new(x)),
+            Err(e) => {
+                for (device_number, _) in jobs {
+                    let error = JobError::Untraceable(format!("could not start tracking changes through QEMU: {}", e));
+                    if log_channel.send(Change::JobFailed(device_number, error)).is_err() {
+                        return;
+                    }
+                }
+            },
         }
     }
-    let device_map = device_map; // Drop mutability
+
+    if let Some(change_replay) = &config.change_replay {
+        let replay = match recording::EventReplay::open(&change_replay.path, change_replay.time_scale) {
+            Ok(x) => x,
+            Err(e) => {
+                panic!("Could not start replaying events: {}", e);
+            },
+        };
+        eprintln!("Replaying recorded events from '{}' instead of tracing", change_replay.path.display());
+        relay_with(&mut ReplayedChanges {replay, mapper, lost: 0}, &mut job_sources, log_channel, control_channel);
+        return;
+    }
+
+    let event_filter = filter::EventFilter::new(config, manifest, trace_segments);
     let whole_disk_devices: Vec<&Device> =
-        devices
+        trace_segments
         .iter()
-        .map(|device| {device.get_base_device()})
+        .flatten()
+        .map(|segment| {&segment.traced})
         .collect::<BTreeSet<&Device>>() // Deduplicate and sort
         .into_iter()
         .collect();
+    if whole_disk_devices.is_empty() {
+        eprintln!("No devices need tracing");
+        relay_with(&mut NoChanges, &mut job_sources, log_channel, control_channel);
+        return;
+    }
 
-    {
-        let events_enabled = slurp_file_at_path(&config.tracing_path.join("events/enable")).unwrap();
+    let backend = choose_backend(config);
+    let use_blk_tracer = backend == TraceBackend::BlkTracer;
+    let use_bio_queue_event = backend == TraceBackend::BlockBioQueue;
+    eprintln!("Tracing block devices using {:?}", backend);
+
+    // A private instance has its own buffers and events, so doesn't clash
+    // with anything else using tracing. The blk tracer is only available
+    // globally though.
+    let use_instance = config.trace_instance && !use_blk_tracer;
+    if config.trace_instance && !use_instance {
+        eprintln!("Warning: the blk tracer cannot run in a tracing instance, so global tracing will be used");
+    }
+    // Declared first, so that it outlives every setting it journals.
+    let session = match trace_state::Session::begin(config, !use_instance) {
+        Ok(x) => x,
+        Err(e) => {
+            panic!("Could not start tracing session: {}", e);
+        },
+    };
+    let trace_root: PathBuf =
+        if use_instance {
+            config.tracing_path.join("instances").join(format!("trackup-{}-{}", std::process::id(), NEXT_INSTANCE_NUMBER.fetch_add(1, Ordering::Relaxed)))
+        } else {
+            config.tracing_path.clone()
+        };
+    // Declared before any settings, so that it is torn down last.
+    let _instance_setup = if !use_instance {None} else {Some(DoUndo::new(
+        || {
+            session.record_instance(&trace_root);
+            if let Err(e) = std::fs::create_dir(&trace_root) {
+                panic!("Could not create tracing instance '{}': {}", trace_root.display(), e);
+            }
+            eprintln!("Tracing in instance '{}'", trace_root.display());
+        },
+        || {
+            if let Err(e) = std::fs::remove_dir(&trace_root) {
+                eprintln!("Warning: could not remove tracing instance '{}': {}", trace_root.display(), e);
+            }
+        },
+    ))};
+
+    if !use_instance {
+        let events_enabled = slurp_file_at_path(&trace_root.join("events/enable")).unwrap();
         if std::str::from_utf8(&events_enabled).unwrap() != "0\n" {
-            panic!("Some tracing events are already enabled");
+            // Anything left by an earlier trackup has already been
+            // recovered, so this belongs to something else.
+            panic!("Some tracing events are already enabled by another program");
         }
     }
 
-    let old_current_tracer = slurp_file_at_path(&config.tracing_path.join("current_tracer")).unwrap();
-    let _current_tracer_setup = DoUndo::new(
-        || {append_to_file_at_path(&config.tracing_path.join("current_tracer"), b"blk\n").unwrap();},
+    let old_current_tracer = if use_blk_tracer {slurp_file_at_path(&config.tracing_path.join("current_tracer")).unwrap()} else {Vec::new()};
+    let _current_tracer_setup = if !use_blk_tracer {None} else {Some(DoUndo::new(
+        || {
+            session.record_setting(&config.tracing_path.join("current_tracer"), &old_current_tracer);
+            append_to_file_at_path(&config.tracing_path.join("current_tracer"), b"blk\n").unwrap();
+        },
         || {append_to_file_at_path(&config.tracing_path.join("current_tracer"), &old_current_tracer).warn_if_err();},
-    );
+    ))};
 
-    let old_tracer_option_bin = slurp_file_at_path(&config.tracing_path.join("options/bin")).unwrap();
-    let _tracer_option_bin_setup = DoUndo::new(
-        || {append_to_file_at_path(&config.tracing_path.join("options/bin"), b"1\n").unwrap();},
+    let old_tracer_option_bin = if use_blk_tracer {slurp_file_at_path(&config.tracing_path.join("options/bin")).unwrap()} else {Vec::new()};
+    let _tracer_option_bin_setup = if !use_blk_tracer {None} else {Some(DoUndo::new(
+        || {
+            session.record_setting(&config.tracing_path.join("options/bin"), &old_tracer_option_bin);
+            append_to_file_at_path(&config.tracing_path.join("options/bin"), b"1\n").unwrap();
+        },
         || {append_to_file_at_path(&config.tracing_path.join("options/bin"), &old_tracer_option_bin).warn_if_err();},
-    );
+    ))};
 
-    let old_tracer_option_context = slurp_file_at_path(&config.tracing_path.join("options/context-info")).unwrap();
-    let _tracer_option_context = DoUndo::new(
-        || {append_to_file_at_path(&config.tracing_path.join("options/context-info"), b"0\n").unwrap();},
+    let old_tracer_option_context = if use_blk_tracer {slurp_file_at_path(&config.tracing_path.join("options/context-info")).unwrap()} else {Vec::new()};
+    let _tracer_option_context = if !use_blk_tracer {None} else {Some(DoUndo::new(
+        || {
+            session.record_setting(&config.tracing_path.join("options/context-info"), &old_tracer_option_context);
+            append_to_file_at_path(&config.tracing_path.join("options/context-info"), b"0\n").unwrap();
+        },
         || {append_to_file_at_path(&config.tracing_path.join("options/context-info"), &old_tracer_option_context).warn_if_err();},
-    );
+    ))};
 
-    let old_buffer_size = slurp_file_at_path(&config.tracing_path.join("buffer_size_kb")).unwrap();
+    let old_buffer_size = slurp_file_at_path(&trace_root.join("buffer_size_kb")).unwrap();
     let _buffer_size = DoUndo::new(
-        || {append_to_file_at_path(&config.tracing_path.join("buffer_size_kb"), format!("{}\n", config.trace_buffer_size).as_bytes()).unwrap();},
-        || {append_to_file_at_path(&config.tracing_path.join("buffer_size_kb"), &old_buffer_size).warn_if_err();},
+        || {
+            session.record_setting(&trace_root.join("buffer_size_kb"), &old_buffer_size);
+            append_to_file_at_path(&trace_root.join("buffer_size_kb"), format!("{}\n", config.trace_buffer_size).as_bytes()).unwrap();
+        },
+        || {append_to_file_at_path(&trace_root.join("buffer_size_kb"), &old_buffer_size).warn_if_err();},
     );
 
-    let trace_pipe_fd = unsafe {
-        libc::open(CString::new(config.tracing_path.join("trace_pipe").to_str().unwrap()).unwrap().as_ptr(), libc::O_RDONLY | libc::O_NONBLOCK)
-    };
-    if trace_pipe_fd < 0 {
-        panic!("Could not open trace pipe");
-    }
-
-    // Flush anything in the trace_pipe first so we know we're only going
-    // to get blk data.
-    {
-        let trace_pipe_file = unsafe{
-            libc::fdopen(trace_pipe_fd, b"rb\0".as_ptr() as *const c_char)
-        };
-        // I'm assuming the stream can't ever half-write a data structure.
-        unsafe{
-            while libc::fgetc(trace_pipe_file) >= 0 {
-            };
+    let event_reader =
+        if use_blk_tracer {
+            open_event_reader(config, &trace_root)
+        } else {
+            EventReader::Text(bio_queue::TextReader::open(&trace_root.join("trace_pipe")).unwrap())
         };
-    }
+
+    // Take the loss baseline before any device is traced, so nothing lost
+    // once tracing starts can go unnoticed.
+    let trace_loss_monitor = TraceLossMonitor::new(&trace_root);
+
+    let recorder = config.trace_recording.as_ref().map(
+        |path| {
+            match recording::EventRecorder::create(path) {
+                Ok(x) => x,
+                Err(e) => {
+                    panic!("Could not start recording events: {}", e);
+                },
+            }
+        }
+    );
 
     // Use whole disk devices, as they're unique, and they'll give us good defaults.
-    let old_block_trace_enables: Vec<(Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>)> = whole_disk_devices.iter().map(
+    let old_block_trace_enables: Vec<(Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>)> = if !use_blk_tracer {Vec::new()} else {whole_disk_devices.iter().map(
         |device| {
             (
                 slurp_file_at_path(&device.sys_dev_path.join("trace/act_mask")).unwrap(),
@@ -218,9 +993,16 @@ pub fn run(config: &Config, manifest: &Manifest, devices: &Vec<Device>, log_chan
                 slurp_file_at_path(&device.sys_dev_path.join("trace/enable")).unwrap()
             )
         }
-    ).collect();
-    let _block_trace_enable_setup = DoUndo::new(
+    ).collect()};
+    let _block_trace_enable_setup = if !use_blk_tracer {None} else {Some(DoUndo::new(
         || {
+            for (device, old_block_trace_enable) in whole_disk_devices.iter().zip(&old_block_trace_enables) {
+                let (act_mask, start_lba, end_lba, enable) = old_block_trace_enable;
+                session.record_setting(&device.sys_dev_path.join("trace/act_mask"), act_mask);
+                session.record_setting(&device.sys_dev_path.join("trace/start_lba"), start_lba);
+                session.record_setting(&device.sys_dev_path.join("trace/end_lba"), end_lba);
+                session.record_setting(&device.sys_dev_path.join("trace/enable"), enable);
+            }
             for device in &whole_disk_devices {
                 append_to_file_at_path(&device.sys_dev_path.join("trace/act_mask"), b"queue\n").unwrap();
                 append_to_file_at_path(&device.sys_dev_path.join("trace/start_lba"), b"0\n").unwrap();
@@ -229,90 +1011,181 @@ pub fn run(config: &Config, manifest: &Manifest, devices: &Vec<Device>, log_chan
             }
         },
         || {
-            for (device, old_block_trace_enable) in whole_disk_devices.iter().zip(&old_block_trace_enables) {
+            // The kernel tears down the traces of removed devices itself.
+            let present_devices: Vec<_> = whole_disk_devices.iter().zip(&old_block_trace_enables).filter(
+                |(device, _)| {
+                    if !device.is_present() {
+                        eprintln!("Device {}:{} has been removed, so its trace is already gone", device.major, device.minor);
+                    }
+                    device.is_present()
+                }
+            ).collect();
+            for (device, old_block_trace_enable) in &present_devices {
                 let (act_mask, start_lba, end_lba, _) = old_block_trace_enable;
                 append_to_file_at_path(&device.sys_dev_path.join("trace/end_lba"), &end_lba).warn_if_err();
                 append_to_file_at_path(&device.sys_dev_path.join("trace/start_lba"), &start_lba).warn_if_err();
                 append_to_file_at_path(&device.sys_dev_path.join("trace/act_mask"), &act_mask).warn_if_err();
             }
-            for (device, old_block_trace_enable) in whole_disk_devices.iter().zip(&old_block_trace_enables) {
+            for (device, old_block_trace_enable) in &present_devices {
                 let (_, _, _, enable) = old_block_trace_enable;
-                append_to_file_at_path(&device.sys_dev_path.join("trace/enable"), &enable).unwrap();
+                append_to_file_at_path(&device.sys_dev_path.join("trace/enable"), &enable).warn_if_err();
             }
         }
-    );
+    ))};
+
+    // The tracepoint equivalent of the above. The filter is set before the
+    // event is enabled, so we never see events for other devices.
+    let bio_queue_event_path = trace_root.join("events/block/block_bio_queue");
+    let old_bio_queue_filter = if use_bio_queue_event {read_event_filter(&bio_queue_event_path.join("filter"))} else {Vec::new()};
+    let _bio_queue_event_setup = if !use_bio_queue_event {None} else {Some(DoUndo::new(
+        || {
+            let event_devs: Vec<u32> = whole_disk_devices.iter().map(|device| {device.event_dev}).collect();
+            session.record_setting(&bio_queue_event_path.join("filter"), &old_bio_queue_filter);
+            session.record_setting(&bio_queue_event_path.join("enable"), b"0\n");
+            append_to_file_at_path(&bio_queue_event_path.join("filter"), format!("{}\n", bio_queue::device_filter(&event_devs)).as_bytes()).unwrap();
+            append_to_file_at_path(&bio_queue_event_path.join("enable"), b"1\n").unwrap();
+        },
+        || {
+            append_to_file_at_path(&bio_queue_event_path.join("enable"), b"0\n").warn_if_err();
+            append_to_file_at_path(&bio_queue_event_path.join("filter"), &old_bio_queue_filter).warn_if_err();
+        }
+    ))};
 
+    let mut traced = TracedChanges {
+        event_reader,
+        trace_loss_monitor,
+        event_filter,
+        mapper,
+        recorder,
+        use_blk_tracer,
+        cpus: list_trace_cpus(&trace_root),
+        trace_root: trace_root.clone(),
+        barrier: None,
+        buffer_sizer: if config.trace_buffer_min_size == config.trace_buffer_max_size {None} else {
+            Some(buffer_sizer::BufferSizer::new(config.trace_buffer_min_size, config.trace_buffer_max_size, config.trace_buffer_size, Instant::now()))
+        },
+    };
+    relay_with(&mut traced, &mut job_sources, log_channel, control_channel);
+    if traced.event_filter.get_ignored_events() > 0 {
+        eprintln!("Ignored {} filtered write events", traced.event_filter.get_ignored_events());
+    }
+}
+
+/// Relay changes from a source along with those from the jobs' own sources.
+fn relay_with(source: &mut dyn ChangeSource, job_sources: &mut [Box<dyn ChangeSource>], log_channel: Sender<Change>, control_channel: Receiver<Control>) {
+    if job_sources.is_empty() {
+        relay(source, log_channel, control_channel);
+        return;
+    }
+    let mut sources: Vec<&mut dyn ChangeSource> = vec![source];
+    sources.extend(job_sources.iter_mut().map(|source| -> &mut dyn ChangeSource {source.as_mut()}));
+    relay(&mut CombinedChanges {sources, next: 0}, log_channel, control_channel);
+}
+
+/// Pass on changes from a source to the copier, until the copier goes away.
+pub fn relay(source: &mut dyn ChangeSource, log_channel: Sender<Change>, control_channel: Receiver<Control>) {
     let continuing = Cell::new(true);
+    let mut last_trace_loss_check = Instant::now();
 
     // Returns bool for whether or not something was read.
-    let consume_event = || {
-        match BlkEvent::try_read_from_file(trace_pipe_fd) {
+    let consume_event = |source: &mut dyn ChangeSource| {
+        match source.try_read() {
             None => {
                 false
             },
-            Some(event) => {
-                let category = event.action >> 16;
-                let action = event.action & 0xffff;
-                let absolute_sector: u64 = event.sector;
-                let bytes: u64 = event.bytes as u64;
-
-                if category & 0x0002 == 0 {
-                    // Was not a write operation, so we don't care.
-                    return true;
-                }
-                if action != 1 {
-                    // Was not a QUEUE action.
-                    return true;
-                }
-                if bytes == 0 {
-                    // There is no data location associated, so skip.
-                    return true;
-                }
-                let event_dev = event.device;
-
-                if let Some(child_devices) = device_map.get(&event_dev) {
-                    // child_device may contain both a whole disk AND partitions.
-                    for (device, device_number) in child_devices {
-                        if device.start_sector <= absolute_sector && absolute_sector < device.end_sector {
-                            let chunk_size = manifest.jobs[*device_number].chunk_size as u64;
-                            let relative_sector: u64 = absolute_sector - device.start_sector;
-                            let first_byte: u64 = relative_sector * 512; // I think a sector is always 512 on Linux?
-                            let last_byte: u64 = first_byte + bytes - 1;
-                            let first_chunk: usize = (first_byte / chunk_size) as usize;
-                            let last_chunk: usize = (last_byte / chunk_size) as usize;
-
-                            if last_byte >= device.sector_count * 512 {
-                                // This might be violated if we're tracing a partition whilst a whole disk is modified!
-                                // As such, this should not panic, but a warning may be useful.
-                                eprintln!("Traced operation extends beyond end of device. This may happen if a device has been extended, or if a whole disk is modified whilst a partition is being traced. Event is from {} to {}, but matched device ({}:{}) is from {} to {}. Event: {:?}", absolute_sector, absolute_sector + bytes/512, device.major, device.minor, device.start_sector, device.end_sector, event);
-                            }
-
-                            for change_index in first_chunk..(last_chunk+1) {
-                                if let Err(_) = log_channel.send((*device_number, change_index)) {
-                                    continuing.set(false);
-                                    return true;
-                                }
-                            }
-                            // We might be tracing both a whole disk AND a partition, so don't break!
-                        }
+            Some(ranges) => {
+                for range in ranges {
+                    if log_channel.send(Change::Chunks(range)).is_err() {
+                        continuing.set(false);
+                        return true;
                     }
-                };
+                }
                 true
             },
         }
     };
+    // Returns bool for whether or not the copier can still be reached.
+    let mut report_trace_loss = |source: &mut dyn ChangeSource, force: bool| {
+        if !force && last_trace_loss_check.elapsed() < TRACE_LOSS_CHECK_PERIOD {
+            return true;
+        }
+        last_trace_loss_check = Instant::now();
+        let lost = source.check_lost();
+        if lost > 0 {
+            eprintln!("Warning: {} trace events were lost. All devices will be treated as dirty. Consider increasing the trace buffer size.", lost);
+            if log_channel.send(Change::EventsLost(lost)).is_err() {
+                return false;
+            }
+        }
+        if let Some(size) = source.adjust_buffer_size() {
+            if log_channel.send(Change::BufferSize(size)).is_err() {
+                return false;
+            }
+        }
+        true
+    };
     while continuing.get() {
-        match sync_barrier_channel.try_recv() {
-            Ok(barrier) => {
+        match control_channel.try_recv() {
+            Ok(Control::Sync(barrier)) => {
                 eprintln!("Syncing...");
-                while consume_event() {}
+                // The copier has synced, so everything it is waiting for was
+                // traced before the barrier.
+                // Sources which don't need barriers have reported everything
+                // once they have been read until empty. A barrier which can't
+                // be placed or passed means the sync may have missed changes.
+                let barrier_passed = match source.place_barrier() {
+                    Ok(true) => {
+                        let barrier_start = Instant::now();
+                        let mut passed = true;
+                        while continuing.get() && !source.passed_barrier() {
+                            if barrier_start.elapsed() > BARRIER_TIMEOUT {
+                                eprintln!("Warning: timed out waiting for trace barrier");
+                                passed = false;
+                                break;
+                            }
+                            consume_event(source);
+                        }
+                        passed
+                    },
+                    Ok(false) => true,
+                    Err(e) => {
+                        eprintln!("Warning: {}", e);
+                        false
+                    },
+                };
+                if !barrier_passed && log_channel.send(Change::BarrierFailed).is_err() {
+                    continuing.set(false);
+                }
+                while consume_event(source) {}
+                // Anything lost up to now must reach the copier before it
+                // is allowed to consider itself consistent.
+                if !report_trace_loss(source, true) {
+                    continuing.set(false);
+                }
+                barrier.wait();
+            },
+            Ok(Control::UpdateDevice {device_number, device, segments, barrier}) => {
+                // Anything already queued was mapped using the old geometry.
+                while consume_event(source) {}
+                source.update_device(device_number, &device, segments);
+                barrier.wait();
+            },
+            Ok(Control::HoldWrites(barrier)) => {
+                source.hold_writes(true);
+                while consume_event(source) {}
                 barrier.wait();
             },
+            Ok(Control::ReleaseWrites) => {
+                source.hold_writes(false);
+            },
             Err(std::sync::mpsc::TryRecvError::Empty) => {
                 // Does not block
-                if !consume_event() {
+                if !consume_event(source) {
                     std::thread::yield_now();
                 }
+                if !report_trace_loss(source, false) {
+                    continuing.set(false);
+                }
             },
             Err(std::sync::mpsc::TryRecvError::Disconnected) => {
                 continuing.set(false);
@@ -320,3 +1193,172 @@ pub fn run(config: &Config, manifest: &Manifest, devices: &Vec<Device>, log_chan
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::interface::Internalize;
+    use std::sync::mpsc::channel;
+    use crate::control::ChangeReplay;
+    use crate::test_util::{test_device,test_dir,test_job,test_manifest};
+
+    #[test]
+    fn test_parse_lost_event_count() {
+        let stats = "entries: 12\noverrun: 3\ncommit overrun: 1\nbytes: 4096\noldest event ts:  1234.567890\nnow ts:  1240.000000\ndropped events: 5\nread events: 100\n";
+        assert_eq!(parse_lost_event_count(stats), 9);
+        assert_eq!(parse_lost_event_count("entries: 0\noverrun: 0\n"), 0);
+        assert_eq!(parse_lost_event_count(""), 0);
+    }
+
+    #[test]
+    fn test_event_mapper() {
+        let device = test_device(8, 0, 100);
+        let config = crate::control::interface::Config::default().internalize().unwrap();
+        let segments = device.trace_segments(&config, crate::control::TraceLayer::Top).unwrap();
+        let mapper = EventMapper {
+            chunk_sizes: vec![4096],
+            device_sizes: vec![100 * SECTOR_SIZE],
+            segment_map: build_segment_map(&[segments]),
+        };
+        let range = |chunks: Range<usize>, kind: ChangeKind| {ChunkRange {device_number: 0, chunks, kind}};
+
+        assert_eq!(mapper.map(8 << 20, 0, 1, ChangeKind::Write), vec![range(0..1, ChangeKind::Write)]);
+        // Only the partially discarded first chunk needs copying.
+        assert_eq!(mapper.map(8 << 20, 4, 40, ChangeKind::Discard), vec![
+            range(0..1, ChangeKind::Write),
+            range(1..5, ChangeKind::Discard),
+        ]);
+        // The last chunk is short, so is wholly discarded.
+        assert_eq!(mapper.map(8 << 20, 96, 100, ChangeKind::Discard), vec![range(12..13, ChangeKind::Discard)]);
+        assert_eq!(mapper.map(8 << 20, 2, 4, ChangeKind::Discard), vec![range(0..1, ChangeKind::Write)]);
+        assert_eq!(mapper.map(9 << 20, 0, 8, ChangeKind::Write), vec![]);
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = test_dir("replay");
+        let event = |sector: u64, bytes: u32, category: u32| {BlkEvent {
+            magic: MAGIC_NATIVE_ENDIAN | SUPPORTED_VERSION as u32,
+            sequence: 0,
+            time: 0,
+            sector,
+            bytes,
+            action: (category << 16) | BLK_TA_QUEUE,
+            pid: 1,
+            device: 8 << 20,
+            cpu: 0,
+            error: 0,
+            pdu_len: 0,
+        }};
+        {
+            let mut recorder = recording::EventRecorder::create(&dir.join("events")).unwrap();
+            recorder.record_event(&event(8, 4096, BLK_TC_WRITE));
+            recorder.record_event(&event(16, 8192, BLK_TC_WRITE | BLK_TC_DISCARD));
+            // Reads don't change anything.
+            recorder.record_event(&event(40, 4096, 0));
+            recorder.record_lost(3);
+        }
+        let mut config = crate::control::interface::Config::default().internalize().unwrap();
+        config.change_replay = Some(ChangeReplay {path: dir.join("events"), time_scale: 0.0});
+        let manifest = test_manifest(vec![test_job(Path::new("/dev/sda"), &dir.join("backup.img"))]);
+        let device = test_device(8, 0, 100);
+        let trace_segments = vec![device.trace_segments(&config, crate::control::TraceLayer::Top).unwrap()];
+
+        let (log_produce, log_consume) = channel();
+        let (control_produce, control_consume) = channel();
+        let logger = std::thread::spawn(move || {
+            run(&config, &manifest, &[device], &trace_segments, log_produce, control_consume);
+        });
+        let barrier = Arc::new(Barrier::new(2));
+        control_produce.send(Control::Sync(Arc::clone(&barrier))).unwrap();
+        barrier.wait();
+
+        // Everything recorded has been reported by the time the sync is done.
+        let mut ranges = Vec::new();
+        let mut lost = 0;
+        for change in log_consume.try_iter() {
+            match change {
+                Change::Chunks(range) => ranges.push(range),
+                Change::EventsLost(count) => lost += count,
+                _ => panic!("Unexpected change"),
+            }
+        }
+        assert_eq!(ranges, vec![
+            ChunkRange {device_number: 0, chunks: 1..2, kind: ChangeKind::Write},
+            ChunkRange {device_number: 0, chunks: 2..4, kind: ChangeKind::Discard},
+        ]);
+        assert_eq!(lost, 3);
+
+        drop(control_produce);
+        logger.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_nbd_server_unusable_socket() {
+        let dir = test_dir("nbd-start");
+        recording::EventRecorder::create(&dir.join("events")).unwrap();
+        let source = dir.join("source.img");
+        std::fs::write(&source, vec![0; 4096]).unwrap();
+        let mut config = crate::control::interface::Config::default().internalize().unwrap();
+        config.change_replay = Some(ChangeReplay {path: dir.join("events"), time_scale: 0.0});
+        let job = Job {
+            nbd_server: Some(crate::control::NbdServer {socket: dir.join("missing").join("nbd.sock"), export_name: String::new()}),
+            ..test_job(&source, &dir.join("backup.img"))
+        };
+        let manifest = test_manifest(vec![job]);
+        let device = test_device(8, 0, 8);
+        let trace_segments = vec![device.trace_segments(&config, crate::control::TraceLayer::Top).unwrap()];
+
+        let (log_produce, log_consume) = channel();
+        let (control_produce, control_consume) = channel();
+        let logger = std::thread::spawn(move || {
+            run(&config, &manifest, &[device], &trace_segments, log_produce, control_consume);
+        });
+        // Only the job fails, and the logger carries on.
+        match log_consume.recv().unwrap() {
+            Change::JobFailed(0, JobError::Untraceable(message)) => assert!(message.starts_with("could not start NBD server: "), "{}", message),
+            _ => panic!("Unexpected change"),
+        }
+        let barrier = Arc::new(Barrier::new(2));
+        control_produce.send(Control::Sync(Arc::clone(&barrier))).unwrap();
+        barrier.wait();
+
+        drop(control_produce);
+        logger.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_marker_on_unusable_cpu() {
+        let dir = test_dir("marker");
+        let trace_marker_path = dir.join("trace_marker");
+        std::fs::write(&trace_marker_path, b"").unwrap();
+        // Not a CPU there is any way of running on.
+        assert!(write_marker_on_cpus(&trace_marker_path, &[libc::CPU_SETSIZE as usize - 1], "barrier").is_err());
+        assert_eq!(std::fs::read(&trace_marker_path).unwrap(), b"");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_barrier() {
+        struct Unplaceable;
+        impl ChangeSource for Unplaceable {
+            fn try_read(&mut self) -> Option<Vec<ChunkRange>> {
+                None
+            }
+            fn place_barrier(&mut self) -> Result<bool,String> {
+                Err(String::from("No CPUs"))
+            }
+        }
+        let (log_produce, log_consume) = channel();
+        let (control_produce, control_consume) = channel();
+        let relay_thread = std::thread::spawn(move || {relay(&mut Unplaceable, log_produce, control_consume)});
+        let barrier = Arc::new(Barrier::new(2));
+        control_produce.send(Control::Sync(Arc::clone(&barrier))).unwrap();
+        barrier.wait();
+        assert!(matches!(log_consume.try_recv(), Ok(Change::BarrierFailed)));
+        drop(control_produce);
+        relay_thread.join().unwrap();
+    }
+}
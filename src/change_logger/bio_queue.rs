@@ -0,0 +1,239 @@
+// Reads events from the block:block_bio_queue tracepoint via the text
+// trace_pipe. This works on kernels without the blk tracer, and doesn't
+// conflict with blktrace.
+//
+// Lines look something like this (with the default trace options):
+//
+//   kworker/u8:2-1234    [001] d..1.  1234.567890: block_bio_queue: 8,0 WS 123456 + 8 [kworker/u8:2]
+//
+// Each event is translated into the equivalent BlkEvent, so the rest of the
+// change logger doesn't need to care which backend is in use. Writes to
+// trace_marker show up as tracing_mark_write lines.
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use libc::{c_int,c_void,size_t};
+use crate::device::SECTOR_SIZE;
+use crate::quick_io::fd_poll_read;
+use super::{BlkEvent,TraceEvent,MAGIC_NATIVE_ENDIAN,SUPPORTED_VERSION,BLK_TC_WRITE,BLK_TC_DISCARD,BLK_TA_QUEUE};
+
+const EVENT_TAG: &str = "block_bio_queue: ";
+const MARKER_TAG: &str = "tracing_mark_write: ";
+
+/// Build a tracepoint filter which only accepts events on the given devices
+/// which might modify data (see rwbs_to_category).
+pub fn device_filter(event_devs: &[u32]) -> String {
+    let devs = event_devs.iter().map(|dev| {format!("dev == {}", dev)}).collect::<Vec<String>>().join(" || ");
+    format!("({}) && (rwbs ~ \"*W*\" || rwbs ~ \"*D*\" || rwbs ~ \"*N*\")", devs)
+}
+
+/// Translate the rwbs flags from the tracepoint into blk tracer categories.
+fn rwbs_to_category(rwbs: &str) -> u32 {
+    let mut category = 0;
+    if rwbs.contains('R') {
+        // Reads never change anything.
+        return category;
+    }
+    if rwbs.contains('D') {
+        category |= BLK_TC_DISCARD | BLK_TC_WRITE;
+    }
+    // Anything else which isn't purely a flush could modify data. 'N' is
+    // used for operations the tracepoint has no letter for (e.g. write
+    // zeroes), so those are treated as writes to be safe.
+    if rwbs.contains('W') || rwbs.contains('N') {
+        category |= BLK_TC_WRITE;
+    }
+    category
+}
+
+/// Find the given event tag in a line, returning the context before it.
+fn find_tag<'l>(line: &'l str, tag: &str) -> Option<(&'l str, &'l str)> {
+    let tag_index = line.find(tag)?;
+    let context = &line[..tag_index];
+    if !(context.is_empty() || context.ends_with(": ")) {
+        return None;
+    }
+    Some((context, &line[tag_index+tag.len()..]))
+}
+
+/// Parse the text of a trace_marker write from a line of trace_pipe output.
+pub fn parse_marker(line: &str) -> Option<&str> {
+    let (_, marker) = find_tag(line, MARKER_TAG)?;
+    Some(marker.trim_end())
+}
+
+/// Parse a single line of trace_pipe output. Returns None for lines which
+/// aren't block_bio_queue events.
+pub fn parse_line(line: &str) -> Option<BlkEvent> {
+    let (context, fields) = find_tag(line, EVENT_TAG)?;
+    let mut fields = fields.split_whitespace();
+
+    let mut major_minor = fields.next()?.split(',');
+    let major: u32 = major_minor.next()?.parse().ok()?;
+    let minor: u32 = major_minor.next()?.parse().ok()?;
+    let rwbs = fields.next()?;
+    let sector: u64 = fields.next()?.parse().ok()?;
+    if fields.next()? != "+" {
+        return None;
+    }
+    let sectors: u32 = fields.next()?.parse().ok()?;
+
+    // The context is only present with the context-info option, so don't
+    // insist on it.
+    let (pid, cpu): (u32, u32) =
+        match context.find(" [") {
+            Some(cpu_index) => {
+                let pid = context[..cpu_index].trim().rsplit('-').next().and_then(|pid| {pid.parse().ok()}).unwrap_or(0);
+                let cpu = context[cpu_index+2..].split(']').next().and_then(|cpu| {cpu.trim().parse().ok()}).unwrap_or(0);
+                (pid, cpu)
+            },
+            None => (0, 0),
+        };
+
+    Some(BlkEvent {
+        magic:    MAGIC_NATIVE_ENDIAN | SUPPORTED_VERSION as u32,
+        sequence: 0,
+        time:     0,
+        sector,
+        bytes:    sectors * SECTOR_SIZE as u32,
+        action:   (rwbs_to_category(rwbs) << 16) | BLK_TA_QUEUE,
+        pid,
+        device:   (major << 20) | minor,
+        cpu,
+        error:    0,
+        pdu_len:  0,
+    })
+}
+
+/// Reads block_bio_queue events and markers, line by line, from the text
+/// trace_pipe.
+pub struct TextReader {
+    trace_pipe_fd: c_int,
+    pending: RefCell<Vec<u8>>,
+}
+
+impl TextReader {
+    /// Open the trace pipe and discard anything already in it.
+    pub fn open(trace_pipe_path: &std::path::Path) -> Result<Self,String> {
+        let trace_pipe_fd = unsafe {
+            libc::open(CString::new(trace_pipe_path.to_str().unwrap()).unwrap().as_ptr(), libc::O_RDONLY | libc::O_NONBLOCK)
+        };
+        if trace_pipe_fd < 0 {
+            return Err(format!("Could not open '{}'", trace_pipe_path.display()));
+        }
+        let reader = Self {
+            trace_pipe_fd,
+            pending: RefCell::new(Vec::new()),
+        };
+        while reader.fill() {}
+        reader.pending.borrow_mut().clear();
+        Ok(reader)
+    }
+
+    /// Read whatever is available into the pending buffer. Returns false if
+    /// there was nothing to read.
+    fn fill(&self) -> bool {
+        let mut buf: [u8; 4096] = [0; 4096];
+        let bytes_read = unsafe {
+            libc::read(self.trace_pipe_fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t)
+        };
+        if bytes_read > 0 {
+            self.pending.borrow_mut().extend_from_slice(&buf[..bytes_read as usize]);
+            true
+        } else if bytes_read == 0 {
+            false
+        } else {
+            let errno = unsafe {*libc::__errno_location()};
+            if errno == libc::EAGAIN || errno == libc::EWOULDBLOCK || errno == libc::EINTR {
+                false
+            } else {
+                panic!("Could not read from trace pipe");
+            }
+        }
+    }
+
+    fn take_line(&self) -> Option<String> {
+        let mut pending = self.pending.borrow_mut();
+        let newline = pending.iter().position(|b| {*b == b'\n'})?;
+        let line: Vec<u8> = pending.drain(..newline+1).collect();
+        Some(String::from_utf8_lossy(&line[..newline]).into_owned())
+    }
+
+    /// Return the next block_bio_queue event or marker, waiting up to 1ms
+    /// for one.
+    pub fn try_read(&self) -> Option<TraceEvent> {
+        let mut waited = false;
+        loop {
+            while let Some(line) = self.take_line() {
+                if let Some(event) = parse_line(&line) {
+                    return Some(TraceEvent::Blk(event));
+                }
+                if let Some(marker) = parse_marker(&line) {
+                    return Some(TraceEvent::Marker(marker.to_string()));
+                }
+            }
+            if !self.fill() {
+                if waited || !fd_poll_read(self.trace_pipe_fd, 1) {
+                    return None;
+                }
+                waited = true;
+            }
+        }
+    }
+}
+
+impl Drop for TextReader {
+    fn drop(&mut self) {
+        unsafe {libc::close(self.trace_pipe_fd)};
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_write() {
+        let event = parse_line("    kworker/u8:2-1234    [001] d..1.  1234.567890: block_bio_queue: 8,16 WS 123456 + 8 [kworker/u8:2]").unwrap();
+        let (sector, bytes, action, pid, device, cpu) = (event.sector, event.bytes, event.action, event.pid, event.device, event.cpu);
+        assert_eq!(sector, 123456);
+        assert_eq!(bytes, 4096);
+        assert_eq!(action, (BLK_TC_WRITE << 16) | BLK_TA_QUEUE);
+        assert_eq!(pid, 1234);
+        assert_eq!(device, (8 << 20) | 16);
+        assert_eq!(cpu, 1);
+    }
+
+    #[test]
+    fn test_parse_other_operations() {
+        let read = parse_line("dd-99 [000] .... 1.0: block_bio_queue: 8,0 RA 0 + 8 [dd]").unwrap();
+        let action = read.action;
+        assert_eq!(action >> 16, 0);
+
+        let discard = parse_line("fstrim-5 [002] .... 1.0: block_bio_queue: 259,1 D 2048 + 1024 [fstrim]").unwrap();
+        let action = discard.action;
+        assert_eq!(action >> 16, BLK_TC_DISCARD | BLK_TC_WRITE);
+
+        let no_context = parse_line("block_bio_queue: 8,0 FWS 0 + 0 [jbd2/sda1-8]").unwrap();
+        let (pid, bytes) = (no_context.pid, no_context.bytes);
+        assert_eq!((pid, bytes), (0, 0));
+    }
+
+    #[test]
+    fn test_parse_unrelated_lines() {
+        assert!(parse_line("bash-1 [000] .... 1.0: tracing_mark_write: hello").is_none());
+        assert!(parse_line("bash-1 [000] .... 1.0: block_bio_queue: garbage").is_none());
+        assert!(parse_line("").is_none());
+    }
+
+    #[test]
+    fn test_parse_marker() {
+        assert_eq!(parse_marker("trackup-42 [003] ..... 1.0: tracing_mark_write: trackup-barrier-42-0\n"), Some("trackup-barrier-42-0"));
+        assert_eq!(parse_marker("dd-99 [000] .... 1.0: block_bio_queue: 8,0 W 0 + 8 [dd]"), None);
+    }
+
+    #[test]
+    fn test_device_filter() {
+        assert_eq!(device_filter(&[(8 << 20), (259 << 20) | 1]), "(dev == 8388608 || dev == 271581185) && (rwbs ~ \"*W*\" || rwbs ~ \"*D*\" || rwbs ~ \"*N*\")");
+    }
+}
@@ -0,0 +1,167 @@
+// Decides how big the per-CPU trace buffers should be, based on how busy
+// they are.
+//
+// Each CPU's stats file says how many bytes are waiting to be read, and how
+// many events have gone through the buffer. From those, we work out how full
+// the fullest buffer could get if we stopped reading for BACKLOG_HEADROOM
+// (e.g. whilst busy syncing), and double the buffer size whenever that's
+// more than half of it. Once the buffers have been nearly empty for a while,
+// they are halved again, but only when nothing is waiting to be read, as
+// shrinking a buffer throws away whatever was in the pages it loses, and
+// that would look like lost events.
+
+use std::time::{Duration,Instant};
+
+// How long a stall in reading the buffers should be survivable for.
+const BACKLOG_HEADROOM: Duration = Duration::from_secs(1);
+// Fractions of the buffer size which trigger growing and allow shrinking.
+const GROW_THRESHOLD: f64 = 0.5;
+const SHRINK_THRESHOLD: f64 = 0.125;
+// How long the buffers must be nearly empty before shrinking.
+const QUIET_PERIOD: Duration = Duration::from_secs(30);
+// A guess at the size of a buffer entry, until we've seen some.
+const DEFAULT_ENTRY_BYTES: f64 = 64.0;
+
+/// The parts of a per-CPU trace `stats` file we care about.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct CpuStats {
+    /// Entries waiting to be read.
+    pub entries: u64,
+    /// Bytes waiting to be read.
+    pub bytes: u64,
+    /// Entries read since the buffer was created.
+    pub read_events: u64,
+}
+
+pub fn parse_cpu_stats(stats: &str) -> CpuStats {
+    let mut cpu_stats = CpuStats::default();
+    for line in stats.lines() {
+        let mut parts = line.splitn(2, ':');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim().parse().unwrap_or(0)),
+            _ => continue,
+        };
+        match key {
+            "entries" => cpu_stats.entries = value,
+            "bytes" => cpu_stats.bytes = value,
+            "read events" => cpu_stats.read_events = value,
+            _ => {},
+        }
+    }
+    cpu_stats
+}
+
+pub struct BufferSizer {
+    min_size_kb: usize,
+    max_size_kb: usize,
+    size_kb: usize,
+    previous_size_kb: usize,
+    entry_bytes: f64,
+    // Entries which had passed through each CPU's buffer at the last check.
+    last_totals: Vec<u64>,
+    last_check: Option<Instant>,
+    quiet_since: Instant,
+}
+
+impl BufferSizer {
+    /// The bounds are widened if needed to include the starting size.
+    pub fn new(min_size_kb: usize, max_size_kb: usize, size_kb: usize, now: Instant) -> Self {
+        Self {
+            min_size_kb: std::cmp::min(min_size_kb, size_kb),
+            max_size_kb: std::cmp::max(max_size_kb, size_kb),
+            size_kb,
+            previous_size_kb: size_kb,
+            entry_bytes: DEFAULT_ENTRY_BYTES,
+            last_totals: Vec::new(),
+            last_check: None,
+            quiet_since: now,
+        }
+    }
+
+    fn resize(&mut self, size_kb: usize, now: Instant) -> Option<usize> {
+        self.previous_size_kb = self.size_kb;
+        self.size_kb = size_kb;
+        self.quiet_since = now;
+        Some(size_kb)
+    }
+
+    /// Look at the latest statistics for every CPU, and return the new
+    /// buffer size in KB if it should change.
+    pub fn observe(&mut self, stats: &[CpuStats], now: Instant) -> Option<usize> {
+        let elapsed = self.last_check.map(|last_check| {now.duration_since(last_check).as_secs_f64()}).unwrap_or(0.0);
+        let totals: Vec<u64> = stats.iter().map(|cpu_stats| {cpu_stats.entries + cpu_stats.read_events}).collect();
+        let mut peak_bytes: f64 = 0.0;
+        for (cpu, cpu_stats) in stats.iter().enumerate() {
+            if cpu_stats.entries > 0 {
+                self.entry_bytes = cpu_stats.bytes as f64 / cpu_stats.entries as f64;
+            }
+            let rate =
+                match self.last_totals.get(cpu) {
+                    Some(last_total) if elapsed > 0.0 => totals[cpu].saturating_sub(*last_total) as f64 / elapsed,
+                    _ => 0.0,
+                };
+            let projected_bytes = cpu_stats.bytes as f64 + rate * self.entry_bytes * BACKLOG_HEADROOM.as_secs_f64();
+            peak_bytes = peak_bytes.max(projected_bytes);
+        }
+        self.last_totals = totals;
+        self.last_check = Some(now);
+
+        let size_bytes = (self.size_kb * 1024) as f64;
+        if peak_bytes > size_bytes * GROW_THRESHOLD {
+            self.quiet_since = now;
+            if self.size_kb < self.max_size_kb {
+                return self.resize(std::cmp::min(self.size_kb * 2, self.max_size_kb), now);
+            }
+        } else if peak_bytes > size_bytes * SHRINK_THRESHOLD {
+            self.quiet_since = now;
+        } else if now.duration_since(self.quiet_since) >= QUIET_PERIOD && self.size_kb > self.min_size_kb && stats.iter().all(|cpu_stats| {cpu_stats.entries == 0}) {
+            return self.resize(std::cmp::max(self.size_kb / 2, self.min_size_kb), now);
+        }
+        None
+    }
+
+    /// The kernel wouldn't take the last size, so go back to the previous
+    /// one and don't try growing past it again.
+    pub fn resize_failed(&mut self) {
+        if self.size_kb > self.previous_size_kb {
+            self.max_size_kb = self.previous_size_kb;
+        }
+        self.size_kb = self.previous_size_kb;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_stats() {
+        let stats = "entries: 12\noverrun: 3\ncommit overrun: 1\nbytes: 4096\noldest event ts:  1234.567890\nnow ts:  1240.000000\ndropped events: 5\nread events: 100\n";
+        assert_eq!(parse_cpu_stats(stats), CpuStats {entries: 12, bytes: 4096, read_events: 100});
+    }
+
+    #[test]
+    fn test_buffer_sizer() {
+        let start = Instant::now();
+        let at = |millis: u64| {start + Duration::from_millis(millis)};
+        let stats = |entries: u64, read_events: u64| {CpuStats {entries, bytes: entries * 64, read_events}};
+        let mut sizer = BufferSizer::new(1024, 4096, 2048, start);
+        assert_eq!(sizer.observe(&[stats(0, 0), stats(0, 0)], at(0)), None);
+        // 1000 entries per 100ms on the second CPU is 640 KB/s, which is
+        // fine with 2 MB buffers.
+        assert_eq!(sizer.observe(&[stats(0, 0), stats(0, 1000)], at(100)), None);
+        // 10000 entries per 100ms is 6.4 MB/s, so the buffers grow, but
+        // only as far as the maximum.
+        assert_eq!(sizer.observe(&[stats(0, 0), stats(1000, 10000)], at(200)), Some(4096));
+        assert_eq!(sizer.observe(&[stats(0, 0), stats(1000, 20000)], at(300)), None);
+        sizer.resize_failed();
+        assert_eq!(sizer.observe(&[stats(0, 0), stats(1000, 30000)], at(400)), None);
+        // Quiet, but not for long enough.
+        assert_eq!(sizer.observe(&[stats(0, 0), stats(0, 31000)], at(500)), None);
+        assert_eq!(sizer.observe(&[stats(0, 0), stats(0, 31000)], at(20_000)), None);
+        // Quiet for long enough, but with unread entries.
+        assert_eq!(sizer.observe(&[stats(1, 0), stats(0, 31000)], at(30_400)), None);
+        assert_eq!(sizer.observe(&[stats(0, 1), stats(0, 31000)], at(30_500)), Some(1024));
+        assert_eq!(sizer.observe(&[stats(0, 0), stats(0, 31000)], at(70_000)), None);
+    }
+}
@@ -0,0 +1,397 @@
+// Takes changes to the disks of a running QEMU virtual machine from QEMU
+// itself, rather than tracing them. On the host, only writes to the disk
+// images can be traced, and for formats such as qcow2 those aren't at the
+// guest's offsets.
+//
+// Two persistent dirty bitmaps are kept on each disk's block node, so that
+// one can be read whilst the other records new writes. Every poll period, the
+// active bitmap's hash is compared with that of an empty bitmap. If it
+// differs, a transaction clears and enables the other bitmap and disables the
+// active one. The frozen bitmap is then read through a temporary NBD export of
+// the node, with the qemu:dirty-bitmap metadata context.
+//
+// If anything goes wrong, the changes in the frozen bitmap may be lost, so
+// everything is set up again. Writes made between the old bitmaps going and
+// the new ones being made aren't recorded anywhere, so the loss is only
+// reported (as lost events) once the new bitmaps exist. Anything copied again
+// because of it is then sure to be seen if it changes afterwards. A failed
+// poll for a barrier is reported straight away as well, since the sync it
+// belongs to can't have seen everything.
+
+use std::os::unix::net::UnixStream;
+use std::path::{Path,PathBuf};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::{Duration,Instant};
+use serde_json::{Value,json};
+use crate::control::Job;
+use crate::nbd;
+use crate::qmp::Qmp;
+use super::{ChangeKind,ChangeSource,ChunkRange};
+
+static NEXT_EXPORT_NUMBER: AtomicUsize = AtomicUsize::new(0);
+
+// How long to wait for QEMU's NBD server to answer.
+const NBD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One job's disk.
+struct Disk {
+    device_number: usize,
+    chunk_size: u64,
+    node: String,
+    nbd_socket: PathBuf,
+    poll_period: Duration,
+    bitmaps: [String; 2],
+    // Which of the bitmaps is recording writes.
+    active: usize,
+    // The hash of the active bitmap when nothing has been written.
+    empty_hash: String,
+    last_poll: Instant,
+}
+
+/// The disks of one virtual machine, all controlled over the same QMP
+/// socket (which only takes one client at a time).
+pub struct DirtyBitmapChanges {
+    qmp_socket: PathBuf,
+    disks: Vec<Disk>,
+    // None after an error, until set up again.
+    qmp: Option<Qmp>,
+    // Whether the bitmaps have been set up before, so that setting them up
+    // again loses changes.
+    set_up: bool,
+    barrier_placed: bool,
+    barrier_passed: bool,
+    lost: u64,
+}
+
+fn bitmap_hash(qmp: &mut Qmp, node: &str, bitmap: &str) -> Result<String,String> {
+    let result = qmp.execute("x-debug-block-dirty-bitmap-sha256", json!({"node": node, "name": bitmap}))?;
+    match result.get("sha256").and_then(Value::as_str) {
+        Some(hash) => Ok(String::from(hash)),
+        None => Err(String::from("QEMU did not give a bitmap hash")),
+    }
+}
+
+/// Make a fresh pair of bitmaps, with only the first recording writes.
+fn setup_disk(qmp: &mut Qmp, disk: &mut Disk) -> Result<(),String> {
+    for (i, bitmap) in disk.bitmaps.iter().enumerate() {
+        // Left over from an earlier run, or before reconnecting.
+        let _ = qmp.execute("block-dirty-bitmap-remove", json!({"node": disk.node, "name": bitmap}));
+        qmp.execute("block-dirty-bitmap-add", json!({"node": disk.node, "name": bitmap, "persistent": true, "disabled": i != 0}))?;
+    }
+    disk.active = 0;
+    disk.empty_hash = bitmap_hash(qmp, &disk.node, &disk.bitmaps[0])?;
+    if let Err(e) = qmp.execute("nbd-server-start", json!({"addr": {"type": "unix", "data": {"path": disk.nbd_socket}}})) {
+        // Most likely, it's already running (e.g. serving the job's source).
+        eprintln!("Note: did not start QEMU's NBD server on '{}': {}", disk.nbd_socket.display(), e);
+    }
+    Ok(())
+}
+
+/// Read which parts of the node a (disabled) bitmap says are dirty, as byte
+/// ranges.
+fn read_bitmap(qmp: &mut Qmp, disk: &Disk, bitmap: &str) -> Result<Vec<(u64, u64)>,String> {
+    let export = format!("trackup-{}-{}", std::process::id(), NEXT_EXPORT_NUMBER.fetch_add(1, Ordering::Relaxed));
+    qmp.execute("block-export-add", json!({"type": "nbd", "id": export, "node-name": disk.node, "name": export, "writable": false, "bitmaps": [bitmap]}))?;
+    let read = || -> Result<Vec<(u64, u64)>,String> {
+        let stream = match UnixStream::connect(&disk.nbd_socket) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Could not connect to QEMU's NBD server at '{}': {}", disk.nbd_socket.display(), e));
+            },
+        };
+        stream.set_read_timeout(Some(NBD_TIMEOUT)).unwrap();
+        let mut client = nbd::Client::connect(stream, &export, &format!("qemu:dirty-bitmap:{}", bitmap))?;
+        let ranges = client.block_status(nbd::STATE_DIRTY)?;
+        client.disconnect();
+        Ok(ranges)
+    };
+    let ranges = read();
+    qmp.execute("block-export-del", json!({"id": export, "mode": "hard"}))?;
+    ranges
+}
+
+impl DirtyBitmapChanges {
+    /// Takes the jobs (with their device numbers) to find changes for, which
+    /// must all be on the same QMP socket.
+    pub fn new(qmp_socket: &Path, jobs: &[(usize, &Job)]) -> Result<Self,String> {
+        let now = Instant::now();
+        let disks = jobs.iter().map(
+            |(device_number, job)| {
+                let qemu = job.qemu.as_ref().unwrap();
+                Disk {
+                    device_number: *device_number,
+                    chunk_size: job.chunk_size as u64,
+                    node: qemu.node.clone(),
+                    nbd_socket: qemu.nbd_socket.clone(),
+                    poll_period: qemu.poll_period,
+                    bitmaps: [format!("{}-a", qemu.bitmap), format!("{}-b", qemu.bitmap)],
+                    active: 0,
+                    empty_hash: String::new(),
+                    last_poll: now,
+                }
+            }
+        ).collect();
+        let mut changes = Self {
+            qmp_socket: qmp_socket.to_path_buf(),
+            disks,
+            qmp: None,
+            set_up: false,
+            barrier_placed: false,
+            barrier_passed: true,
+            lost: 0,
+        };
+        changes.connect()?;
+        Ok(changes)
+    }
+
+    fn connect(&mut self) -> Result<&mut Qmp,String> {
+        if self.qmp.is_none() {
+            let mut qmp = Qmp::connect(&self.qmp_socket)?;
+            for disk in &mut self.disks {
+                setup_disk(&mut qmp, disk)?;
+            }
+            if self.set_up {
+                self.lost += 1;
+            }
+            self.set_up = true;
+            eprintln!("Tracking changes to {} disk(s) through QMP socket '{}'", self.disks.len(), self.qmp_socket.display());
+            self.qmp = Some(qmp);
+        }
+        Ok(self.qmp.as_mut().unwrap())
+    }
+
+    /// Returns the changed byte ranges of one disk.
+    fn poll(&mut self, disk_index: usize) -> Result<Vec<(u64, u64)>,String> {
+        self.connect()?;
+        let qmp = self.qmp.as_mut().unwrap();
+        let disk = &mut self.disks[disk_index];
+        let frozen = disk.active;
+        if bitmap_hash(qmp, &disk.node, &disk.bitmaps[frozen])? == disk.empty_hash {
+            return Ok(Vec::new());
+        }
+        let next = 1 - frozen;
+        qmp.execute("transaction", json!({"actions": [
+            {"type": "block-dirty-bitmap-clear", "data": {"node": disk.node, "name": disk.bitmaps[next]}},
+            {"type": "block-dirty-bitmap-enable", "data": {"node": disk.node, "name": disk.bitmaps[next]}},
+            {"type": "block-dirty-bitmap-disable", "data": {"node": disk.node, "name": disk.bitmaps[frozen]}},
+        ]}))?;
+        disk.active = next;
+        read_bitmap(qmp, disk, &disk.bitmaps[frozen])
+    }
+}
+
+impl ChangeSource for DirtyBitmapChanges {
+    fn try_read(&mut self) -> Option<Vec<ChunkRange>> {
+        // Nothing blocks here, so that other sources being relayed alongside
+        // this one aren't held up.
+        let now = Instant::now();
+        let forced = std::mem::take(&mut self.barrier_placed);
+        let due: Vec<usize> = (0..self.disks.len()).filter(
+            |i| {forced || now.duration_since(self.disks[*i].last_poll) >= self.disks[*i].poll_period}
+        ).collect();
+        if due.is_empty() {
+            return None;
+        }
+        let mut ranges = Vec::new();
+        for disk_index in due {
+            self.disks[disk_index].last_poll = now;
+            match self.poll(disk_index) {
+                Ok(byte_ranges) => {
+                    let disk = &self.disks[disk_index];
+                    ranges.extend(byte_ranges.into_iter().map(
+                        |(start, end)| {ChunkRange {
+                            device_number: disk.device_number,
+                            chunks: (start / disk.chunk_size) as usize..end.div_ceil(disk.chunk_size) as usize,
+                            kind: ChangeKind::Write,
+                        }}
+                    ));
+                },
+                Err(e) => {
+                    eprintln!("Warning: could not read changes from QEMU: {}", e);
+                    self.qmp = None;
+                    if forced {
+                        self.lost += 1;
+                    }
+                },
+            }
+        }
+        if forced {
+            self.barrier_passed = true;
+        }
+        Some(ranges)
+    }
+
+    fn check_lost(&mut self) -> u64 {
+        std::mem::take(&mut self.lost)
+    }
+
+    /// Every write which has completed is already in the active bitmap, so
+    /// a poll straight away returns them all.
+    fn place_barrier(&mut self) -> Result<bool,String> {
+        self.barrier_placed = true;
+        self.barrier_passed = false;
+        Ok(true)
+    }
+
+    fn passed_barrier(&self) -> bool {
+        self.barrier_passed
+    }
+}
+
+impl Drop for DirtyBitmapChanges {
+    fn drop(&mut self) {
+        if let Some(qmp) = &mut self.qmp {
+            for disk in &self.disks {
+                for bitmap in &disk.bitmaps {
+                    if let Err(e) = qmp.execute("block-dirty-bitmap-remove", json!({"node": disk.node, "name": bitmap})) {
+                        eprintln!("Warning: could not remove dirty bitmap '{}' from '{}': {}", bitmap, disk.node, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc,Barrier};
+    use std::sync::mpsc::channel;
+    use crate::change_logger::{Change,Control,relay};
+    use crate::control::QemuSource;
+    use crate::qmp::tests::mock_server;
+    use crate::test_util::{test_dir,test_job};
+
+    type Step = (Value, Result<Value,&'static str>);
+
+    fn bitmap(name: &str) -> Value {
+        json!({"node": "disk0", "name": name})
+    }
+
+    // Only trackup-a (the first to be active) is ever written to here.
+    fn hash(name: &str) -> Step {
+        (json!({"execute": "x-debug-block-dirty-bitmap-sha256", "arguments": bitmap(name)}), Ok(json!({"sha256": if name == "trackup-a" {"empty"} else {"dirty"}})))
+    }
+
+    fn remove(name: &str) -> Step {
+        (json!({"execute": "block-dirty-bitmap-remove", "arguments": bitmap(name)}), Ok(json!({})))
+    }
+
+    fn setup(removed: Result<Value,&'static str>) -> Vec<Step> {
+        vec![
+            (json!({"execute": "block-dirty-bitmap-remove", "arguments": bitmap("trackup-a")}), removed),
+            (json!({"execute": "block-dirty-bitmap-add", "arguments": {"node": "disk0", "name": "trackup-a", "persistent": true, "disabled": false}}), Ok(json!({}))),
+            remove("trackup-b"),
+            (json!({"execute": "block-dirty-bitmap-add", "arguments": {"node": "disk0", "name": "trackup-b", "persistent": true, "disabled": true}}), Ok(json!({}))),
+            hash("trackup-a"),
+            (json!({"execute": "nbd-server-start", "arguments": {"addr": {"type": "unix", "data": {"path": "*"}}}}), Err("NBD server already running")),
+        ]
+    }
+
+    fn qemu_job(qmp_socket: &Path, nbd_socket: &Path) -> Job {
+        Job {
+            chunk_size: 65536,
+            qemu: Some(QemuSource {
+                qmp_socket: qmp_socket.to_path_buf(),
+                node: String::from("disk0"),
+                bitmap: String::from("trackup"),
+                nbd_socket: nbd_socket.to_path_buf(),
+                poll_period: Duration::from_secs(3600),
+            }),
+            ..test_job(Path::new("/dev/nbd0"), Path::new("backup.img"))
+        }
+    }
+
+    #[test]
+    fn test_dirty_bitmap_changes() {
+        let dir = test_dir("dirty-bitmap");
+        let qmp_socket = dir.join("qmp.sock");
+        let nbd_socket = dir.join("nbd.sock");
+        let qmp_listener = UnixListener::bind(&qmp_socket).unwrap();
+        let nbd_listener = UnixListener::bind(&nbd_socket).unwrap();
+        let job = qemu_job(&qmp_socket, &nbd_socket);
+
+        let qmp_server = std::thread::spawn(move || {
+            let mut script = setup(Err("Dirty bitmap 'trackup-a' not found"));
+            script.extend(vec![
+                // Nothing written.
+                hash("trackup-a"),
+                // Written to, so swap and read.
+                (json!({"execute": "x-debug-block-dirty-bitmap-sha256", "arguments": bitmap("trackup-a")}), Ok(json!({"sha256": "dirty"}))),
+                (json!({"execute": "transaction", "arguments": {"actions": [
+                    {"type": "block-dirty-bitmap-clear", "data": bitmap("trackup-b")},
+                    {"type": "block-dirty-bitmap-enable", "data": bitmap("trackup-b")},
+                    {"type": "block-dirty-bitmap-disable", "data": bitmap("trackup-a")},
+                ]}}), Ok(json!({}))),
+                (json!({"execute": "block-export-add", "arguments": {"type": "nbd", "id": "*", "node-name": "disk0", "name": "*", "writable": false, "bitmaps": ["trackup-a"]}}), Ok(json!({}))),
+                (json!({"execute": "block-export-del", "arguments": {"id": "*", "mode": "hard"}}), Ok(json!({}))),
+                // A failure means starting again.
+                (json!({"execute": "x-debug-block-dirty-bitmap-sha256", "arguments": bitmap("trackup-b")}), Err("Bitmap gone")),
+            ]);
+            mock_server(&qmp_listener, script);
+            let mut script = setup(Ok(json!({})));
+            script.extend(vec![
+                hash("trackup-a"),
+                remove("trackup-a"),
+                remove("trackup-b"),
+            ]);
+            mock_server(&qmp_listener, script);
+        });
+        let nbd_server = std::thread::spawn(move || {
+            let (stream, _) = nbd_listener.accept().unwrap();
+            nbd::tests::mock_server(stream, 1 << 20, vec![(4096, 0), (4096, 1), (65536, 0), (131072, 1), (1048576 - 204800, 0)]);
+        });
+
+        let mut changes = DirtyBitmapChanges::new(&qmp_socket, &[(0, &job)]).unwrap();
+        // Not due yet.
+        assert_eq!(changes.try_read(), None);
+        assert_eq!(changes.place_barrier(), Ok(true));
+        assert!(!changes.passed_barrier());
+        assert_eq!(changes.try_read(), Some(Vec::new()));
+        assert!(changes.passed_barrier());
+        assert_eq!(changes.place_barrier(), Ok(true));
+        let range = |chunks: std::ops::Range<usize>| {ChunkRange {device_number: 0, chunks, kind: ChangeKind::Write}};
+        assert_eq!(changes.try_read(), Some(vec![range(0..1), range(1..4)]));
+        assert_eq!(changes.check_lost(), 0);
+        // A failed forced poll is a loss, and so is setting the bitmaps up
+        // again.
+        assert_eq!(changes.place_barrier(), Ok(true));
+        assert_eq!(changes.try_read(), Some(Vec::new()));
+        assert_eq!(changes.check_lost(), 1);
+        assert_eq!(changes.place_barrier(), Ok(true));
+        assert_eq!(changes.try_read(), Some(Vec::new()));
+        assert_eq!(changes.check_lost(), 1);
+        drop(changes);
+        qmp_server.join().unwrap();
+        nbd_server.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_connection_lost_during_sync() {
+        let dir = test_dir("dirty-bitmap-lost");
+        let qmp_socket = dir.join("qmp.sock");
+        let qmp_listener = UnixListener::bind(&qmp_socket).unwrap();
+        let job = qemu_job(&qmp_socket, &dir.join("nbd.sock"));
+        // QEMU goes away just as the sync's poll starts.
+        let qmp_server = std::thread::spawn(move || {
+            mock_server(&qmp_listener, setup(Ok(json!({}))));
+        });
+        let mut changes = DirtyBitmapChanges::new(&qmp_socket, &[(0, &job)]).unwrap();
+        qmp_server.join().unwrap();
+
+        let (log_produce, log_consume) = channel();
+        let (control_produce, control_consume) = channel();
+        let relay_thread = std::thread::spawn(move || {relay(&mut changes, log_produce, control_consume)});
+        let barrier = Arc::new(Barrier::new(2));
+        control_produce.send(Control::Sync(Arc::clone(&barrier))).unwrap();
+        barrier.wait();
+        // The copier hears of the loss before the sync finishes, so the pass
+        // isn't consistent.
+        assert!(matches!(log_consume.try_recv(), Ok(Change::EventsLost(1))));
+        drop(control_produce);
+        relay_thread.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+// Decides which traced writes can be ignored.
+//
+// Writes made by trackup itself are ignored only when they provably land on
+// one of the job destinations (which are block devices), as those are the
+// only writes we know to be our own backup data. A destination which lies
+// within a traced source is part of that source's data too, so writes to it
+// are never ignored. Everything else is down to the manifest's change_filter.
+
+use std::cell::{Cell,RefCell};
+use std::collections::HashMap;
+use std::os::unix::fs::FileTypeExt;
+use std::time::{Duration,Instant};
+use crate::control::{ChangeFilter,Config,Manifest};
+use crate::device::{Device,Segment};
+
+// Pids get reused, and processes move between cgroups, so what we know about
+// them is forgotten after a while.
+const PID_CACHE_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+struct PidInfo {
+    own: bool,
+    ignored: bool,
+}
+
+/// Whether any cgroup in the contents of /proc/<pid>/cgroup is one of the
+/// given cgroups, or is inside one of them.
+pub fn in_cgroups(proc_cgroup: &str, cgroups: &[String]) -> bool {
+    proc_cgroup.lines().filter_map(|line| {line.splitn(3, ':').nth(2)}).any(
+        |path| {
+            cgroups.iter().any(|cgroup| {
+                let cgroup = cgroup.trim_end_matches('/');
+                path == cgroup || (path.starts_with(cgroup) && path[cgroup.len()..].starts_with('/'))
+            })
+        }
+    )
+}
+
+fn read_tgid(pid: u32) -> Option<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status.lines().find_map(|line| {line.strip_prefix("Tgid:")}).and_then(|tgid| {tgid.trim().parse().ok()})
+}
+
+/// Whether any sector of a (whole disk event_dev, start sector, end sector)
+/// extent maps onto a traced source.
+fn within_traced_source(extent: (u32, u64, u64), trace_segments: &[Vec<Segment>]) -> bool {
+    let (event_dev, start, end) = extent;
+    trace_segments.iter().flatten().any(
+        |segment| {segment.traced.event_dev == event_dev && !segment.map_range(start, end).is_empty()}
+    )
+}
+
+pub struct EventFilter {
+    change_filter: ChangeFilter,
+    own_tgid: u32,
+    // (whole disk event_dev, start sector, end sector)
+    destination_extents: Vec<(u32, u64, u64)>,
+    pid_cache: RefCell<HashMap<u32, PidInfo>>,
+    pid_cache_time: Cell<Instant>,
+    ignored_events: Cell<u64>,
+}
+
+impl EventFilter {
+    pub fn new(config: &Config, manifest: &Manifest, trace_segments: &[Vec<Segment>]) -> Self {
+        let destination_extents = manifest.jobs.iter().filter_map(
+            |job| {
+                let is_block_device = std::fs::metadata(&job.destination).map(|metadata| {metadata.file_type().is_block_device()}).unwrap_or(false);
+                if !is_block_device {
+                    // We don't know where a file's data lives on disk.
+                    return None;
+                }
+                let device = Device::from_path(config, &job.destination).ok()?;
+                let extent = (device.get_base_device().event_dev, device.start_sector, device.end_sector);
+                if within_traced_source(extent, trace_segments) {
+                    eprintln!("Warning: destination '{}' lies within a traced source, so trackup's own writes to it will be copied too", job.destination.display());
+                    return None;
+                }
+                Some(extent)
+            }
+        ).collect();
+        Self {
+            change_filter: manifest.change_filter.clone(),
+            own_tgid: std::process::id(),
+            destination_extents,
+            pid_cache: RefCell::new(HashMap::new()),
+            pid_cache_time: Cell::new(Instant::now()),
+            ignored_events: Cell::new(0),
+        }
+    }
+
+    fn pid_info(&self, pid: u32) -> PidInfo {
+        if self.pid_cache_time.get().elapsed() >= PID_CACHE_PERIOD {
+            self.pid_cache.borrow_mut().clear();
+            self.pid_cache_time.set(Instant::now());
+        }
+        if let Some(info) = self.pid_cache.borrow().get(&pid) {
+            return info.clone();
+        }
+
+        let filter = &self.change_filter;
+        let (in_ignored_cgroup, in_counted_cgroup) =
+            if filter.ignored_cgroups.is_empty() && filter.counted_cgroups.is_empty() {
+                (false, false)
+            } else {
+                // If the process has already gone, we can't tell, so count it.
+                let proc_cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).unwrap_or_default();
+                (in_cgroups(&proc_cgroup, &filter.ignored_cgroups), in_cgroups(&proc_cgroup, &filter.counted_cgroups))
+            };
+        let ignored = (filter.ignored_pids.contains(&pid) || in_ignored_cgroup)
+            && !(filter.counted_pids.contains(&pid) || in_counted_cgroup);
+        let info = PidInfo {
+            own: read_tgid(pid) == Some(self.own_tgid),
+            ignored,
+        };
+        self.pid_cache.borrow_mut().insert(pid, info.clone());
+        info
+    }
+
+    /// Whether a write to sectors [start, end) of the given whole disk can
+    /// be ignored.
+    pub fn should_ignore(&self, pid: u32, event_dev: u32, start: u64, end: u64) -> bool {
+        if pid == 0 {
+            // Unknown, or the idle task, which isn't filterable.
+            return false;
+        }
+        let info = self.pid_info(pid);
+        let ignore = info.ignored || (
+            info.own && self.destination_extents.iter().any(
+                |(dev, dest_start, dest_end)| {*dev == event_dev && *dest_start <= start && end <= *dest_end}
+            )
+        );
+        if ignore {
+            self.ignored_events.set(self.ignored_events.get() + 1);
+        }
+        ignore
+    }
+
+    pub fn get_ignored_events(&self) -> u64 {
+        self.ignored_events.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::interface::Internalize;
+
+    #[test]
+    fn test_in_cgroups() {
+        let v2 = "0::/system.slice/backup-hooks.service\n";
+        let v1 = "12:pids:/user.slice/user-1000.slice\n1:name=systemd:/user.slice/user-1000.slice/session-2.scope\n";
+        let cgroups = vec![String::from("/system.slice/backup-hooks.service"), String::from("/user.slice/user-1000.slice/")];
+        assert!(in_cgroups(v2, &cgroups));
+        assert!(in_cgroups(v1, &cgroups));
+        assert!(!in_cgroups("0::/system.slice/backup-hooks.service.d\n", &cgroups));
+        assert!(!in_cgroups("0::/system.slice\n", &cgroups));
+        assert!(!in_cgroups("", &cgroups));
+    }
+
+    #[test]
+    fn test_within_traced_source() {
+        // A partition covering sectors [100, 200) of a disk.
+        let source = Device {event_dev: 8 << 20, start_sector: 100, end_sector: 200, ..crate::test_util::test_device(8, 1, 100)};
+        let config = crate::control::interface::Config::default().internalize().unwrap();
+        let trace_segments = vec![source.trace_segments(&config, crate::control::TraceLayer::Top).unwrap()];
+        assert!(within_traced_source((8 << 20, 150, 300), &trace_segments));
+        assert!(!within_traced_source((8 << 20, 200, 300), &trace_segments));
+        assert!(!within_traced_source((8 << 20 | 16, 100, 200), &trace_segments));
+    }
+}
@@ -0,0 +1,532 @@
+// Serves a job's source (e.g. a raw image file) over NBD, so that its real
+// users (e.g. QEMU or nbd-client) write to it through us, and takes its
+// changes from those writes rather than tracing them.
+//
+// Each connection is handled by its own thread. A change is reported before
+// the client is told it has been made, so anything the client considers
+// written has been reported by the time the copier syncs. So that the final
+// locked pass can be consistent, writes can be held: new ones wait, without
+// being replied to, until they are released, and holding waits for those
+// already under way to be reported. Writes are never failed because they
+// were held, even if the server is closing.
+
+use std::fs::{File,OpenOptions};
+use std::io::{Seek,SeekFrom,Write};
+use std::os::unix::fs::{FileExt,FileTypeExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener,UnixStream};
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Condvar,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::sync::mpsc::{Receiver,Sender,channel};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use crate::control::{Job,NbdServer};
+use crate::nbd;
+use super::{ChangeKind,ChangeSource,ChunkRange,byte_range_changes};
+
+// The largest read or write we accept, as suggested by the protocol.
+const MAX_REQUEST_LENGTH: u32 = 32 << 20;
+// Also bounds how long closing the server takes.
+const ACCEPT_PERIOD: Duration = Duration::from_millis(10);
+
+#[derive(Default)]
+struct HoldState {
+    held: bool,
+    // Changes which got past the hold, but haven't been reported yet.
+    in_flight: usize,
+}
+
+#[derive(Default)]
+struct WriteHold {
+    state: Mutex<HoldState>,
+    changed: Condvar,
+}
+
+impl WriteHold {
+    /// Wait until changes are allowed.
+    fn begin_change(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.held {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.in_flight += 1;
+    }
+
+    fn end_change(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        self.changed.notify_all();
+    }
+
+    /// When holding, this waits for changes under way to be reported.
+    fn set_held(&self, held: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.held = held;
+        self.changed.notify_all();
+        while held && state.in_flight > 0 {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+/// What every connection shares.
+struct Export {
+    device_number: usize,
+    chunk_size: u64,
+    name: String,
+    file: File,
+    size: u64,
+    hold: WriteHold,
+}
+
+/// The error number to send to the client.
+fn errno(e: &std::io::Error) -> u32 {
+    match e.raw_os_error() {
+        Some(code @ (libc::EPERM | libc::EIO | libc::ENOMEM | libc::EINVAL | libc::ENOSPC | libc::EOVERFLOW | libc::ENOTSUP | libc::ESHUTDOWN)) => code as u32,
+        _ => libc::EIO as u32,
+    }
+}
+
+fn fallocate(file: &File, mode: libc::c_int, offset: u64, length: u64) -> std::io::Result<()> {
+    if unsafe {libc::fallocate(file.as_raw_fd(), mode | libc::FALLOC_FL_KEEP_SIZE, offset as libc::off_t, length as libc::off_t)} != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn write_zeroes(file: &File, offset: u64, length: u64, may_punch: bool) -> std::io::Result<()> {
+    if may_punch && fallocate(file, libc::FALLOC_FL_PUNCH_HOLE, offset, length).is_ok() {
+        return Ok(());
+    }
+    if fallocate(file, libc::FALLOC_FL_ZERO_RANGE, offset, length).is_ok() {
+        return Ok(());
+    }
+    let zeroes = vec![0; std::cmp::min(length, MAX_REQUEST_LENGTH as u64) as usize];
+    let mut done = 0;
+    while done < length {
+        let piece = std::cmp::min(length - done, zeroes.len() as u64) as usize;
+        file.write_all_at(&zeroes[..piece], offset + done)?;
+        done += piece as u64;
+    }
+    Ok(())
+}
+
+fn send_option_reply(stream: &mut UnixStream, option: u32, reply_type: u32, data: &[u8]) -> Result<(),String> {
+    let mut buf = Vec::with_capacity(20 + data.len());
+    buf.extend_from_slice(&nbd::OPTION_REPLY_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&option.to_be_bytes());
+    buf.extend_from_slice(&reply_type.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf).map_err(|e| format!("NBD connection failed: {}", e))
+}
+
+fn send_reply(stream: &mut UnixStream, error: u32, handle: u64, data: &[u8]) -> Result<(),String> {
+    let mut buf = Vec::with_capacity(16 + data.len());
+    buf.extend_from_slice(&nbd::SIMPLE_REPLY_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&error.to_be_bytes());
+    buf.extend_from_slice(&handle.to_be_bytes());
+    if error == 0 {
+        buf.extend_from_slice(data);
+    }
+    stream.write_all(&buf).map_err(|e| format!("NBD connection failed: {}", e))
+}
+
+/// Agree on the export with the client. Returns false if the client went
+/// away (politely) instead.
+fn negotiate(stream: &mut UnixStream, export: &Export) -> Result<bool,String> {
+    let transmission_flags = nbd::FLAG_HAS_FLAGS | nbd::FLAG_SEND_FLUSH | nbd::FLAG_SEND_FUA | nbd::FLAG_SEND_TRIM | nbd::FLAG_SEND_WRITE_ZEROES | nbd::FLAG_CAN_MULTI_CONN;
+    let mut greeting = Vec::new();
+    greeting.extend_from_slice(&nbd::NBDMAGIC.to_be_bytes());
+    greeting.extend_from_slice(&nbd::IHAVEOPT.to_be_bytes());
+    greeting.extend_from_slice(&(nbd::FLAG_FIXED_NEWSTYLE | nbd::FLAG_NO_ZEROES).to_be_bytes());
+    stream.write_all(&greeting).map_err(|e| format!("NBD connection failed: {}", e))?;
+    let client_flags = nbd::read_u32(stream)?;
+    if client_flags & nbd::FLAG_FIXED_NEWSTYLE as u32 == 0 {
+        return Err(String::from("NBD client doesn't support fixed newstyle negotiation"));
+    }
+    let no_zeroes = client_flags & nbd::FLAG_NO_ZEROES as u32 != 0;
+    let mut export_info = Vec::new();
+    export_info.extend_from_slice(&export.size.to_be_bytes());
+    export_info.extend_from_slice(&transmission_flags.to_be_bytes());
+    loop {
+        if nbd::read_u64(stream)? != nbd::IHAVEOPT {
+            return Err(String::from("Bad NBD option"));
+        }
+        let option = nbd::read_u32(stream)?;
+        let length = nbd::read_u32(stream)?;
+        if length > 65536 {
+            return Err(String::from("NBD option too long"));
+        }
+        let data = nbd::read_bytes(stream, length as usize)?;
+        match option {
+            nbd::OPT_EXPORT_NAME => {
+                if data != export.name.as_bytes() {
+                    // There's no way to refuse other than hanging up.
+                    return Ok(false);
+                }
+                stream.write_all(&export_info).map_err(|e| format!("NBD connection failed: {}", e))?;
+                if !no_zeroes {
+                    stream.write_all(&[0; 124]).map_err(|e| format!("NBD connection failed: {}", e))?;
+                }
+                return Ok(true);
+            },
+            nbd::OPT_INFO | nbd::OPT_GO => {
+                let name_length = if data.len() >= 4 {u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize} else {usize::MAX};
+                if data.len() < 4 || data.len() - 4 < name_length || &data[4..4 + name_length] != export.name.as_bytes() {
+                    send_option_reply(stream, option, nbd::REP_ERR_UNKNOWN, b"No such export")?;
+                    continue;
+                }
+                let mut info = nbd::INFO_EXPORT.to_be_bytes().to_vec();
+                info.extend_from_slice(&export_info);
+                send_option_reply(stream, option, nbd::REP_INFO, &info)?;
+                send_option_reply(stream, option, nbd::REP_ACK, &[])?;
+                if option == nbd::OPT_GO {
+                    return Ok(true);
+                }
+            },
+            nbd::OPT_LIST => {
+                let mut server = Vec::new();
+                nbd::push_string(&mut server, &export.name);
+                send_option_reply(stream, option, nbd::REP_SERVER, &server)?;
+                send_option_reply(stream, option, nbd::REP_ACK, &[])?;
+            },
+            nbd::OPT_ABORT => {
+                send_option_reply(stream, option, nbd::REP_ACK, &[])?;
+                return Ok(false);
+            },
+            _ => {
+                send_option_reply(stream, option, nbd::REP_ERR_UNSUP, &[])?;
+            },
+        }
+    }
+}
+
+/// Make a change to the export, and report it before returning the error
+/// number to reply with.
+fn change<F: FnOnce(&File) -> std::io::Result<bool>>(export: &Export, changes: &Sender<Vec<ChunkRange>>, offset: u64, length: u32, kind: ChangeKind, operation: F) -> u32 {
+    export.hold.begin_change();
+    let result = operation(&export.file);
+    // Even a failed change may have changed something.
+    let kind = if let Ok(true) = result {kind} else {ChangeKind::Write};
+    if length > 0 && !matches!(result, Ok(false)) {
+        // The copier going away doesn't matter to the client.
+        let _ = changes.send(byte_range_changes(export.device_number, offset, offset + length as u64, export.chunk_size, export.size, kind));
+    }
+    export.hold.end_change();
+    match result {
+        Ok(_) => 0,
+        Err(e) => errno(&e),
+    }
+}
+
+fn serve(mut stream: UnixStream, export: &Export, changes: &Sender<Vec<ChunkRange>>) -> Result<(),String> {
+    if !negotiate(&mut stream, export)? {
+        return Ok(());
+    }
+    loop {
+        if nbd::read_u32(&mut stream)? != nbd::REQUEST_MAGIC {
+            return Err(String::from("Bad NBD request"));
+        }
+        let flags = nbd::read_u16(&mut stream)?;
+        let command = nbd::read_u16(&mut stream)?;
+        let handle = nbd::read_u64(&mut stream)?;
+        let offset = nbd::read_u64(&mut stream)?;
+        let length = nbd::read_u32(&mut stream)?;
+        let in_bounds = offset.checked_add(length as u64).is_some_and(|end| {end <= export.size});
+        let fua = flags & nbd::CMD_FLAG_FUA != 0;
+        let sync_if_fua = |file: &File| {if fua {file.sync_data()} else {Ok(())}};
+        match command {
+            nbd::CMD_DISC => {
+                return Ok(());
+            },
+            nbd::CMD_READ => {
+                if !in_bounds || length > MAX_REQUEST_LENGTH {
+                    send_reply(&mut stream, libc::EINVAL as u32, handle, &[])?;
+                    continue;
+                }
+                let mut data = vec![0; length as usize];
+                match export.file.read_exact_at(&mut data, offset) {
+                    Ok(()) => send_reply(&mut stream, 0, handle, &data)?,
+                    Err(e) => send_reply(&mut stream, errno(&e), handle, &[])?,
+                }
+            },
+            nbd::CMD_WRITE => {
+                if length > MAX_REQUEST_LENGTH {
+                    return Err(format!("NBD write of {} bytes is too big", length));
+                }
+                let data = nbd::read_bytes(&mut stream, length as usize)?;
+                let error =
+                    if !in_bounds {
+                        libc::ENOSPC as u32
+                    } else {
+                        change(export, changes, offset, length, ChangeKind::Write, |file| {
+                            file.write_all_at(&data, offset)?;
+                            sync_if_fua(file)?;
+                            Ok(true)
+                        })
+                    };
+                send_reply(&mut stream, error, handle, &[])?;
+            },
+            nbd::CMD_TRIM => {
+                let error =
+                    if !in_bounds {
+                        libc::EINVAL as u32
+                    } else {
+                        change(export, changes, offset, length, ChangeKind::Discard, |file| {
+                            // Trimming is only advisory, so if it can't be
+                            // done, nothing has changed.
+                            if fallocate(file, libc::FALLOC_FL_PUNCH_HOLE, offset, length as u64).is_err() {
+                                return Ok(false);
+                            }
+                            sync_if_fua(file)?;
+                            Ok(true)
+                        })
+                    };
+                send_reply(&mut stream, error, handle, &[])?;
+            },
+            nbd::CMD_WRITE_ZEROES => {
+                let error =
+                    if !in_bounds {
+                        libc::ENOSPC as u32
+                    } else {
+                        change(export, changes, offset, length, ChangeKind::WriteZeroes, |file| {
+                            write_zeroes(file, offset, length as u64, flags & nbd::CMD_FLAG_NO_HOLE == 0)?;
+                            sync_if_fua(file)?;
+                            Ok(true)
+                        })
+                    };
+                send_reply(&mut stream, error, handle, &[])?;
+            },
+            nbd::CMD_FLUSH => {
+                let error = export.file.sync_data().err().map(|e| {errno(&e)}).unwrap_or(0);
+                send_reply(&mut stream, error, handle, &[])?;
+            },
+            _ => {
+                send_reply(&mut stream, libc::EINVAL as u32, handle, &[])?;
+            },
+        }
+    }
+}
+
+fn accept_connections(listener: UnixListener, export: Arc<Export>, changes: Sender<Vec<ChunkRange>>, closing: Arc<AtomicBool>) {
+    let mut connections: Vec<(UnixStream, JoinHandle<()>)> = Vec::new();
+    while !closing.load(Ordering::Relaxed) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::WouldBlock {
+                    eprintln!("Warning: could not accept NBD connection: {}", e);
+                }
+                std::thread::sleep(ACCEPT_PERIOD);
+                continue;
+            },
+        };
+        stream.set_nonblocking(false).unwrap();
+        let export = Arc::clone(&export);
+        let changes = changes.clone();
+        let connection = stream.try_clone().unwrap();
+        let handle = std::thread::Builder::new()
+            .name(String::from("nbd-connection"))
+            .spawn(move || {
+                if let Err(e) = serve(stream, &export, &changes) {
+                    eprintln!("Warning: NBD connection ended: {}", e);
+                }
+            })
+            .unwrap();
+        connections.push((connection, handle));
+        connections.retain(|(_, handle)| {!handle.is_finished()});
+    }
+    for (connection, handle) in connections {
+        let _ = connection.shutdown(std::net::Shutdown::Both);
+        handle.join().unwrap();
+    }
+}
+
+/// Changes made through our NBD server for one job's source.
+pub struct NbdServerChanges {
+    export: Arc<Export>,
+    socket: PathBuf,
+    changes: Receiver<Vec<ChunkRange>>,
+    closing: Arc<AtomicBool>,
+    listener_thread: Option<JoinHandle<()>>,
+}
+
+impl NbdServerChanges {
+    pub fn new(device_number: usize, job: &Job, server: &NbdServer) -> Result<Self,String> {
+        let mut file = match OpenOptions::new().read(true).write(true).open(&job.source) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Could not open '{}' for serving: {}", job.source.display(), e));
+            },
+        };
+        let size = file.seek(SeekFrom::End(0)).map_err(|e| format!("Could not find the size of '{}': {}", job.source.display(), e))?;
+        remove_stale_socket(&server.socket)?;
+        let listener = match UnixListener::bind(&server.socket) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Could not listen on '{}': {}", server.socket.display(), e));
+            },
+        };
+        listener.set_nonblocking(true).unwrap();
+        let export = Arc::new(Export {
+            device_number,
+            chunk_size: job.chunk_size as u64,
+            name: server.export_name.clone(),
+            file,
+            size,
+            hold: WriteHold::default(),
+        });
+        let (changes_produce, changes_consume) = channel();
+        let closing = Arc::new(AtomicBool::new(false));
+        let listener_thread = {
+            let export = Arc::clone(&export);
+            let closing = Arc::clone(&closing);
+            std::thread::Builder::new()
+                .name(String::from("nbd-server"))
+                .spawn(move || {accept_connections(listener, export, changes_produce, closing)})
+                .unwrap()
+        };
+        eprintln!("Serving '{}' over NBD at '{}'", job.source.display(), server.socket.display());
+        Ok(Self {
+            export,
+            socket: server.socket.clone(),
+            changes: changes_consume,
+            closing,
+            listener_thread: Some(listener_thread),
+        })
+    }
+}
+
+/// A socket left behind by an earlier run would stop us listening.
+fn remove_stale_socket(path: &Path) -> Result<(),String> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path).map_err(|e| format!("Could not remove old socket '{}': {}", path.display(), e))
+        },
+        Ok(_) => Err(format!("'{}' exists and is not a socket", path.display())),
+        Err(_) => Ok(()),
+    }
+}
+
+impl ChangeSource for NbdServerChanges {
+    fn try_read(&mut self) -> Option<Vec<ChunkRange>> {
+        self.changes.try_recv().ok()
+    }
+
+    fn hold_writes(&mut self, hold: bool) {
+        self.export.hold.set_held(hold);
+    }
+}
+
+impl Drop for NbdServerChanges {
+    fn drop(&mut self) {
+        // Held writes are made, and connections finish what they're doing
+        // before being shut down.
+        self.export.hold.set_held(false);
+        self.closing.store(true, Ordering::Relaxed);
+        self.listener_thread.take().unwrap().join().unwrap();
+        if let Err(e) = std::fs::remove_file(&self.socket) {
+            eprintln!("Warning: could not remove socket '{}': {}", self.socket.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{test_dir,test_job};
+
+    fn request(stream: &mut UnixStream, command: u16, handle: u64, offset: u64, length: u32, data: &[u8]) {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&nbd::REQUEST_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        buf.extend_from_slice(&command.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(&length.to_be_bytes());
+        buf.extend_from_slice(data);
+        stream.write_all(&buf).unwrap();
+    }
+
+    // Returns the error number and handle.
+    fn reply(stream: &mut UnixStream) -> (u32, u64) {
+        assert_eq!(nbd::read_u32(stream).unwrap(), nbd::SIMPLE_REPLY_MAGIC);
+        (nbd::read_u32(stream).unwrap(), nbd::read_u64(stream).unwrap())
+    }
+
+    fn wait_for_changes(changes: &mut NbdServerChanges) -> Vec<ChunkRange> {
+        loop {
+            if let Some(ranges) = changes.try_read() {
+                return ranges;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_nbd_server_changes() {
+        let dir = test_dir("nbd-server");
+        let image = dir.join("disk.img");
+        std::fs::write(&image, vec![1; 1 << 20]).unwrap();
+        let server = NbdServer {
+            socket: dir.join("nbd.sock"),
+            export_name: String::from("disk"),
+        };
+        let job = Job {
+            chunk_size: 65536,
+            nbd_server: Some(server.clone()),
+            ..test_job(&image, &dir.join("backup.img"))
+        };
+        let mut changes = NbdServerChanges::new(2, &job, &server).unwrap();
+
+        let mut stream = UnixStream::connect(&server.socket).unwrap();
+        assert_eq!(nbd::read_u64(&mut stream).unwrap(), nbd::NBDMAGIC);
+        assert_eq!(nbd::read_u64(&mut stream).unwrap(), nbd::IHAVEOPT);
+        nbd::read_u16(&mut stream).unwrap();
+        let mut negotiation = ((nbd::FLAG_FIXED_NEWSTYLE | nbd::FLAG_NO_ZEROES) as u32).to_be_bytes().to_vec();
+        negotiation.extend_from_slice(&nbd::IHAVEOPT.to_be_bytes());
+        negotiation.extend_from_slice(&nbd::OPT_EXPORT_NAME.to_be_bytes());
+        nbd::push_string(&mut negotiation, "disk");
+        stream.write_all(&negotiation).unwrap();
+        assert_eq!(nbd::read_u64(&mut stream).unwrap(), 1 << 20);
+        nbd::read_u16(&mut stream).unwrap();
+
+        let range = |chunks: std::ops::Range<usize>, kind: ChangeKind| {ChunkRange {device_number: 2, chunks, kind}};
+        request(&mut stream, nbd::CMD_WRITE, 1, 65536 + 512, 1024, &[2; 1024]);
+        assert_eq!(reply(&mut stream), (0, 1));
+        assert_eq!(wait_for_changes(&mut changes), vec![range(1..2, ChangeKind::Write)]);
+        request(&mut stream, nbd::CMD_READ, 2, 65536, 1024, &[]);
+        assert_eq!(reply(&mut stream), (0, 2));
+        let data = nbd::read_bytes(&mut stream, 1024).unwrap();
+        assert_eq!(&data[..512], &[1; 512][..]);
+        assert_eq!(&data[512..], &[2; 512][..]);
+        request(&mut stream, nbd::CMD_WRITE_ZEROES, 3, 32768, 3 * 65536, &[]);
+        assert_eq!(reply(&mut stream), (0, 3));
+        assert_eq!(wait_for_changes(&mut changes), vec![range(0..1, ChangeKind::Write), range(1..3, ChangeKind::WriteZeroes), range(3..4, ChangeKind::Write)]);
+        request(&mut stream, nbd::CMD_WRITE, 4, (1 << 20) - 512, 1024, &[3; 1024]);
+        assert_eq!(reply(&mut stream), (libc::ENOSPC as u32, 4));
+
+        // Held writes aren't made (or replied to) until released.
+        changes.hold_writes(true);
+        request(&mut stream, nbd::CMD_WRITE, 5, 0, 512, &[4; 512]);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(changes.try_read(), None);
+        assert_eq!(std::fs::read(&image).unwrap()[0], 1);
+        changes.hold_writes(false);
+        assert_eq!(reply(&mut stream), (0, 5));
+        assert_eq!(wait_for_changes(&mut changes), vec![range(0..1, ChangeKind::Write)]);
+        assert_eq!(std::fs::read(&image).unwrap()[0], 4);
+
+        // Writes still held when the server closes are made, not failed.
+        changes.hold_writes(true);
+        request(&mut stream, nbd::CMD_WRITE, 6, 0, 512, &[5; 512]);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(std::fs::read(&image).unwrap()[0], 4);
+        drop(changes);
+        assert_eq!(std::fs::read(&image).unwrap()[0], 5);
+        assert!(!server.socket.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+// Records the blk events which change data to a file, and replays them
+// later with their original (or scaled) timing. This lets a backup run's
+// changes be reproduced without tracing, e.g. when debugging the copier.
+//
+// Only events which made it through the event filter are recorded, along
+// with any reports of lost events. The file starts with a magic line, and is
+// followed by records of:
+//
+//   u8  record type ('E' for an event, 'L' for lost events)
+//   u64 nanoseconds since recording started
+//   for 'E', each BlkEvent field in order; for 'L', a u64 lost event count
+//
+// All integers are little endian.
+
+use std::fs::File;
+use std::io::{BufReader,BufWriter,Read,Write};
+use std::path::Path;
+use std::time::{Duration,Instant};
+use super::BlkEvent;
+
+const FILE_MAGIC: &[u8] = b"trackup-events-1\n";
+const EVENT_RECORD: u8 = b'E';
+const LOST_RECORD: u8 = b'L';
+
+/// Something read back from a recording.
+#[derive(Debug)]
+pub enum Recorded {
+    Event(BlkEvent),
+    Lost(u64),
+}
+
+fn read_u16(reader: &mut impl Read) -> std::io::Result<u16> {
+    let mut buf = [0; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn encode_event(event: &BlkEvent, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&event.magic.to_le_bytes());
+    buf.extend_from_slice(&event.sequence.to_le_bytes());
+    buf.extend_from_slice(&event.time.to_le_bytes());
+    buf.extend_from_slice(&event.sector.to_le_bytes());
+    buf.extend_from_slice(&event.bytes.to_le_bytes());
+    buf.extend_from_slice(&event.action.to_le_bytes());
+    buf.extend_from_slice(&event.pid.to_le_bytes());
+    buf.extend_from_slice(&event.device.to_le_bytes());
+    buf.extend_from_slice(&event.cpu.to_le_bytes());
+    buf.extend_from_slice(&event.error.to_le_bytes());
+    // Any pdu has already been discarded.
+    buf.extend_from_slice(&0u16.to_le_bytes());
+}
+
+fn decode_event(reader: &mut impl Read) -> std::io::Result<BlkEvent> {
+    Ok(BlkEvent {
+        magic:    read_u32(reader)?,
+        sequence: read_u32(reader)?,
+        time:     read_u64(reader)?,
+        sector:   read_u64(reader)?,
+        bytes:    read_u32(reader)?,
+        action:   read_u32(reader)?,
+        pid:      read_u32(reader)?,
+        device:   read_u32(reader)?,
+        cpu:      read_u32(reader)?,
+        error:    read_u16(reader)?,
+        pdu_len:  read_u16(reader)?,
+    })
+}
+
+/// Writes events to a recording as they are read.
+pub struct EventRecorder {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl EventRecorder {
+    pub fn create(path: &Path) -> Result<Self,String> {
+        let file = match File::create(path) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Could not create event recording '{}': {}", path.display(), e));
+            },
+        };
+        let mut recorder = Self {
+            file: BufWriter::new(file),
+            start: Instant::now(),
+        };
+        recorder.write(FILE_MAGIC);
+        Ok(recorder)
+    }
+
+    fn write(&mut self, buf: &[u8]) {
+        self.file.write_all(buf).expect("Could not write to event recording");
+    }
+
+    fn record(&mut self, record_type: u8, payload: &[u8]) {
+        let mut buf = vec![record_type];
+        buf.extend_from_slice(&(self.start.elapsed().as_nanos() as u64).to_le_bytes());
+        buf.extend_from_slice(payload);
+        self.write(&buf);
+    }
+
+    pub fn record_event(&mut self, event: &BlkEvent) {
+        let mut payload = Vec::new();
+        encode_event(event, &mut payload);
+        self.record(EVENT_RECORD, &payload);
+    }
+
+    pub fn record_lost(&mut self, lost: u64) {
+        self.record(LOST_RECORD, &lost.to_le_bytes());
+    }
+
+    /// Make sure everything recorded so far survives us being killed.
+    pub fn flush(&mut self) {
+        self.file.flush().expect("Could not write to event recording");
+    }
+}
+
+/// Reads a recording back, handing out each record once it is due.
+pub struct EventReplay {
+    file: BufReader<File>,
+    start: Instant,
+    time_scale: f64,
+    // The next record, once it has been read but isn't yet due.
+    next: Option<(Duration, Recorded)>,
+    finished: bool,
+}
+
+impl EventReplay {
+    /// `time_scale` multiplies the recorded times, so 1.0 replays in real
+    /// time and 0.0 replays everything as fast as it's asked for.
+    pub fn open(path: &Path, time_scale: f64) -> Result<Self,String> {
+        let mut file = match File::open(path) {
+            Ok(x) => BufReader::new(x),
+            Err(e) => {
+                return Err(format!("Could not open event recording '{}': {}", path.display(), e));
+            },
+        };
+        let mut magic = vec![0; FILE_MAGIC.len()];
+        if file.read_exact(&mut magic).is_err() || magic != FILE_MAGIC {
+            return Err(format!("'{}' is not an event recording", path.display()));
+        }
+        Ok(Self {
+            file,
+            start: Instant::now(),
+            time_scale,
+            next: None,
+            finished: false,
+        })
+    }
+
+    fn read_record(&mut self) -> std::io::Result<Option<(Duration, Recorded)>> {
+        let mut record_type = [0; 1];
+        if self.file.read(&mut record_type)? == 0 {
+            return Ok(None);
+        }
+        let due = Duration::from_nanos(read_u64(&mut self.file)?).mul_f64(self.time_scale);
+        let record = match record_type[0] {
+            EVENT_RECORD => Recorded::Event(decode_event(&mut self.file)?),
+            LOST_RECORD => Recorded::Lost(read_u64(&mut self.file)?),
+            other => {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unknown record type {}", other)));
+            },
+        };
+        Ok(Some((due, record)))
+    }
+
+    /// Return the next record if it is due, waiting up to 1ms for it.
+    pub fn try_read(&mut self) -> Option<Recorded> {
+        if self.next.is_none() && !self.finished {
+            match self.read_record() {
+                Ok(Some(next)) => {
+                    self.next = Some(next);
+                },
+                Ok(None) => {
+                    eprintln!("Finished replaying recorded events");
+                    self.finished = true;
+                },
+                Err(e) => {
+                    // Most likely the recorder was killed part way through.
+                    eprintln!("Warning: stopped replaying recorded events early: {}", e);
+                    self.finished = true;
+                },
+            }
+        }
+        let wait = match &self.next {
+            Some((due, _)) => due.saturating_sub(self.start.elapsed()),
+            None => {
+                std::thread::sleep(Duration::from_millis(1));
+                return None;
+            },
+        };
+        if wait > Duration::from_millis(1) {
+            std::thread::sleep(Duration::from_millis(1));
+            return None;
+        }
+        std::thread::sleep(wait);
+        self.next.take().map(|(_, record)| {record})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_replay() {
+        let dir = crate::test_util::test_dir("recording");
+        let path = dir.join("events");
+        let event = BlkEvent {
+            magic: super::super::MAGIC_NATIVE_ENDIAN | super::super::SUPPORTED_VERSION as u32,
+            sequence: 1,
+            time: 2,
+            sector: 1234,
+            bytes: 4096,
+            action: 0x20001,
+            pid: 99,
+            device: (8 << 20) | 16,
+            cpu: 3,
+            error: 0,
+            pdu_len: 0,
+        };
+        {
+            let mut recorder = EventRecorder::create(&path).unwrap();
+            recorder.record_event(&event);
+            recorder.record_lost(7);
+        }
+
+        let mut replay = EventReplay::open(&path, 0.0).unwrap();
+        match replay.try_read() {
+            Some(Recorded::Event(replayed)) => {
+                let (sector, bytes, action, pid, device) = (replayed.sector, replayed.bytes, replayed.action, replayed.pid, replayed.device);
+                assert_eq!((sector, bytes, action, pid, device), (1234, 4096, 0x20001, 99, (8 << 20) | 16));
+            },
+            other => panic!("Expected an event, got {:?}", other),
+        }
+        assert!(matches!(replay.try_read(), Some(Recorded::Lost(7))));
+        assert!(replay.try_read().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(EventReplay::open(Path::new("/dev/null"), 1.0).is_err());
+    }
+}
@@ -0,0 +1,522 @@
+// Reads kernel ring buffer pages directly from per_cpu/cpuN/trace_pipe_raw.
+//
+// This avoids the text/binary formatting layer behind trace_pipe, and lets us
+// pull a whole page of events with a single read. Every CPU gets its own
+// reader thread, which parses pages into BlkEvents (and trace_marker
+// writes) and hands them to the change logger over a channel.
+//
+// The page and event header layouts are described by the kernel in
+// events/header_page and events/header_event. We read the page header layout
+// from there, but the event header encoding has been stable for a long time,
+// so it is hard coded.
+
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::sync::mpsc::{channel,Receiver,RecvTimeoutError,Sender};
+use std::ffi::CString;
+use std::time::Duration;
+use libc::{c_int,c_void,size_t};
+use crate::quick_io::fd_poll_read;
+use super::{BlkEvent,TraceEvent,MAGIC_NATIVE_ENDIAN,SUPPORTED_VERSION};
+
+// Event header type_len values. Anything up to 28 is a data entry.
+const TYPE_LEN_PADDING: u32 = 29;
+const TYPE_LEN_TIME_EXTEND: u32 = 30;
+const TYPE_LEN_TIME_STAMP: u32 = 31;
+
+// Flags the kernel keeps in the upper bits of a page's commit field.
+const COMMIT_FLAGS_MASK: u64 = (1 << 31) | (1 << 30);
+
+// The fields of struct blk_io_trace which the blk tracer actually fills in
+// for ring buffer entries. The first 16 bytes overlap the generic
+// trace_entry header instead of holding magic, sequence and time.
+const BLK_ENTRY_FIELDS_OFFSET: usize = 16;
+const BLK_ENTRY_SIZE: usize = 48;
+
+/// Layout of the header at the start of every ring buffer page.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct PageLayout {
+    commit_offset: usize,
+    commit_size: usize,
+    data_offset: usize,
+}
+
+impl PageLayout {
+    /// Parse the contents of events/header_page.
+    pub fn parse(header_page: &str) -> Result<Self,String> {
+        let mut commit: Option<(usize, usize)> = None;
+        let mut data_offset: Option<usize> = None;
+        for line in header_page.lines() {
+            let mut name: Option<&str> = None;
+            let mut offset: Option<usize> = None;
+            let mut size: Option<usize> = None;
+            for part in line.split(';') {
+                let part = part.trim();
+                if let Some(field) = part.strip_prefix("field:") {
+                    name = field.split_whitespace().last();
+                } else if let Some(value) = part.strip_prefix("offset:") {
+                    offset = value.trim().parse().ok();
+                } else if let Some(value) = part.strip_prefix("size:") {
+                    size = value.trim().parse().ok();
+                }
+            }
+            match (name, offset, size) {
+                (Some("commit"), Some(offset), Some(size)) => {
+                    commit = Some((offset, size));
+                },
+                (Some("data"), Some(offset), _) => {
+                    data_offset = Some(offset);
+                },
+                _ => {},
+            }
+        }
+        match (commit, data_offset) {
+            (Some((commit_offset, commit_size)), Some(data_offset)) if commit_size == 4 || commit_size == 8 => {
+                Ok(Self {
+                    commit_offset,
+                    commit_size,
+                    data_offset,
+                })
+            },
+            _ => Err(String::from("Unrecognised ring buffer page header format")),
+        }
+    }
+
+    pub fn read_from(tracing_path: &Path) -> Result<Self,String> {
+        let header_page_path = tracing_path.join("events/header_page");
+        match std::fs::read_to_string(&header_page_path) {
+            Ok(header_page) => Self::parse(&header_page),
+            Err(_) => Err(format!("Could not read '{}'", header_page_path.display())),
+        }
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[offset..offset+4]);
+    u32::from_ne_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[offset..offset+8]);
+    u64::from_ne_bytes(bytes)
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(&buf[offset..offset+2]);
+    u16::from_ne_bytes(bytes)
+}
+
+/// Where to find the text of entries written to trace_marker.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct PrintEntry {
+    id: u16,
+    buf_offset: usize,
+}
+
+impl PrintEntry {
+    /// Parse the contents of events/ftrace/print/format.
+    pub fn parse(format: &str) -> Option<Self> {
+        let mut id: Option<u16> = None;
+        let mut buf_offset: Option<usize> = None;
+        for line in format.lines() {
+            if let Some(value) = line.trim().strip_prefix("ID:") {
+                id = value.trim().parse().ok();
+            }
+            let mut parts = line.split(';').map(str::trim);
+            if parts.next().is_some_and(|field| {field.starts_with("field:") && field.ends_with(" buf[]")}) {
+                buf_offset = parts.find_map(|part| {part.strip_prefix("offset:")}).and_then(|offset| {offset.trim().parse().ok()});
+            }
+        }
+        Some(Self {
+            id: id?,
+            buf_offset: buf_offset?,
+        })
+    }
+
+    fn read_from(tracing_path: &Path) -> Option<Self> {
+        Self::parse(&std::fs::read_to_string(tracing_path.join("events/ftrace/print/format")).ok()?)
+    }
+}
+
+/// Which ring buffer entries (by trace_entry type id) aren't blk entries.
+#[derive(Clone,Debug,Default)]
+pub struct EntryTypes {
+    /// Skipped when parsing.
+    foreign: Vec<u16>,
+    /// Parsed as markers, if given.
+    print: Option<PrintEntry>,
+}
+
+impl EntryTypes {
+    fn read_from(tracing_path: &Path) -> Self {
+        Self {
+            foreign: read_foreign_entry_types(tracing_path),
+            print: PrintEntry::read_from(tracing_path),
+        }
+    }
+}
+
+fn read_foreign_entry_types(tracing_path: &Path) -> Vec<u16> {
+    let mut types = Vec::new();
+    for event in &["print", "bprint"] {
+        if let Ok(format) = std::fs::read_to_string(tracing_path.join("events/ftrace").join(event).join("format")) {
+            for line in format.lines() {
+                if let Some(id) = line.strip_prefix("ID:") {
+                    if let Ok(id) = id.trim().parse() {
+                        types.push(id);
+                    }
+                }
+            }
+        }
+    }
+    types
+}
+
+/// Parse all blk entries and trace_marker writes out of a single ring buffer
+/// page, appending them to `events`.
+pub fn parse_page(page: &[u8], layout: &PageLayout, entry_types: &EntryTypes, events: &mut Vec<TraceEvent>) -> Result<(),String> {
+    if page.len() < layout.data_offset {
+        return Err(format!("Ring buffer page is too short ({} bytes)", page.len()));
+    }
+    let mut time = read_u64(page, 0);
+    let commit =
+        if layout.commit_size == 8 {
+            read_u64(page, layout.commit_offset)
+        } else {
+            read_u32(page, layout.commit_offset) as u64
+        };
+    // Lost events are picked up from the per-CPU stats instead, which also
+    // know how many went missing.
+    let data_len = (commit & !COMMIT_FLAGS_MASK) as usize;
+    let data_end = layout.data_offset + data_len;
+    if data_end > page.len() {
+        return Err(format!("Ring buffer page commit ({}) runs past end of page", data_len));
+    }
+
+    let mut offset = layout.data_offset;
+    while offset + 4 <= data_end {
+        let header = read_u32(page, offset);
+        let type_len = header & 0x1f;
+        let time_delta = (header >> 5) as u64;
+        if type_len == TYPE_LEN_PADDING && time_delta == 0 {
+            // Nothing else on this page.
+            break;
+        }
+        if (type_len == 0 || type_len >= TYPE_LEN_PADDING) && offset + 8 > data_end {
+            return Err(format!("Ring buffer event header at offset {} runs past end of page data", offset));
+        }
+        match type_len {
+            TYPE_LEN_PADDING => {
+                offset += 4 + read_u32(page, offset + 4) as usize;
+            },
+            TYPE_LEN_TIME_EXTEND => {
+                time += (read_u32(page, offset + 4) as u64) << 27 | time_delta;
+                offset += 8;
+            },
+            TYPE_LEN_TIME_STAMP => {
+                time = (read_u32(page, offset + 4) as u64) << 27 | time_delta;
+                offset += 8;
+            },
+            _ => {
+                time += time_delta;
+                let (data_offset, data_len) =
+                    if type_len == 0 {
+                        (offset + 8, (read_u32(page, offset + 4) as usize).saturating_sub(4))
+                    } else {
+                        (offset + 4, (type_len * 4) as usize)
+                    };
+                if data_offset + data_len > data_end {
+                    return Err(format!("Ring buffer event at offset {} runs past end of page data", offset));
+                }
+                let data = &page[data_offset..data_offset+data_len];
+                let entry_type = if data_len >= 2 {Some(read_u16(data, 0))} else {None};
+                if let (Some(print_entry), Some(entry_type)) = (entry_types.print, entry_type) {
+                    if entry_type == print_entry.id && data_len >= print_entry.buf_offset {
+                        let text = &data[print_entry.buf_offset..];
+                        let text = &text[..text.iter().position(|b| {*b == 0}).unwrap_or(text.len())];
+                        events.push(TraceEvent::Marker(String::from_utf8_lossy(text).trim_end().to_string()));
+                        offset = data_offset + data_len;
+                        continue;
+                    }
+                }
+                if data_len >= BLK_ENTRY_SIZE && !entry_types.foreign.contains(&read_u16(data, 0)) {
+                    let fields = BLK_ENTRY_FIELDS_OFFSET;
+                    events.push(TraceEvent::Blk(BlkEvent {
+                        magic:    MAGIC_NATIVE_ENDIAN | SUPPORTED_VERSION as u32,
+                        sequence: 0,
+                        time,
+                        sector:   read_u64(data, fields),
+                        bytes:    read_u32(data, fields + 8),
+                        action:   read_u32(data, fields + 12),
+                        pid:      read_u32(data, fields + 16),
+                        device:   read_u32(data, fields + 20),
+                        cpu:      read_u32(data, fields + 24),
+                        error:    read_u16(data, fields + 28),
+                        pdu_len:  read_u16(data, fields + 30),
+                    }));
+                }
+                offset = data_offset + data_len;
+            },
+        }
+    }
+    Ok(())
+}
+
+fn open_raw_pipe(path: &Path) -> Result<c_int,String> {
+    let fd = unsafe {
+        libc::open(CString::new(path.to_str().unwrap()).unwrap().as_ptr(), libc::O_RDONLY | libc::O_NONBLOCK)
+    };
+    if fd < 0 {
+        return Err(format!("Could not open '{}'", path.display()));
+    }
+    Ok(fd)
+}
+
+/// Read one page. Returns None if there is nothing to read right now.
+fn read_raw_page(fd: c_int, page: &mut Vec<u8>) -> Option<usize> {
+    let bytes_read = unsafe {
+        libc::read(fd, page.as_mut_ptr() as *mut c_void, page.len() as size_t)
+    };
+    if bytes_read > 0 {
+        Some(bytes_read as usize)
+    } else if bytes_read == 0 {
+        None
+    } else {
+        let errno = unsafe {*libc::__errno_location()};
+        if errno == libc::EAGAIN || errno == libc::EWOULDBLOCK || errno == libc::EINTR {
+            None
+        } else {
+            panic!("Could not read from raw trace pipe (errno {})", errno);
+        }
+    }
+}
+
+/// Reads blk events from every CPU's trace_pipe_raw using one thread per CPU.
+pub struct RawReader {
+    event_receiver: Receiver<TraceEvent>,
+    stopping: Arc<AtomicBool>,
+    join_handles: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl RawReader {
+    /// Whether per-CPU raw pipes look usable under this tracing directory.
+    pub fn is_supported(tracing_path: &Path) -> bool {
+        tracing_path.join("per_cpu/cpu0/trace_pipe_raw").exists()
+            && PageLayout::read_from(tracing_path).is_ok()
+    }
+
+    /// Open and flush every CPU's raw pipe, then start reading from them.
+    pub fn open(tracing_path: &Path) -> Result<Self,String> {
+        let layout = PageLayout::read_from(tracing_path)?;
+        let entry_types = EntryTypes::read_from(tracing_path);
+        let page_size = unsafe {libc::sysconf(libc::_SC_PAGESIZE)} as usize;
+
+        let mut raw_pipe_paths: Vec<PathBuf> = Vec::new();
+        let per_cpu_dir = match std::fs::read_dir(tracing_path.join("per_cpu")) {
+            Ok(x) => x,
+            Err(_) => {
+                return Err(String::from("Could not list per-CPU tracing directories"));
+            },
+        };
+        for entry in per_cpu_dir.flatten() {
+            if entry.file_name().to_string_lossy().starts_with("cpu") {
+                raw_pipe_paths.push(entry.path().join("trace_pipe_raw"));
+            }
+        }
+
+        let mut fds: Vec<c_int> = Vec::with_capacity(raw_pipe_paths.len());
+        for path in &raw_pipe_paths {
+            match open_raw_pipe(path) {
+                Ok(fd) => fds.push(fd),
+                Err(e) => {
+                    for fd in fds {
+                        unsafe {libc::close(fd)};
+                    }
+                    return Err(e);
+                },
+            }
+        }
+
+        // Flush anything already in the buffers so we know we're only
+        // going to get data from now on.
+        let mut page: Vec<u8> = vec![0; page_size];
+        for fd in &fds {
+            while read_raw_page(*fd, &mut page).is_some() {}
+        }
+
+        let (event_sender, event_receiver) = channel();
+        let stopping = Arc::new(AtomicBool::new(false));
+        let join_handles = fds.into_iter().zip(raw_pipe_paths).enumerate().map(
+            |(cpu, (fd, path))| {
+                let event_sender = event_sender.clone();
+                let stopping = Arc::clone(&stopping);
+                let entry_types = entry_types.clone();
+                std::thread::Builder::new()
+                    .name(format!("trace-reader-{}", cpu))
+                    .spawn(move || {
+                        Self::read_loop(fd, &path, page_size, layout, entry_types, event_sender, stopping);
+                        unsafe {libc::close(fd)};
+                    })
+                    .unwrap()
+            }
+        ).collect();
+
+        Ok(Self {
+            event_receiver,
+            stopping,
+            join_handles,
+        })
+    }
+
+    fn read_loop(fd: c_int, path: &Path, page_size: usize, layout: PageLayout, entry_types: EntryTypes, event_sender: Sender<TraceEvent>, stopping: Arc<AtomicBool>) {
+        let mut page: Vec<u8> = vec![0; page_size];
+        let mut events: Vec<TraceEvent> = Vec::new();
+        while !stopping.load(Ordering::Relaxed) {
+            match read_raw_page(fd, &mut page) {
+                Some(bytes_read) => {
+                    if let Err(e) = parse_page(&page[..bytes_read], &layout, &entry_types, &mut events) {
+                        panic!("Could not parse ring buffer page from '{}': {}", path.display(), e);
+                    }
+                    for event in events.drain(..) {
+                        if event_sender.send(event).is_err() {
+                            return;
+                        }
+                    }
+                },
+                None => {
+                    // Don't rely on poll alone, as the kernel may hold off
+                    // waking us until the buffer is partly full.
+                    fd_poll_read(fd, 1);
+                },
+            }
+        }
+    }
+
+    /// Wait up to 1ms for an event from any CPU.
+    pub fn try_read(&self) -> Option<TraceEvent> {
+        match self.event_receiver.recv_timeout(Duration::from_millis(1)) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                panic!("All trace reader threads have died");
+            },
+        }
+    }
+}
+
+impl Drop for RawReader {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        for join_handle in self.join_handles.drain(..) {
+            if join_handle.join().is_err() {
+                eprintln!("Warning: a trace reader thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_PAGE: &str = "\tfield: u64 timestamp;\toffset:0;\tsize:8;\tsigned:0;\n\tfield: local_t commit;\toffset:8;\tsize:8;\tsigned:1;\n\tfield: int overwrite;\toffset:8;\tsize:1;\tsigned:1;\n\tfield: char data;\toffset:16;\tsize:4080;\tsigned:1;\n";
+
+    fn push_blk_entry(page: &mut Vec<u8>, entry_type: u16, sector: u64, bytes: u32) {
+        let mut entry: Vec<u8> = vec![0; BLK_ENTRY_SIZE];
+        entry[0..2].copy_from_slice(&entry_type.to_ne_bytes());
+        entry[16..24].copy_from_slice(&sector.to_ne_bytes());
+        entry[24..28].copy_from_slice(&bytes.to_ne_bytes());
+        entry[28..32].copy_from_slice(&0x0002_0001u32.to_ne_bytes());
+        entry[36..40].copy_from_slice(&((8 << 20) | 16u32).to_ne_bytes());
+        // Long form header: type_len 0, length in array[0].
+        page.extend_from_slice(&(5u32 << 5).to_ne_bytes());
+        page.extend_from_slice(&((entry.len() + 4) as u32).to_ne_bytes());
+        page.extend_from_slice(&entry);
+    }
+
+    fn blk_events(events: Vec<TraceEvent>) -> Vec<BlkEvent> {
+        events.into_iter().map(|event| {
+            match event {
+                TraceEvent::Blk(event) => event,
+                TraceEvent::Marker(marker) => panic!("Unexpected marker '{}'", marker),
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn test_parse_page_layout() {
+        let layout = PageLayout::parse(HEADER_PAGE).unwrap();
+        assert_eq!(layout, PageLayout {commit_offset: 8, commit_size: 8, data_offset: 16});
+        assert!(PageLayout::parse("nonsense").is_err());
+    }
+
+    #[test]
+    fn test_parse_page() {
+        let layout = PageLayout::parse(HEADER_PAGE).unwrap();
+        let mut data: Vec<u8> = Vec::new();
+        push_blk_entry(&mut data, 1, 2048, 4096);
+        // A time extend, then a foreign (print) entry, then another blk entry.
+        data.extend_from_slice(&(TYPE_LEN_TIME_EXTEND | (1 << 5)).to_ne_bytes());
+        data.extend_from_slice(&1u32.to_ne_bytes());
+        push_blk_entry(&mut data, 5, 0, 0);
+        push_blk_entry(&mut data, 1, 4096, 512);
+
+        let mut page: Vec<u8> = Vec::new();
+        page.extend_from_slice(&1000u64.to_ne_bytes());
+        // Set a missed events flag to make sure it's masked off.
+        page.extend_from_slice(&((data.len() as u64) | (1 << 31)).to_ne_bytes());
+        page.extend_from_slice(&data);
+        page.resize(4096, 0);
+
+        let mut events = Vec::new();
+        parse_page(&page, &layout, &EntryTypes {foreign: vec![5], print: None}, &mut events).unwrap();
+        let events = blk_events(events);
+        assert_eq!(events.len(), 2);
+        let (sector, bytes, device) = (events[0].sector, events[0].bytes, events[0].device);
+        assert_eq!((sector, bytes, device), (2048, 4096, (8 << 20) | 16));
+        let (sector, bytes, action) = (events[1].sector, events[1].bytes, events[1].action);
+        assert_eq!((sector, bytes, action), (4096, 512, 0x0002_0001));
+        let (first_time, second_time) = (events[0].time, events[1].time);
+        assert_eq!(first_time, 1005);
+        assert!(second_time > first_time);
+    }
+
+    #[test]
+    fn test_parse_empty_page() {
+        let layout = PageLayout::parse(HEADER_PAGE).unwrap();
+        let page: Vec<u8> = vec![0; 4096];
+        let mut events = Vec::new();
+        parse_page(&page, &layout, &EntryTypes::default(), &mut events).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_parse_marker() {
+        let print_format = "name: print\nID: 5\nformat:\n\tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;\n\tfield:unsigned long ip;\toffset:8;\tsize:8;\tsigned:0;\n\tfield:char buf[];\toffset:16;\tsize:0;\tsigned:0;\n";
+        let print_entry = PrintEntry::parse(print_format).unwrap();
+        assert_eq!(print_entry, PrintEntry {id: 5, buf_offset: 16});
+
+        let layout = PageLayout::parse(HEADER_PAGE).unwrap();
+        let mut entry: Vec<u8> = vec![0; 16];
+        entry[0..2].copy_from_slice(&5u16.to_ne_bytes());
+        entry.extend_from_slice(b"trackup-barrier-1-0\n\0");
+        entry.resize(entry.len().div_ceil(4) * 4, 0);
+        let mut page: Vec<u8> = Vec::new();
+        page.extend_from_slice(&0u64.to_ne_bytes());
+        page.extend_from_slice(&((entry.len() + 4) as u64).to_ne_bytes());
+        page.extend_from_slice(&(((entry.len() / 4) as u32) | (1 << 5)).to_ne_bytes());
+        page.extend_from_slice(&entry);
+        page.resize(4096, 0);
+
+        let mut events = Vec::new();
+        parse_page(&page, &layout, &EntryTypes {foreign: vec![5], print: Some(print_entry)}, &mut events).unwrap();
+        match events.as_slice() {
+            [TraceEvent::Marker(marker)] => assert_eq!(marker, "trackup-barrier-1-0"),
+            _ => panic!("Expected a single marker"),
+        }
+    }
+}
@@ -1,4 +1,13 @@
 pub struct Chunk {
     pub offset: u64,
-    pub data: Vec<u8>,
+    pub content: ChunkContent,
+}
+
+pub enum ChunkContent {
+    /// Data read from the source.
+    Data(Vec<u8>),
+    /// The given number of bytes were discarded on the source.
+    Discarded(usize),
+    /// The given number of bytes were zeroed on the source.
+    Zeroed(usize),
 }
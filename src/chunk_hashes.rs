@@ -0,0 +1,64 @@
+// Finds changed chunks without tracing, by remembering a hash of each chunk's
+// data as it was copied, and comparing it with a hash of what the source
+// holds now.
+//
+// The hashes aren't cryptographic, but are keyed randomly for each run, so
+// data can't be crafted to collide.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher,Hasher};
+
+pub struct ChunkHashes {
+    keys: RandomState,
+    // None for chunks which haven't been copied yet.
+    hashes: Vec<Option<u64>>,
+}
+
+impl ChunkHashes {
+    pub fn new(chunk_count: usize) -> Self {
+        Self {
+            keys: RandomState::new(),
+            hashes: vec![None; chunk_count],
+        }
+    }
+
+    fn hash(&self, data: &[u8]) -> u64 {
+        let mut hasher = self.keys.build_hasher();
+        hasher.write(data);
+        hasher.finish()
+    }
+
+    /// Remember the data which has been copied for a chunk.
+    pub fn record(&mut self, index: usize, data: &[u8]) {
+        if index >= self.hashes.len() {
+            // The source has grown.
+            self.hashes.resize(index + 1, None);
+        }
+        self.hashes[index] = Some(self.hash(data));
+    }
+
+    /// Whether a chunk's data differs from what was last copied.
+    pub fn changed(&self, index: usize, data: &[u8]) -> bool {
+        match self.hashes.get(index) {
+            Some(Some(hash)) => *hash != self.hash(data),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_hashes() {
+        let mut hashes = ChunkHashes::new(2);
+        assert!(hashes.changed(0, &[0; 512]));
+        hashes.record(0, &[0; 512]);
+        assert!(!hashes.changed(0, &[0; 512]));
+        assert!(hashes.changed(0, &[1; 512]));
+        hashes.record(3, &[1; 512]);
+        assert!(hashes.changed(2, &[0; 512]));
+        assert!(!hashes.changed(3, &[1; 512]));
+    }
+}
@@ -1,13 +1,67 @@
+use std::ops::Range;
 use crate::alias_tree::AliasTree;
+use crate::change_logger::ChangeKind;
 use crate::control::{ProgressLogging};
 
+// Chunk flags are packed into one bitset per flag, so that trackers for
+// multi-terabyte devices (which are locked into memory) stay small. Each
+// word of a bitset holds 64 chunks. An alias tree of the flags found in each
+// word then lets pending chunks be found, and progress diagrams be drawn,
+// without looking at every word.
 pub struct ChunkTracker {
     chunk_count: usize,
-    chunks: AliasTree<u8>,
+    // Indexed by the flag's bit number.
+    flags: [Vec<u64>; FLAG_COUNT],
+    words: AliasTree<u8>,
 }
 
+const CHUNKS_PER_WORD: usize = 64;
+// log2(CHUNKS_PER_WORD)
+const WORD_HEIGHT: usize = 6;
+const FLAG_COUNT: usize = 4;
+
 const FLAG_UNPROCESSED: u8 = 2;
 const FLAG_DIRTY: u8 = 1;
+// A chunk which has only been discarded or zeroed since it was last copied
+// doesn't need its data copying. Changes needn't arrive in the order they
+// were made (e.g. from per-CPU readers), so these flags are only ever added
+// to the chunk's state, and never clear FLAG_DIRTY.
+const FLAG_DISCARDED: u8 = 4;
+const FLAG_ZEROED: u8 = 8;
+
+/// What needs doing to bring a chunk with the given (non-zero) flags up to
+/// date in the backup.
+fn change_for(flags: u8) -> ChangeKind {
+    // Zeros are a valid result of either a discard or zeroing, whichever
+    // happened last, but a discard needn't leave zeros behind.
+    if flags & (FLAG_UNPROCESSED | FLAG_DIRTY) != 0 {
+        ChangeKind::Write
+    } else if flags & FLAG_ZEROED != 0 {
+        ChangeKind::WriteZeroes
+    } else if flags & FLAG_DISCARDED != 0 {
+        ChangeKind::Discard
+    } else {
+        ChangeKind::Write
+    }
+}
+
+/// Reduce chunk flags to those shown in progress diagrams, where discarded
+/// and zeroed chunks are displayed as dirty.
+fn display_flags(flags: u8) -> u8 {
+    let mut display = flags & (FLAG_UNPROCESSED | FLAG_DIRTY);
+    if flags & (FLAG_DISCARDED | FLAG_ZEROED) != 0 {
+        display |= FLAG_DIRTY;
+    }
+    display
+}
+
+/// The bits of a word covering a range of chunks, which must overlap it.
+fn word_mask(word: usize, chunks: &Range<usize>) -> u64 {
+    let first = chunks.start.max(word * CHUNKS_PER_WORD) - word * CHUNKS_PER_WORD;
+    let end = chunks.end.min((word + 1) * CHUNKS_PER_WORD) - word * CHUNKS_PER_WORD;
+    let below_end = if end == CHUNKS_PER_WORD {!0} else {(1 << end) - 1};
+    below_end & !((1 << first) - 1)
+}
 
 impl ChunkTracker {
     pub fn new(chunk_count: usize) -> Self {
@@ -15,12 +69,14 @@ impl ChunkTracker {
         //     devices.sectors / chunk_size
         //     + if devices.sectors % chunk_size {1} else {0};
 
-        let chunks: AliasTree<u8> = AliasTree::new(chunk_count, FLAG_UNPROCESSED);
-
-        ChunkTracker {
+        let word_count = chunk_count.div_ceil(CHUNKS_PER_WORD);
+        let mut tracker = ChunkTracker {
             chunk_count,
-            chunks,
-        }
+            flags: [vec![0; word_count], vec![0; word_count], vec![0; word_count], vec![0; word_count]],
+            words: AliasTree::new(word_count, 0),
+        };
+        tracker.change_range(0..chunk_count, FLAG_UNPROCESSED, true);
+        tracker
     }
 
     pub fn get_chunk_count(&self) -> usize {
@@ -37,28 +93,186 @@ impl ChunkTracker {
     //     sector / self.chunk_size;
     // }
 
+    fn get(&self, index: usize) -> u8 {
+        let word = index / CHUNKS_PER_WORD;
+        let bit = index % CHUNKS_PER_WORD;
+        let mut flags = 0;
+        for (flag_bit, words) in self.flags.iter().enumerate() {
+            flags |= (((words[word] >> bit) & 1) as u8) << flag_bit;
+        }
+        flags
+    }
+
+    /// The flags of any chunk in a word, under a mask.
+    fn word_flags(&self, word: usize, mask: u64) -> u8 {
+        let mut flags = 0;
+        for (flag_bit, words) in self.flags.iter().enumerate() {
+            if words[word] & mask != 0 {
+                flags |= 1 << flag_bit;
+            }
+        }
+        flags
+    }
+
+    /// Pending chunks in a word.
+    fn pending_bits(&self, word: usize) -> u64 {
+        self.flags.iter().fold(0, |bits, words| {bits | words[word]})
+    }
+
+    fn flag_bits(&self, word: usize, flag: u8) -> u64 {
+        self.flags[flag.trailing_zeros() as usize][word]
+    }
+
+    /// The chunks in a word needing the given change, as change_for would
+    /// find for each of them.
+    fn change_bits(&self, word: usize, change: ChangeKind) -> u64 {
+        let write = self.flag_bits(word, FLAG_UNPROCESSED) | self.flag_bits(word, FLAG_DIRTY);
+        let zeroed = self.flag_bits(word, FLAG_ZEROED) & !write;
+        match change {
+            ChangeKind::Write => write,
+            ChangeKind::WriteZeroes => zeroed,
+            ChangeKind::Discard => self.flag_bits(word, FLAG_DISCARDED) & !write & !zeroed,
+        }
+    }
+
+    /// Either replace the flags of a range of chunks, or add to them.
+    fn change_range(&mut self, chunks: Range<usize>, flags: u8, replace: bool) {
+        // Chunks beyond the end are only possible if the device has shrunk,
+        // which fails the job once noticed.
+        let chunks = chunks.start..chunks.end.min(self.chunk_count);
+        if chunks.is_empty() {
+            return;
+        }
+        let first_word = chunks.start / CHUNKS_PER_WORD;
+        let end_word = chunks.end.div_ceil(CHUNKS_PER_WORD);
+        for word in first_word..end_word {
+            let mask = word_mask(word, &chunks);
+            for (flag_bit, words) in self.flags.iter_mut().enumerate() {
+                if flags & (1 << flag_bit) != 0 {
+                    words[word] |= mask;
+                } else if replace {
+                    words[word] &= !mask;
+                }
+            }
+        }
+        // Words wholly within the range all gain the same flags. Flags are
+        // only replaced for single chunks and new ones, which aren't worth
+        // doing lazily.
+        let whole_words = chunks.start.div_ceil(CHUNKS_PER_WORD)..(chunks.end / CHUNKS_PER_WORD);
+        if whole_words.is_empty() || replace {
+            for word in first_word..end_word {
+                self.words.set(word, self.word_flags(word, !0));
+            }
+            return;
+        }
+        self.words.or_mask_range(whole_words.clone(), flags);
+        for word in [first_word, end_word - 1] {
+            if !whole_words.contains(&word) {
+                self.words.set(word, self.word_flags(word, !0));
+            }
+        }
+    }
+
     pub fn clear_chunk(&mut self, index: usize) {
-        self.chunks.set(index, 0);
+        self.change_range(index..(index + 1), 0, true);
     }
 
     pub fn mark_chunk(&mut self, index: usize) {
-        self.chunks.or_mask(index, FLAG_DIRTY);
+        self.change_range(index..(index + 1), FLAG_DIRTY, false);
     }
 
-    #[allow(dead_code)]
-    pub fn mark_chunks(&mut self, start: usize, end: usize) {
-        let end = if end < self.chunk_count {
-            end
-        } else {
-            self.chunk_count
+    /// Record a change to a run of chunks. Discards and zeroes must cover
+    /// whole chunks. As changes may be recorded out of order, they are
+    /// added to any earlier state, and a write always wins.
+    pub fn record_changes(&mut self, chunks: Range<usize>, kind: ChangeKind) {
+        let flag = match kind {
+            ChangeKind::Write => FLAG_DIRTY,
+            ChangeKind::Discard => FLAG_DISCARDED,
+            ChangeKind::WriteZeroes => FLAG_ZEROED,
         };
-        for i in start..end {
-            self.chunks.or_mask(i, FLAG_DIRTY);
+        self.change_range(chunks, flag, false);
+    }
+
+    /// Whether the chunk still needs something doing to it.
+    pub fn is_pending(&self, index: usize) -> bool {
+        self.get(index) != 0
+    }
+
+    /// What needs doing to bring a pending chunk up to date in the backup.
+    pub fn get_chunk_change(&self, index: usize) -> ChangeKind {
+        change_for(self.get(index))
+    }
+
+    /// The end of the run of pending chunks from start (which must be
+    /// pending) needing the same change, which can then be made with a
+    /// single I/O. The run ends at limit at the latest.
+    pub fn find_run_end(&self, start: usize, limit: usize) -> usize {
+        let change = self.get_chunk_change(start);
+        let limit = limit.min(self.chunk_count);
+        let mut word = start / CHUNKS_PER_WORD;
+        let mut outside = !self.change_bits(word, change) & (!0 << (start % CHUNKS_PER_WORD));
+        while outside == 0 {
+            word += 1;
+            if word * CHUNKS_PER_WORD >= limit {
+                return limit;
+            }
+            outside = !self.change_bits(word, change);
         }
+        (word * CHUNKS_PER_WORD + outside.trailing_zeros() as usize).min(limit)
+    }
+
+    /// Extend the tracker for a device which has grown. New chunks are
+    /// unprocessed, and the old last chunk may have been partial, so is
+    /// marked dirty.
+    pub fn grow(&mut self, chunk_count: usize) {
+        if chunk_count <= self.chunk_count {
+            return;
+        }
+        let old_chunk_count = self.chunk_count;
+        let word_count = chunk_count.div_ceil(CHUNKS_PER_WORD);
+        for words in self.flags.iter_mut() {
+            words.resize(word_count, 0);
+        }
+        self.words = AliasTree::new(word_count, 0);
+        for word in 0..word_count {
+            let flags = self.word_flags(word, !0);
+            if flags != 0 {
+                self.words.set(word, flags);
+            }
+        }
+        self.chunk_count = chunk_count;
+        self.change_range(old_chunk_count..chunk_count, FLAG_UNPROCESSED, true);
+        if old_chunk_count > 0 {
+            self.mark_chunk(old_chunk_count - 1);
+        }
+    }
+
+    pub fn mark_chunks(&mut self, start: usize, end: usize) {
+        self.change_range(start..end, FLAG_DIRTY, false);
     }
 
     pub fn find_next(&self, start: usize) -> Option<usize> {
-        self.chunks.find_next(|x|{*x!=0}, start)
+        if start >= self.chunk_count {
+            return None;
+        }
+        let word = start / CHUNKS_PER_WORD;
+        let rest = self.pending_bits(word) & (!0 << (start % CHUNKS_PER_WORD));
+        if rest != 0 {
+            return Some(word * CHUNKS_PER_WORD + rest.trailing_zeros() as usize);
+        }
+        let word = self.words.find_next(|x|{*x!=0}, word + 1)?;
+        Some(word * CHUNKS_PER_WORD + self.pending_bits(word).trailing_zeros() as usize)
+    }
+
+    /// The flags of any chunk in the aligned block of 1 << height chunks
+    /// containing index.
+    fn get_aliased(&self, index: usize, height: usize) -> u8 {
+        if height >= WORD_HEIGHT {
+            return self.words.get_aliased(index / CHUNKS_PER_WORD, height - WORD_HEIGHT);
+        }
+        let first = index >> height << height;
+        let block = first..(first + (1 << height));
+        self.word_flags(index / CHUNKS_PER_WORD, word_mask(index / CHUNKS_PER_WORD, &block))
     }
 
     pub fn summary_report(&self, progress_logging: &ProgressLogging, height: usize) -> String {
@@ -70,7 +284,7 @@ impl ChunkTracker {
         let mut done = 0;
 
         for index in 0..checks {
-            let flags = *self.chunks.get_aliased(index*factor, height);
+            let flags = display_flags(self.get_aliased(index*factor, height));
             diagram.push_str(&progress_logging.diagram_cells[flags as usize]);
             if flags == 0 {
                 done += 1;
@@ -85,7 +299,7 @@ impl ChunkTracker {
         let checks = (self.chunk_count-1)/factor+1;
         let mut cells = Vec::with_capacity(checks);
         for index in 0..checks {
-            cells.push(*self.chunks.get_aliased(index*factor, height));
+            cells.push(display_flags(self.get_aliased(index*factor, height)));
         }
         cells
     }
@@ -102,3 +316,118 @@ pub fn calculate_display_detail(chunk_count: usize, limit: usize) -> usize {
         } as usize
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_change() {
+        let mut tracker = ChunkTracker::new(4);
+        for index in 0..4 {
+            tracker.clear_chunk(index);
+        }
+        tracker.record_changes(0..1, ChangeKind::Discard);
+        tracker.record_changes(1..2, ChangeKind::WriteZeroes);
+        tracker.record_changes(2..3, ChangeKind::Discard);
+        tracker.record_changes(2..3, ChangeKind::Write);
+        tracker.record_changes(3..4, ChangeKind::Write);
+        tracker.record_changes(3..4, ChangeKind::Discard);
+
+        assert_eq!(tracker.get_chunk_change(0), ChangeKind::Discard);
+        assert_eq!(tracker.get_chunk_change(1), ChangeKind::WriteZeroes);
+        assert_eq!(tracker.get_chunk_change(2), ChangeKind::Write);
+        assert_eq!(tracker.get_chunk_change(3), ChangeKind::Write);
+        assert_eq!(tracker.snapshot_level(0), vec![1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_write_before_discard() {
+        // A write recorded ahead of an earlier discard covering it (as
+        // readers on different CPUs may deliver them) must still be copied.
+        let mut tracker = ChunkTracker::new(4);
+        for index in 0..4 {
+            tracker.clear_chunk(index);
+        }
+        tracker.record_changes(1..2, ChangeKind::Write);
+        tracker.record_changes(0..4, ChangeKind::Discard);
+        tracker.record_changes(2..3, ChangeKind::WriteZeroes);
+        assert_eq!(tracker.get_chunk_change(0), ChangeKind::Discard);
+        assert_eq!(tracker.get_chunk_change(1), ChangeKind::Write);
+        assert_eq!(tracker.get_chunk_change(2), ChangeKind::WriteZeroes);
+        assert_eq!(tracker.get_chunk_change(3), ChangeKind::Discard);
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut tracker = ChunkTracker::new(3);
+        for index in 0..3 {
+            tracker.clear_chunk(index);
+        }
+        tracker.record_changes(0..1, ChangeKind::Discard);
+        tracker.record_changes(5..6, ChangeKind::Write);
+        tracker.grow(6);
+        assert_eq!(tracker.get_chunk_count(), 6);
+        assert_eq!(tracker.get_chunk_change(0), ChangeKind::Discard);
+        assert_eq!(tracker.snapshot_level(0), vec![1, 0, 1, 2, 2, 2]);
+        assert_eq!(tracker.find_next(1), Some(2));
+    }
+
+    #[test]
+    fn test_packed_words() {
+        // Runs across word boundaries, checked against plain flags.
+        let mut tracker = ChunkTracker::new(300);
+        let mut expected: Vec<u8> = vec![FLAG_UNPROCESSED; 300];
+        for index in 0..300 {
+            tracker.clear_chunk(index);
+            expected[index] = 0;
+        }
+        let changes = [(10..20, ChangeKind::Write), (60..200, ChangeKind::Discard), (63..66, ChangeKind::Write), (128..192, ChangeKind::WriteZeroes), (250..400, ChangeKind::Write)];
+        for (chunks, kind) in changes {
+            tracker.record_changes(chunks.clone(), kind);
+            for index in chunks.start..chunks.end.min(300) {
+                expected[index] = match kind {
+                    ChangeKind::Write => expected[index] | FLAG_DIRTY,
+                    ChangeKind::Discard => expected[index] | FLAG_DISCARDED,
+                    ChangeKind::WriteZeroes => expected[index] | FLAG_ZEROED,
+                };
+            }
+        }
+        tracker.grow(330);
+        expected[299] |= FLAG_DIRTY;
+        expected.resize(330, FLAG_UNPROCESSED);
+        for index in 0..330 {
+            assert_eq!(tracker.get(index), expected[index]);
+            assert_eq!(tracker.find_next(index), (index..330).find(|i| {expected[*i] != 0}));
+        }
+        for height in [0, 3, 6, 7] {
+            let factor = 1 << height;
+            let cells: Vec<u8> = expected.chunks(factor).map(|block| {display_flags(block.iter().fold(0, |x, y| {x | y}))}).collect();
+            assert_eq!(tracker.snapshot_level(height), cells);
+        }
+        assert_eq!(tracker.find_run_end(60, 100), 63);
+        assert_eq!(tracker.find_run_end(66, 300), 128);
+        assert_eq!(tracker.find_run_end(250, 310), 310);
+        for index in (0..330).filter(|i| {expected[*i] != 0}) {
+            let change = change_for(expected[index]);
+            let run_end = (index..330).find(|i| {expected[*i] == 0 || change_for(expected[*i]) != change}).unwrap_or(330);
+            assert_eq!(tracker.find_run_end(index, 330), run_end);
+        }
+    }
+
+    #[test]
+    fn test_find_run_end() {
+        let mut tracker = ChunkTracker::new(8);
+        for index in 2..6 {
+            tracker.clear_chunk(index);
+        }
+        tracker.mark_chunk(3);
+        tracker.record_changes(4..5, ChangeKind::Discard);
+        tracker.record_changes(5..6, ChangeKind::Discard);
+        assert_eq!(tracker.find_run_end(0, 8), 2);
+        assert_eq!(tracker.find_run_end(0, 1), 1);
+        assert_eq!(tracker.find_run_end(3, 8), 4);
+        assert_eq!(tracker.find_run_end(4, 8), 6);
+        assert_eq!(tracker.find_run_end(6, 100), 8);
+    }
+}
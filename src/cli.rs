@@ -21,6 +21,13 @@ pub fn get_app() -> App<'static, 'static> {
                 .help("Path to sysfs")
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("state-path")
+                .long("state-path")
+                .value_name("STATE_PATH")
+                .help("Directory for the system-wide lock and journals of tracing settings")
+                .takes_value(true)
+        )
         .arg(
             Arg::with_name("trace-buffer-size")
                 .short("b")
@@ -29,6 +36,36 @@ pub fn get_app() -> App<'static, 'static> {
                 .help("Per-CPU size of kernel tracing buffer in KB")
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("change-detection")
+                .long("change-detection")
+                .value_name("METHOD")
+                .help("How changes to the sources are found. Hashing works without tracing, but is only consistent if locking stops all writes")
+                .takes_value(true)
+                .possible_values(&["auto", "tracing", "hashing"])
+        )
+        .arg(
+            Arg::with_name("reader-threads")
+                .long("reader-threads")
+                .value_name("THREADS")
+                .help("Number of threads reading from the sources (per source with --reader-pool per-device)")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("reader-pool")
+                .long("reader-pool")
+                .value_name("POOL")
+                .help("Whether the reader threads are shared between sources, or each source has its own")
+                .takes_value(true)
+                .possible_values(&["shared", "per-device"])
+        )
+        .arg(
+            Arg::with_name("max-io-size")
+                .long("max-io-size")
+                .value_name("BYTES")
+                .help("Largest read or write to make when copying runs of neighbouring chunks")
+                .takes_value(true)
+        )
         .arg(
             Arg::with_name("progress-period")
                 .short("p")
@@ -68,7 +105,7 @@ pub fn get_app() -> App<'static, 'static> {
                 .takes_value(true)
                 .number_of_values(2)
                 .multiple(true)
-                .required_unless("daemon")
+                .required_unless_one(&["daemon", "recover"])
                 .conflicts_with("manifest")
         )
         .arg(
@@ -78,7 +115,7 @@ pub fn get_app() -> App<'static, 'static> {
                 .value_name("CHUNK_SIZE")
                 .help("Granularity of modification tracking")
                 .takes_value(true)
-                .required(true)
+                .required_unless("recover")
                 .conflicts_with("manifest")
         )
         .arg(
@@ -105,6 +142,13 @@ pub fn get_app() -> App<'static, 'static> {
                 .takes_value(false)
                 .requires("management-socket")
         )
+        .arg(
+            Arg::with_name("recover")
+                .long("recover")
+                .help("Restore tracing settings left behind by trackup processes which did not exit cleanly, then exit")
+                .takes_value(false)
+                .conflicts_with_all(&["copy", "daemon", "manifest"])
+        )
         .arg(
             Arg::with_name("config")
                 .long("config")
@@ -21,7 +21,7 @@ pub enum Response {
     Cancel(Result<(),String>),
     Pause(Result<(),String>),
     Resume(Result<(),String>),
-    Query(Status),
+    Query(Box<Status>),
 }
 
 #[derive(Clone,Serialize,Deserialize)]
@@ -33,14 +33,94 @@ pub struct ProgressLogging {
     pub diagram_cells_reset: String,
 }
 
+/// How blk trace events are read from the kernel.
+#[derive(Copy,Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub enum TraceReader {
+    /// Use per-CPU raw pipes if the kernel provides them, otherwise trace_pipe.
+    Auto,
+    /// Read formatted binary events one at a time from trace_pipe.
+    Pipe,
+    /// Read whole ring buffer pages from per_cpu/cpuN/trace_pipe_raw.
+    PerCpuRaw,
+}
+
+/// Which kernel mechanism is used to trace block device writes.
+#[derive(Copy,Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub enum TraceBackend {
+    /// Use the blk tracer if the kernel has it, otherwise the tracepoint.
+    Auto,
+    /// The blk tracer (current_tracer = blk), as used by blktrace.
+    BlkTracer,
+    /// The block:block_bio_queue tracepoint.
+    BlockBioQueue,
+}
+
+/// How the copier finds chunks which have changed since they were copied.
+#[derive(Copy,Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub enum ChangeDetection {
+    /// Hash if tracing is unavailable, otherwise trace.
+    Auto,
+    /// Trace writes to the sources.
+    Tracing,
+    /// Re-read the sources on every pass, comparing hashes of each chunk
+    /// with those of the data last copied. This is only consistent if
+    /// locking stops everything writing to the sources.
+    Hashing,
+}
+
+/// Which reader threads read each source's chunks.
+#[derive(Copy,Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub enum ReaderPool {
+    /// One pool of threads reads from all of the sources.
+    Shared,
+    /// Each source has a pool of its own, so that a slow source can't hold
+    /// up the others.
+    PerDevice,
+}
+
 #[derive(Clone,Serialize,Deserialize)]
 pub struct Config {
     pub tracing_path: PathBuf,
     pub sys_path: PathBuf,
+    /// Per-CPU trace buffer size in KB, at least to begin with.
     pub trace_buffer_size: usize,
+    /// Bounds for resizing the trace buffers to suit the rate of events.
+    /// They're left alone if these are equal.
+    pub trace_buffer_min_size: usize,
+    pub trace_buffer_max_size: usize,
+    pub trace_reader: TraceReader,
+    pub trace_backend: TraceBackend,
+    pub change_detection: ChangeDetection,
+    /// Number of threads reading chunks from the sources (in each pool),
+    /// which allows several reads to be in progress at once.
+    pub reader_threads: usize,
+    pub reader_pool: ReaderPool,
+    /// Runs of neighbouring chunks needing the same change are read and
+    /// written together, in I/Os of up to this many bytes (or one chunk, if
+    /// larger).
+    pub max_io_size: usize,
+    /// Trace in a private tracefs instance rather than the global buffer,
+    /// so that other tracing users (including other trackup runs) don't
+    /// interfere. Not possible with the blk tracer.
+    pub trace_instance: bool,
+    /// Holds the system-wide tracing lock, and journals of the tracing
+    /// settings each running session has changed.
+    pub state_path: PathBuf,
+    /// Record the traced events which change data to this file, so that the
+    /// run's changes can be replayed later.
+    pub trace_recording: Option<PathBuf>,
+    /// Replay a recording instead of tracing.
+    pub change_replay: Option<ChangeReplay>,
     pub progress_logging: Option<ProgressLogging>,
 }
 
+#[derive(Clone,Serialize,Deserialize)]
+pub struct ChangeReplay {
+    pub path: PathBuf,
+    /// Multiplies the recorded event times. 0 replays as fast as possible.
+    pub time_scale: f64,
+}
+
 pub const PLAIN_DIAGRAM_CELLS: [&str; 4] = ["#", "*", ".", "o"];
 pub const COLOR_DIAGRAM_CELLS: [&str; 4] = ["\x1b[42m#", "\x1b[41m*", "\x1b[100m.", "\x1b[44mo"];
 
@@ -50,6 +130,91 @@ pub struct Job {
     pub destination: PathBuf,
     pub chunk_size: usize,
     pub reuse_output: bool,
+    pub trace_layer: TraceLayer,
+    /// If nothing can write to the source (it is unmounted and can be
+    /// opened exclusively, or only mounted read-only, and nothing is stacked
+    /// on it), copy it in a single pass without tracing it.
+    pub untraced_when_idle: bool,
+    /// Take changes to the source from a running QEMU virtual machine,
+    /// rather than tracing them.
+    pub qemu: Option<QemuSource>,
+    /// Serve the source (a raw image file) over NBD, and take changes from
+    /// the writes made through it, rather than tracing them.
+    pub nbd_server: Option<NbdServer>,
+    pub read_mode: ReadMode,
+}
+
+/// How a job's source is read.
+#[derive(Copy,Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub enum ReadMode {
+    /// Through the page cache, as normal.
+    Cached,
+    /// With O_DIRECT, bypassing the page cache. Falls back to DropBehind if
+    /// the source doesn't support it.
+    Direct,
+    /// Through the page cache, but dropping what has been read from it
+    /// straight away, so that it doesn't push out anything else.
+    DropBehind,
+}
+
+/// A disk of a QEMU virtual machine, whose changes are found with a dirty
+/// bitmap. The job's source must present the same data as the guest sees
+/// (e.g. an NBD device connected to an export of the node).
+#[derive(Clone,Serialize,Deserialize)]
+pub struct QemuSource {
+    /// The machine's QMP monitor socket.
+    pub qmp_socket: PathBuf,
+    /// The block node (not device) name of the disk.
+    pub node: String,
+    /// Names of the dirty bitmaps are derived from this.
+    pub bitmap: String,
+    /// Where QEMU's NBD server listens, for reading the bitmaps.
+    pub nbd_socket: PathBuf,
+    /// How often the bitmap is checked for changes.
+    pub poll_period: Duration,
+}
+
+/// An NBD server for a job's source, which its real users connect to
+/// instead of opening it directly.
+#[derive(Clone,Serialize,Deserialize)]
+pub struct NbdServer {
+    /// The unix socket to listen on.
+    pub socket: PathBuf,
+    /// The name clients must ask for. Empty for the default export.
+    pub export_name: String,
+}
+
+/// Where in a stack of block devices (e.g. LVM or dm-crypt on top of a
+/// partition) writes to a job's source are traced.
+#[derive(Copy,Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub enum TraceLayer {
+    /// Trace the source device itself (or its whole disk if it is a
+    /// partition).
+    Top,
+    /// Trace the physical devices beneath the source, mapping writes back
+    /// through device-mapper tables. This also sees writes which bypass the
+    /// source.
+    Underlying,
+}
+
+/// How data is flushed to the sources before checking for consistency.
+#[derive(Copy,Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub enum SyncMethod {
+    /// sync(), which flushes every filesystem on the machine.
+    Global,
+    /// syncfs() on just the filesystems which live on the sources, falling
+    /// back to sync() if they can't be worked out.
+    Filesystems,
+}
+
+/// What happens to the rest of a backup when one of its jobs fails.
+#[derive(Copy,Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub enum JobFailurePolicy {
+    /// Stop the whole backup.
+    AbortAll,
+    /// Drop the failed job and carry on with the others. The backup still
+    /// fails if every job does.
+    ContinueOthers,
 }
 
 #[derive(Clone,Serialize,Deserialize)]
@@ -64,7 +229,28 @@ pub struct Locking {
 pub struct Manifest {
     pub jobs: Vec<Job>,
     pub do_sync: bool,
+    pub sync_method: SyncMethod,
+    pub job_failure_policy: JobFailurePolicy,
     pub locking: Option<Locking>,
+    pub change_filter: ChangeFilter,
+}
+
+/// Processes whose writes to traced devices are ignored (or counted).
+///
+/// Writes are attributed to whichever task submits them to the block layer.
+/// Buffered writes are usually submitted later by kernel flusher threads
+/// (kworker), so these filters only reliably apply to direct or synced I/O.
+/// Ignoring writes means the backup of the chunks they touch may not be
+/// consistent, so only ignore processes which write to unimportant data.
+#[derive(Clone,Default,Serialize,Deserialize)]
+pub struct ChangeFilter {
+    pub ignored_pids: Vec<u32>,
+    /// cgroup paths (e.g. /system.slice/foo.service), including children.
+    pub ignored_cgroups: Vec<String>,
+    /// Counted even if otherwise ignored.
+    pub counted_pids: Vec<u32>,
+    /// Counted even if otherwise ignored.
+    pub counted_cgroups: Vec<String>,
 }
 
 #[derive(Clone,Serialize,Deserialize)]
@@ -84,13 +270,109 @@ pub struct RunStatus {
     pub manifest: Manifest,
     pub progress: Vec<JobProgress>,
     pub paused: bool,
+    pub lost_trace_events: u64,
+    /// How changes are being found (never Auto).
+    pub change_detection: ChangeDetection,
+    /// Current per-CPU trace buffer size in KB.
+    pub trace_buffer_size: usize,
+}
+
+/// Summary of a backup which ran to completion (or was cancelled).
+#[derive(Clone,Serialize,Deserialize)]
+pub struct RunReport {
+    /// Number of trace events the kernel dropped during the run. Each loss
+    /// caused every device to be treated as entirely dirty.
+    pub lost_trace_events: u64,
+    /// How changes were found (never Auto). Hashing is weaker than tracing.
+    pub change_detection: ChangeDetection,
+    /// Jobs which failed without stopping the others. Their destinations
+    /// are incomplete.
+    pub failed_jobs: Vec<JobFailure>,
+}
+
+/// A job which was dropped part way through a backup.
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct JobFailure {
+    pub source: PathBuf,
+    pub error: JobError,
+}
+
+/// Why a backup did not complete.
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub enum RunError {
+    /// A job's source could not be backed up.
+    Job {
+        source: PathBuf,
+        error: JobError,
+    },
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RunError::Job {source, error} => write!(f, "Job for '{}' failed: {}", source.display(), error),
+        }
+    }
+}
+
+/// Why a single job could not be backed up.
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub enum JobError {
+    /// The chunk size doesn't suit the source's logical block size.
+    InvalidChunkSize {
+        chunk_size: usize,
+        logical_block_size: u64,
+    },
+    /// Could not work out how to trace the source.
+    Untraceable(String),
+    /// The source got smaller during the backup. Sizes are in bytes.
+    SourceShrunk {
+        old_size: u64,
+        new_size: u64,
+    },
+    /// The source (a partition) now starts somewhere else on its disk.
+    SourceMoved {
+        old_start_sector: u64,
+        new_start_sector: u64,
+    },
+    /// The source grew, but is now backed by different devices, so the
+    /// existing traces no longer cover it.
+    TracedDevicesChanged,
+    /// The source grew beyond what the destination block device can hold.
+    DestinationTooSmall {
+        destination_size: u64,
+        required_size: u64,
+    },
+    /// The source device went away (e.g. it was unplugged).
+    SourceRemoved,
+    /// Reading from the source failed for some other reason.
+    ReadFailed(String),
+    /// The source was being copied without tracing, but then became
+    /// writable (e.g. it was mounted read-write).
+    SourceNoLongerIdle,
+}
+
+impl std::fmt::Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JobError::InvalidChunkSize {chunk_size, logical_block_size} => write!(f, "chunk size {} is not a multiple of the source's logical block size ({})", chunk_size, logical_block_size),
+            JobError::Untraceable(reason) => write!(f, "could not trace source: {}", reason),
+            JobError::SourceShrunk {old_size, new_size} => write!(f, "source shrank from {} to {} bytes", old_size, new_size),
+            JobError::SourceMoved {old_start_sector, new_start_sector} => write!(f, "source moved from sector {} to sector {} of its disk", old_start_sector, new_start_sector),
+            JobError::TracedDevicesChanged => write!(f, "source grew onto devices which aren't being traced"),
+            JobError::DestinationTooSmall {destination_size, required_size} => write!(f, "source grew to {} bytes, but the destination only holds {} bytes", required_size, destination_size),
+            JobError::SourceRemoved => write!(f, "source device was removed"),
+            JobError::ReadFailed(reason) => write!(f, "could not read from source: {}", reason),
+            JobError::SourceNoLongerIdle => write!(f, "source became writable whilst being copied without tracing"),
+        }
+    }
 }
 
 #[derive(Clone,Serialize,Deserialize)]
 pub struct LastResult {
     pub manifest: Manifest,
     pub time: std::time::SystemTime,
-    pub result: Result<(),()>,
+    pub result: Result<RunReport,RunError>,
 }
 
 #[derive(Clone,Serialize,Deserialize)]
@@ -99,6 +381,8 @@ pub struct JobProgress {
     pub chunk_count: usize,
     pub cells: Vec<u8>,
     pub chunks_per_cell: usize,
+    /// Set once the job has failed.
+    pub error: Option<JobError>,
 }
 
 pub struct ManagementTicket {
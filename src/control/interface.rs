@@ -78,6 +78,18 @@ pub struct Config {
     pub tracing_path: PathBuf,
     pub sys_path: PathBuf,
     pub trace_buffer_size: usize,
+    pub trace_buffer_min_size: usize,
+    pub trace_buffer_max_size: usize,
+    pub trace_reader: TraceReader,
+    pub trace_backend: TraceBackend,
+    pub change_detection: ChangeDetection,
+    pub reader_threads: usize,
+    pub reader_pool: ReaderPool,
+    pub max_io_size: usize,
+    pub trace_instance: bool,
+    pub state_path: PathBuf,
+    pub trace_recording: Option<PathBuf>,
+    pub change_replay: Option<ChangeReplay>,
     pub progress_logging: Option<ProgressLogging>,
 }
 
@@ -87,6 +99,18 @@ impl Default for Config {
             tracing_path: Path::new("/sys/kernel/debug/tracing").to_path_buf(),
             sys_path: Path::new("/sys").to_path_buf(),
             trace_buffer_size: 8192,
+            trace_buffer_min_size: 1024,
+            trace_buffer_max_size: 65536,
+            trace_reader: TraceReader::Auto,
+            trace_backend: TraceBackend::Auto,
+            change_detection: ChangeDetection::Tracing,
+            reader_threads: 1,
+            reader_pool: ReaderPool::Shared,
+            max_io_size: 1048576,
+            trace_instance: true,
+            state_path: Path::new("/run/trackup").to_path_buf(),
+            trace_recording: None,
+            change_replay: None,
             progress_logging: None,
         }
     }
@@ -95,16 +119,135 @@ impl Default for Config {
 impl Internalize<super::Config> for Config {
     fn internalize(&self) -> Result<super::Config,String> {
         let progress_logging = self.progress_logging.maybe_internalize()?;
+        if self.trace_buffer_min_size == 0 || self.trace_buffer_min_size > self.trace_buffer_max_size {
+            return Err(String::from("trace_buffer_min_size must be positive and no more than trace_buffer_max_size"));
+        }
+        if self.reader_threads == 0 {
+            return Err(String::from("reader_threads must be positive"));
+        }
+        if self.max_io_size == 0 {
+            return Err(String::from("max_io_size must be positive"));
+        }
 
         Ok(super::Config {
             tracing_path: self.tracing_path.clone(),
             sys_path: self.sys_path.clone(),
             trace_buffer_size: self.trace_buffer_size,
+            trace_buffer_min_size: self.trace_buffer_min_size,
+            trace_buffer_max_size: self.trace_buffer_max_size,
+            trace_reader: self.trace_reader.internalize()?,
+            trace_backend: self.trace_backend.internalize()?,
+            change_detection: self.change_detection.internalize()?,
+            reader_threads: self.reader_threads,
+            reader_pool: self.reader_pool.internalize()?,
+            max_io_size: self.max_io_size,
+            trace_instance: self.trace_instance,
+            state_path: self.state_path.clone(),
+            trace_recording: self.trace_recording.clone(),
+            change_replay: self.change_replay.maybe_internalize()?,
             progress_logging,
         })
     }
 }
 
+#[derive(Clone,Serialize,Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum TraceReader {
+    Auto,
+    Pipe,
+    PerCpuRaw,
+}
+
+impl Internalize<super::TraceReader> for TraceReader {
+    fn internalize(&self) -> Result<super::TraceReader,String> {
+        Ok(match self {
+            TraceReader::Auto      => super::TraceReader::Auto,
+            TraceReader::Pipe      => super::TraceReader::Pipe,
+            TraceReader::PerCpuRaw => super::TraceReader::PerCpuRaw,
+        })
+    }
+}
+
+#[derive(Clone,Serialize,Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum TraceBackend {
+    Auto,
+    BlkTracer,
+    BlockBioQueue,
+}
+
+impl Internalize<super::TraceBackend> for TraceBackend {
+    fn internalize(&self) -> Result<super::TraceBackend,String> {
+        Ok(match self {
+            TraceBackend::Auto          => super::TraceBackend::Auto,
+            TraceBackend::BlkTracer     => super::TraceBackend::BlkTracer,
+            TraceBackend::BlockBioQueue => super::TraceBackend::BlockBioQueue,
+        })
+    }
+}
+
+#[derive(Clone,Serialize,Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum ChangeDetection {
+    Auto,
+    Tracing,
+    Hashing,
+}
+
+impl Internalize<super::ChangeDetection> for ChangeDetection {
+    fn internalize(&self) -> Result<super::ChangeDetection,String> {
+        Ok(match self {
+            ChangeDetection::Auto    => super::ChangeDetection::Auto,
+            ChangeDetection::Tracing => super::ChangeDetection::Tracing,
+            ChangeDetection::Hashing => super::ChangeDetection::Hashing,
+        })
+    }
+}
+
+#[derive(Clone,Serialize,Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum ReaderPool {
+    Shared,
+    PerDevice,
+}
+
+impl Internalize<super::ReaderPool> for ReaderPool {
+    fn internalize(&self) -> Result<super::ReaderPool,String> {
+        Ok(match self {
+            ReaderPool::Shared    => super::ReaderPool::Shared,
+            ReaderPool::PerDevice => super::ReaderPool::PerDevice,
+        })
+    }
+}
+
+#[derive(Clone,Serialize,Deserialize)]
+#[serde(default)]
+pub struct ChangeReplay {
+    pub path: Required<PathBuf>,
+    pub time_scale: f64,
+}
+
+impl Default for ChangeReplay {
+    fn default() -> Self {
+        Self {
+            path: None,
+            time_scale: 1.0,
+        }
+    }
+}
+
+impl Internalize<super::ChangeReplay> for ChangeReplay {
+    fn internalize(&self) -> Result<super::ChangeReplay,String> {
+        if !(self.time_scale.is_finite() && self.time_scale >= 0.0) {
+            return Err(format!("time_scale must be a finite, non-negative float, not {}", self.time_scale));
+        }
+        Ok(super::ChangeReplay {
+            path: self.path.require()?,
+            time_scale: self.time_scale,
+        })
+    }
+}
+
 #[derive(Clone,Serialize,Deserialize)]
 #[serde(rename_all="snake_case")]
 pub enum ProgressStyle {
@@ -166,7 +309,10 @@ impl Internalize<super::ProgressLogging> for ProgressLogging {
 struct Manifest {
     pub jobs: Vec<Job>,
     pub do_sync: bool,
+    pub sync_method: SyncMethod,
+    pub job_failure_policy: JobFailurePolicy,
     pub locking: Option<Locking>,
+    pub change_filter: ChangeFilter,
 }
 
 impl Default for Manifest {
@@ -174,7 +320,10 @@ impl Default for Manifest {
         Self {
             jobs: Vec::new(),
             do_sync: true,
+            sync_method: SyncMethod::Filesystems,
+            job_failure_policy: JobFailurePolicy::AbortAll,
             locking: None,
+            change_filter: ChangeFilter::default(),
         }
     }
 }
@@ -186,7 +335,67 @@ impl Internalize<super::Manifest> for Manifest {
         Ok(super::Manifest {
             jobs,
             do_sync: self.do_sync,
+            sync_method: self.sync_method.internalize()?,
+            job_failure_policy: self.job_failure_policy.internalize()?,
             locking,
+            change_filter: self.change_filter.internalize()?,
+        })
+    }
+}
+
+#[derive(Clone,Serialize,Deserialize)]
+#[serde(rename_all="snake_case")]
+enum SyncMethod {
+    Global,
+    Filesystems,
+}
+
+impl Internalize<super::SyncMethod> for SyncMethod {
+    fn internalize(&self) -> Result<super::SyncMethod,String> {
+        Ok(match self {
+            SyncMethod::Global      => super::SyncMethod::Global,
+            SyncMethod::Filesystems => super::SyncMethod::Filesystems,
+        })
+    }
+}
+
+#[derive(Clone,Serialize,Deserialize)]
+#[serde(rename_all="snake_case")]
+enum JobFailurePolicy {
+    AbortAll,
+    ContinueOthers,
+}
+
+impl Internalize<super::JobFailurePolicy> for JobFailurePolicy {
+    fn internalize(&self) -> Result<super::JobFailurePolicy,String> {
+        Ok(match self {
+            JobFailurePolicy::AbortAll       => super::JobFailurePolicy::AbortAll,
+            JobFailurePolicy::ContinueOthers => super::JobFailurePolicy::ContinueOthers,
+        })
+    }
+}
+
+#[derive(Clone,Default,Serialize,Deserialize)]
+#[serde(default)]
+struct ChangeFilter {
+    pub ignored_pids: Vec<u32>,
+    pub ignored_cgroups: Vec<String>,
+    pub counted_pids: Vec<u32>,
+    pub counted_cgroups: Vec<String>,
+}
+
+impl Internalize<super::ChangeFilter> for ChangeFilter {
+    fn internalize(&self) -> Result<super::ChangeFilter,String> {
+        for cgroup in self.ignored_cgroups.iter().chain(&self.counted_cgroups) {
+            if !cgroup.starts_with('/') {
+                return Err(format!("cgroup '{}' must be an absolute path within the cgroup hierarchy (e.g. /system.slice/foo.service)", cgroup));
+            }
+        }
+        Ok(super::ChangeFilter {
+            ignored_pids: self.ignored_pids.clone(),
+            ignored_cgroups: self.ignored_cgroups.clone(),
+            counted_pids: self.counted_pids.clone(),
+            counted_cgroups: self.counted_cgroups.clone(),
         })
     }
 }
@@ -324,6 +533,11 @@ struct Job {
     pub destination: Required<PathBuf>,
     pub chunk_size: Required<usize>,
     pub reuse_output: bool,
+    pub trace_layer: TraceLayer,
+    pub untraced_when_idle: bool,
+    pub qemu: Option<QemuSource>,
+    pub nbd_server: Option<NbdServer>,
+    pub read_mode: ReadMode,
 }
 
 impl Default for Job {
@@ -333,6 +547,11 @@ impl Default for Job {
             destination: None,
             chunk_size: None,
             reuse_output: false,
+            trace_layer: TraceLayer::Top,
+            untraced_when_idle: true,
+            qemu: None,
+            nbd_server: None,
+            read_mode: ReadMode::Cached,
         }
     }
 }
@@ -340,17 +559,113 @@ impl Default for Job {
 impl Internalize<super::Job> for Job {
     fn internalize(&self) -> Result<super::Job,String> {
         let chunk_size = self.chunk_size.require()?;
-        if chunk_size < 512 {
-            return Err(format!("chunk_size must be at least 512"));
+        // This is only the smallest block size any device can have. Whether
+        // it suits the source's block size is checked once it is opened.
+        let sector_size = crate::device::SECTOR_SIZE as usize;
+        if chunk_size < sector_size {
+            return Err(format!("chunk_size must be at least {}", sector_size));
+        }
+        if chunk_size % sector_size != 0 {
+            return Err(format!("chunk_size must be a multiple of {}", sector_size));
         }
-        if chunk_size % 512 != 0 {
-            return Err(format!("chunk_size must be a multiple of 512"));
+        if self.qemu.is_some() && self.nbd_server.is_some() {
+            return Err(String::from("A job can't take its changes from both qemu and nbd_server"));
         }
         Ok(super::Job {
             source: self.source.require()?,
             destination: self.destination.require()?,
             chunk_size,
             reuse_output: self.reuse_output,
+            trace_layer: self.trace_layer.internalize()?,
+            untraced_when_idle: self.untraced_when_idle,
+            qemu: self.qemu.maybe_internalize()?,
+            nbd_server: self.nbd_server.maybe_internalize()?,
+            read_mode: self.read_mode.internalize()?,
+        })
+    }
+}
+
+#[derive(Clone,Serialize,Deserialize)]
+#[serde(rename_all="snake_case")]
+enum ReadMode {
+    Cached,
+    Direct,
+    DropBehind,
+}
+
+impl Internalize<super::ReadMode> for ReadMode {
+    fn internalize(&self) -> Result<super::ReadMode,String> {
+        Ok(match self {
+            ReadMode::Cached     => super::ReadMode::Cached,
+            ReadMode::Direct     => super::ReadMode::Direct,
+            ReadMode::DropBehind => super::ReadMode::DropBehind,
+        })
+    }
+}
+
+#[derive(Clone,Serialize,Deserialize)]
+#[serde(default)]
+struct QemuSource {
+    pub qmp_socket: Required<PathBuf>,
+    pub node: Required<String>,
+    pub bitmap: String,
+    pub nbd_socket: Required<PathBuf>,
+    /// Time between checks of the bitmap in seconds
+    pub poll_period: f64,
+}
+
+impl Default for QemuSource {
+    fn default() -> Self {
+        Self {
+            qmp_socket: None,
+            node: None,
+            bitmap: String::from("trackup"),
+            nbd_socket: None,
+            poll_period: 1.0,
+        }
+    }
+}
+
+impl Internalize<super::QemuSource> for QemuSource {
+    fn internalize(&self) -> Result<super::QemuSource,String> {
+        Ok(super::QemuSource {
+            qmp_socket: self.qmp_socket.require()?,
+            node: self.node.require()?,
+            bitmap: self.bitmap.clone(),
+            nbd_socket: self.nbd_socket.require()?,
+            poll_period: duration_from_f64(self.poll_period)?,
+        })
+    }
+}
+
+#[derive(Clone,Default,Serialize,Deserialize)]
+#[serde(default)]
+struct NbdServer {
+    pub socket: Required<PathBuf>,
+    pub export_name: String,
+}
+
+impl Internalize<super::NbdServer> for NbdServer {
+    fn internalize(&self) -> Result<super::NbdServer,String> {
+        Ok(super::NbdServer {
+            socket: self.socket.require()?,
+            export_name: self.export_name.clone(),
+        })
+    }
+}
+
+#[derive(Clone,Serialize,Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum TraceLayer {
+    Top,
+    Underlying,
+}
+
+impl Internalize<super::TraceLayer> for TraceLayer {
+    fn internalize(&self) -> Result<super::TraceLayer,String> {
+        Ok(match self {
+            TraceLayer::Top        => super::TraceLayer::Top,
+            TraceLayer::Underlying => super::TraceLayer::Underlying,
         })
     }
 }
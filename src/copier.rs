@@ -5,15 +5,102 @@ use std::sync::{Arc,Barrier};
 use std::time::{Duration,Instant};
 use std::io::Write;
 use std::path::PathBuf;
+use std::cell::{Cell,RefCell};
+use std::collections::{BTreeSet,VecDeque};
 
-use crate::device::{Device,DeviceFile};
+use crate::device::{Device,DeviceFile,Extent,Segment,changed_ranges,is_removal_error};
 use crate::backup_file::BackupFile;
 use crate::chunk_tracker::{ChunkTracker,calculate_display_detail};
-use crate::control::{Request,Response,Status,RunStatus,JobProgress,ManagementInterface,Config,Manifest};
+use crate::chunk_hashes::ChunkHashes;
+use crate::change_logger::{Change,ChangeKind,Control};
+use crate::chunk::{Chunk,ChunkContent};
+use crate::reader::{ReadRequest,ReadResult};
+use crate::control::{ChangeDetection,Request,Response,Status,RunStatus,RunReport,RunError,JobError,JobFailure,JobFailurePolicy,JobProgress,ManagementInterface,Config,Manifest,ReaderPool,SyncMethod};
+use crate::mounts;
 use crate::lock::AutoLocker;
 
 
-pub fn run(config: &Config, manifest: &Manifest, management_interface: &ManagementInterface) -> Result<(),()> {
+// How often sources are checked for being resized or moved.
+const GEOMETRY_CHECK_PERIOD: Duration = Duration::from_secs(1);
+// How many chunks which are ready to be written can pile up, whilst the
+// writer is busy, before no more are read.
+const MAX_PENDING_WRITES: usize = 16;
+
+fn chunk_count_for(bytes: u64, chunk_size: usize) -> usize {
+    let chunk_size = chunk_size as u64;
+    (bytes / chunk_size + (if !bytes.is_multiple_of(chunk_size) {1} else {0})) as usize
+}
+
+fn traced_event_devs(segments: &[Segment]) -> BTreeSet<u32> {
+    segments.iter().map(|segment| {segment.traced.event_dev}).collect()
+}
+
+fn sync_source_filesystems(sources: &[DeviceFile], devices: &[Device], failures: &[Option<JobError>]) -> Result<(),String> {
+    let mut synced = BTreeSet::new();
+    for ((source, device), failure) in sources.iter().zip(devices).zip(failures) {
+        if failure.is_some() {
+            continue;
+        }
+        for mount in mounts::mounts_on(device)? {
+            // The same filesystem may be mounted in several places.
+            if synced.insert(mount.dev) {
+                mount.syncfs()?;
+            }
+        }
+        // Writes made to the device directly are cached separately.
+        source.sync()?;
+    }
+    Ok(())
+}
+
+/// Flush dirty data on its way to the sources, so that it gets traced.
+fn sync_sources(sync_method: SyncMethod, sources: &[DeviceFile], devices: &[Device], failures: &[Option<JobError>]) {
+    if sync_method == SyncMethod::Filesystems {
+        match sync_source_filesystems(sources, devices, failures) {
+            Ok(()) => {
+                return;
+            },
+            Err(e) => {
+                eprintln!("Warning: could not sync just the sources' filesystems ({}). Syncing everything instead.", e);
+            },
+        }
+    }
+    // Everything in libc is unsafe. :P
+    unsafe {libc::sync()};
+}
+
+/// Note that a job has failed. Returns the error to end the whole backup
+/// with, if the policy (or the lack of any other jobs) means it can't go on.
+fn fail_job(manifest: &Manifest, failures: &mut [Option<JobError>], device_number: usize, error: JobError) -> Option<RunError> {
+    let source = manifest.jobs[device_number].source.clone();
+    failures[device_number] = Some(error.clone());
+    if manifest.job_failure_policy == JobFailurePolicy::AbortAll || failures.iter().all(|failure| {failure.is_some()}) {
+        return Some(RunError::Job {source, error});
+    }
+    eprintln!("Job for '{}' failed, continuing with the others: {}", source.display(), error);
+    None
+}
+
+/// Why reading from a source failed.
+fn read_error(e: std::io::Error, device: &Device) -> JobError {
+    if is_removal_error(&e) || !device.is_present() {
+        JobError::SourceRemoved
+    } else {
+        JobError::ReadFailed(e.to_string())
+    }
+}
+
+/// Resolve how changes will be found. Tracing needs tracefs, unless the
+/// changes are being replayed.
+fn choose_change_detection(config: &Config) -> ChangeDetection {
+    match config.change_detection {
+        ChangeDetection::Auto if config.change_replay.is_none() && !config.tracing_path.join("trace_marker").exists() => ChangeDetection::Hashing,
+        ChangeDetection::Auto => ChangeDetection::Tracing,
+        method => method,
+    }
+}
+
+pub fn run(config: &Config, manifest: &Manifest, management_interface: &ManagementInterface) -> Result<RunReport,RunError> {
     let mut sources = Vec::new();
     let mut destinations = Vec::new();
     for job in &manifest.jobs {
@@ -37,40 +124,157 @@ pub fn run(config: &Config, manifest: &Manifest, management_interface: &Manageme
         |destination| {destination.get_path().to_path_buf()}
     ).collect();
 
-    let devices = sources.iter().map(
-        |source| {
-            Device::from_file(config, source).unwrap()
+    let change_detection = choose_change_detection(config);
+    let hashing = change_detection == ChangeDetection::Hashing;
+    // Sources whose changes don't come from tracing here only need their
+    // size, so can be files on any filesystem.
+    let size_only: Vec<bool> = manifest.jobs.iter().map(
+        |job| {hashing || job.qemu.is_some() || job.nbd_server.is_some()}
+    ).collect();
+    let describe_device = |device_number: usize, source: &DeviceFile| -> Result<Device,String> {
+        if size_only[device_number] {
+            Device::from_file_untraced(config, source)
+        } else {
+            Device::from_file(config, source)
+        }
+    };
+    let devices: Vec<Device> = sources.iter().enumerate().map(
+        |(device_number, source)| {
+            describe_device(device_number, source).map_err(
+                |e| {RunError::Job {source: manifest.jobs[device_number].source.clone(), error: JobError::Untraceable(e)}}
+            )
+        }
+    ).collect::<Result<_,_>>()?;
+    // Sources which nothing can write to are copied once, without tracing.
+    let idle: Vec<bool> = manifest.jobs.iter().zip(&devices).zip(sources.iter_mut()).map(
+        |((job, device), source)| {
+            // The host can't see what a virtual machine writes, and served
+            // sources are only idle until a client connects.
+            // Files can't be claimed exclusively (see below), so are never
+            // treated as idle.
+            if !job.untraced_when_idle || job.qemu.is_some() || job.nbd_server.is_some() || device.file.is_some() {
+                return false;
+            }
+            match mounts::is_idle(device) {
+                Ok(true) => {},
+                Ok(false) => {
+                    return false;
+                },
+                Err(e) => {
+                    eprintln!("Warning: could not tell whether '{}' is idle, so it will be traced: {}", job.source.display(), e);
+                    return false;
+                },
+            }
+            // This stops the source being mounted or stacked on whilst we
+            // copy it. Read-only mounts have already claimed it though, so
+            // for those we rely on checking that it stays idle. Otherwise,
+            // a source we can't claim has to be traced.
+            let mounted_read_only = mounts::mounts_on(device).is_ok_and(|mounts| {!mounts.is_empty()});
+            if !mounted_read_only {
+                if let Err(e) = source.claim_exclusive() {
+                    eprintln!("{}, so '{}' will be traced", e, job.source.display());
+                    return false;
+                }
+            }
+            eprintln!("Source '{}' is idle, so it will be copied in a single pass without tracing", job.source.display());
+            true
+        }
+    ).collect();
+    // Done after any exclusive claims, which reopen the sources.
+    for ((source, device), job) in sources.iter_mut().zip(&devices).zip(&manifest.jobs) {
+        if let Err(e) = source.set_read_mode(job.read_mode, device.logical_block_size) {
+            eprintln!("Warning: {}. Dropping what is read from the page cache instead.", e);
         }
+    }
+    if hashing {
+        eprintln!("Warning: changes will be found by re-reading and hashing the sources rather than tracing. The backup is only consistent if locking stops everything writing to them.");
+    }
+    // Sources whose changes have to be found on this machine, rather than
+    // coming from QEMU or our NBD server (or not being looked for at all).
+    let watched: Vec<bool> = idle.iter().zip(&manifest.jobs).map(
+        |(idle, job)| {!idle && job.qemu.is_none() && job.nbd_server.is_none()}
+    ).collect();
+    let traced: Vec<bool> = watched.iter().map(|watched| {*watched && !hashing}).collect();
+    let hashed: Vec<bool> = watched.iter().map(|watched| {*watched && hashing}).collect();
+    // Whether the change logger makes some writes itself, and can hold them
+    // back whilst locked.
+    let serves_writes = manifest.jobs.iter().any(|job| {job.nbd_server.is_some()});
+    let mut trace_segments: Vec<Vec<Segment>> = Vec::with_capacity(devices.len());
+    for ((device, job), traced) in devices.iter().zip(&manifest.jobs).zip(&traced) {
+        let fail = |error| {RunError::Job {source: job.source.clone(), error}};
+        device.check_chunk_size(job.chunk_size).map_err(fail)?;
+        trace_segments.push(
+            if !traced {
+                Vec::new()
+            } else {
+                device.trace_segments(config, job.trace_layer).map_err(fail)?
+            }
+        );
+    }
+    let initial_event_devs: Vec<BTreeSet<u32>> = trace_segments.iter().map(|segments| {traced_event_devs(segments)}).collect();
+    // (start sector, size in bytes) of each source, as last seen.
+    let mut geometries: Vec<(u64, u64)> = devices.iter().zip(&sources).map(
+        |(device, source)| {(device.start_sector, source.get_size())}
+    ).collect();
+    // The extents of file sources, as last seen.
+    let mut extent_maps: Vec<Vec<Extent>> = devices.iter().map(
+        |device| {device.file.as_ref().map(|file| {file.extents.clone()}).unwrap_or_default()}
+    ).collect();
+    let destination_capacities: Vec<Option<u64>> = destinations.iter_mut().map(
+        |destination| {destination.get_capacity()}
     ).collect();
 
     let mut total_chunk_count = 0;
     let mut chunk_trackers = sources.iter().enumerate().map(
         |(i, source)| {
-            let chunk_size: u64 = manifest.jobs[i].chunk_size as u64;
-            let bytes: u64 = source.get_size();
-            let chunk_count: usize = (
-                bytes / chunk_size + (if bytes % chunk_size != 0 {1} else {0})
-            ) as usize;
+            let chunk_count: usize = chunk_count_for(source.get_size(), manifest.jobs[i].chunk_size);
 
             total_chunk_count += chunk_count;
 
             ChunkTracker::new(chunk_count)
         }
     ).collect();
-    let total_chunk_count = total_chunk_count; // drop mut
+
+    let mut chunk_hashes: Vec<ChunkHashes> = sources.iter().zip(&manifest.jobs).map(
+        |(source, job)| {ChunkHashes::new(chunk_count_for(source.get_size(), job.chunk_size))}
+    ).collect();
 
     let (change_queue_produce, change_queue_consume) = channel();
     // The sync channel size could possibly be enlarged.
     let (write_queue_produce, write_queue_consume) = sync_channel(4);
-    let (sync_barrier_produce, sync_barrier_consume) = channel();
+    let (control_produce, control_consume) = channel();
+
+    // Sources are read by pools of reader threads, each with its own queue.
+    let pool_count =
+        match config.reader_pool {
+            ReaderPool::Shared => 1,
+            ReaderPool::PerDevice => number_of_devices,
+        };
+    let pool_of = |device_number: usize| -> usize {
+        if config.reader_pool == ReaderPool::Shared {0} else {device_number}
+    };
+    // Enough reads are queued to keep every thread busy.
+    let pool_capacity = config.reader_threads * 2;
+    // The most chunks of each job to read or write at once.
+    let max_run_lengths: Vec<usize> = manifest.jobs.iter().map(
+        |job| {(config.max_io_size / job.chunk_size).max(1)}
+    ).collect();
+
+    let mut run_error: Option<RunError> = None;
+
+    let mut lost_trace_events: u64 = 0;
+    let mut trace_buffer_size = config.trace_buffer_size;
+    // Jobs which have been dropped, under JobFailurePolicy::ContinueOthers.
+    let mut failures: Vec<Option<JobError>> = vec![None; number_of_devices];
 
     crossbeam::scope(|thread_scope| {
         {
             let devices_ref = &devices;
+            let trace_segments_ref = &trace_segments;
             thread_scope.builder()
                 .name("change-logger".to_string())
                 .spawn(move |_| {
-                    crate::change_logger::run(config, manifest, devices_ref, change_queue_produce, sync_barrier_consume);
+                    crate::change_logger::run(config, manifest, devices_ref, trace_segments_ref, change_queue_produce, control_consume);
                 })
                 .unwrap();
         }
@@ -83,27 +287,190 @@ pub fn run(config: &Config, manifest: &Manifest, management_interface: &Manageme
                 })
                 .unwrap();
         }
+        let (chunk_queue_produce, chunk_queue_consume) = crossbeam::channel::unbounded();
+        let mut read_queue_produces = Vec::with_capacity(pool_count);
+        for pool in 0..pool_count {
+            let (read_queue_produce, read_queue_consume) = crossbeam::channel::unbounded();
+            for thread in 0..config.reader_threads {
+                let readers = sources.iter().enumerate().map(
+                    |(device_number, source)| {
+                        if pool_of(device_number) == pool {
+                            Some(source.reader().expect("Could not open device for reading"))
+                        } else {
+                            None
+                        }
+                    }
+                ).collect();
+                let read_queue_consume = read_queue_consume.clone();
+                let chunk_queue_produce = chunk_queue_produce.clone();
+                thread_scope.builder()
+                    .name(format!("reader-{}-{}", pool, thread))
+                    .spawn(move |_| {
+                        crate::reader::run(readers, read_queue_consume, chunk_queue_produce);
+                    })
+                    .unwrap();
+            }
+            read_queue_produces.push(read_queue_produce);
+        }
         let auto_locker = AutoLocker::new(config, manifest);
 
         // Constrain the lifetime of our producers/consumers so that
         // the child threads can witness a disconnect.
-        let sync_barrier_produce = sync_barrier_produce;
+        let control_produce = control_produce;
         let write_queue_produce = write_queue_produce;
+        let read_queue_produces = read_queue_produces;
         // We don't need to constrain change_queue as it doesn't
         // strictly control any looping behaviour.
 
-        let display_detail: Option<usize> = match &config.progress_logging {
-            Some(progress_logging) => {
-                Some(calculate_display_detail(total_chunk_count, progress_logging.max_diagram_size))
-            },
-            None => None
+        let get_display_detail = |total_chunk_count: usize| -> Option<usize> {
+            config.progress_logging.as_ref().map(
+                |progress_logging| {calculate_display_detail(total_chunk_count, progress_logging.max_diagram_size)}
+            )
+        };
+        let mut display_detail = get_display_detail(total_chunk_count);
+
+        let source_is_idle = |device_number: usize| -> bool {
+            mounts::is_idle(&devices[device_number]).unwrap_or_else(
+                |e| {
+                    eprintln!("Warning: could not check whether '{}' is still idle: {}", manifest.jobs[device_number].source.display(), e);
+                    false
+                }
+            )
         };
 
-        let update_chunk_trackers = |chunk_trackers: &mut Vec<ChunkTracker>| {
+        // Returns the number of chunks added, if the source has grown.
+        let check_device_geometry = |device_number: usize, source: &mut DeviceFile, geometry: &mut (u64, u64), extents: &mut Vec<Extent>, chunk_tracker: &mut ChunkTracker| -> Result<usize,JobError> {
+            let job = &manifest.jobs[device_number];
+            // Checked first, as a removed device can look like it shrank.
+            if !devices[device_number].is_present() {
+                return Err(JobError::SourceRemoved);
+            }
+            // Nothing traces idle sources, so anything written from now on
+            // would go unnoticed.
+            if idle[device_number] && !source_is_idle(device_number) {
+                return Err(JobError::SourceNoLongerIdle);
+            }
+            let (old_start_sector, old_size) = *geometry;
+            let new_size = source.refresh_size();
+            if new_size < old_size {
+                return Err(JobError::SourceShrunk {old_size, new_size});
+            }
+            let device = describe_device(device_number, source).map_err(
+                |e| {
+                    if devices[device_number].is_present() {JobError::Untraceable(e)} else {JobError::SourceRemoved}
+                }
+            )?;
+            if device.start_sector != old_start_sector {
+                return Err(JobError::SourceMoved {old_start_sector, new_start_sector: device.start_sector});
+            }
+            let grown = new_size != old_size && device.get_size() == new_size;
+            // Blocks newly given to a traced file weren't being traced.
+            let new_extents = device.file.as_ref().map(|file| {file.extents.clone()}).unwrap_or_default();
+            let remapped = traced[device_number] && new_extents != *extents;
+            if !grown && !remapped {
+                // Either nothing has changed, or sysfs hasn't caught up
+                // with the resize yet. Look again later.
+                return Ok(0);
+            }
+            if grown {
+                eprintln!("Source '{}' grew from {} to {} bytes", job.source.display(), old_size, new_size);
+                if let Some(destination_size) = destination_capacities[device_number] {
+                    if destination_size < new_size {
+                        return Err(JobError::DestinationTooSmall {destination_size, required_size: new_size});
+                    }
+                }
+            }
+            if traced[device_number] {
+                let segments = device.trace_segments(config, job.trace_layer)?;
+                if traced_event_devs(&segments) != initial_event_devs[device_number] {
+                    return Err(JobError::TracedDevicesChanged);
+                }
+
+                // Writes to the new area before this point don't matter, as
+                // it will all be copied anyway.
+                let barrier = Arc::new(Barrier::new(2));
+                control_produce.send(Control::UpdateDevice {device_number, device, segments, barrier: Arc::clone(&barrier)}).expect("Change logger thread died before it was relieved");
+                barrier.wait();
+            }
+
+            let old_chunk_count = chunk_tracker.get_chunk_count();
+            if grown {
+                chunk_tracker.grow(chunk_count_for(new_size, job.chunk_size));
+                *geometry = (old_start_sector, new_size);
+            }
+            if remapped {
+                // Now that the new extents are traced, copy anything which
+                // may have been written to them before.
+                eprintln!("Extents of '{}' changed, so the affected parts will be copied again", job.source.display());
+                let chunk_size = job.chunk_size as u64;
+                for (start, end) in changed_ranges(extents, &new_extents) {
+                    chunk_tracker.mark_chunks((start / chunk_size) as usize, end.div_ceil(chunk_size) as usize);
+                }
+                *extents = new_extents;
+            }
+            Ok(chunk_tracker.get_chunk_count() - old_chunk_count)
+        };
+        // Jobs which the change logger can't find changes for. They fail
+        // when the geometry is next checked.
+        let reported_failures: RefCell<Vec<(usize, JobError)>> = RefCell::new(Vec::new());
+        // Returns the number of chunks added, if any source has grown.
+        let check_geometry = |sources: &mut Vec<DeviceFile>, geometries: &mut Vec<(u64, u64)>, extent_maps: &mut Vec<Vec<Extent>>, chunk_trackers: &mut Vec<ChunkTracker>, failures: &mut Vec<Option<JobError>>| -> Result<usize,RunError> {
+            for (device_number, error) in reported_failures.take() {
+                if failures[device_number].is_none() {
+                    if let Some(run_error) = fail_job(manifest, failures, device_number, error) {
+                        return Err(run_error);
+                    }
+                }
+            }
+            let mut added_chunks = 0;
+            for device_number in 0..sources.len() {
+                if failures[device_number].is_some() {
+                    continue;
+                }
+                match check_device_geometry(device_number, &mut sources[device_number], &mut geometries[device_number], &mut extent_maps[device_number], &mut chunk_trackers[device_number]) {
+                    Ok(added) => {
+                        added_chunks += added;
+                    },
+                    Err(error) => {
+                        if let Some(run_error) = fail_job(manifest, failures, device_number, error) {
+                            return Err(run_error);
+                        }
+                    },
+                }
+            }
+            Ok(added_chunks)
+        };
+        let mut last_geometry_check = Instant::now();
+
+        // Set if the last sync may not have caught every change made before
+        // it, so the pass can't be consistent.
+        let barrier_failed = Cell::new(false);
+        let update_chunk_trackers = |chunk_trackers: &mut Vec<ChunkTracker>, lost_trace_events: &mut u64, trace_buffer_size: &mut usize| {
             'drain_change_queue: loop {
                 match change_queue_consume.try_recv() {
-                    Ok((device_number, change_index)) => {
-                        chunk_trackers[device_number].mark_chunk(change_index);
+                    Ok(Change::Chunks(range)) => {
+                        chunk_trackers[range.device_number].record_changes(range.chunks, range.kind);
+                    },
+                    Ok(Change::EventsLost(lost)) => {
+                        // We can't know what was written, so assume everything
+                        // that's traced.
+                        *lost_trace_events += lost;
+                        for (chunk_tracker, idle) in chunk_trackers.iter_mut().zip(&idle) {
+                            if *idle {
+                                continue;
+                            }
+                            let chunk_count = chunk_tracker.get_chunk_count();
+                            chunk_tracker.mark_chunks(0, chunk_count);
+                        }
+                    },
+                    Ok(Change::BufferSize(size)) => {
+                        *trace_buffer_size = size;
+                    },
+                    Ok(Change::BarrierFailed) => {
+                        barrier_failed.set(true);
+                    },
+                    Ok(Change::JobFailed(device_number, error)) => {
+                        reported_failures.borrow_mut().push((device_number, error));
                     },
                     Err(TryRecvError::Empty) => {
                         break 'drain_change_queue;
@@ -119,7 +486,7 @@ pub fn run(config: &Config, manifest: &Manifest, management_interface: &Manageme
         let mut paused = false;
 
         let handle_management_tickets =
-            |cancelled: &mut bool, paused: &mut bool, chunk_trackers: &Vec<ChunkTracker>| {
+            |cancelled: &mut bool, paused: &mut bool, chunk_trackers: &Vec<ChunkTracker>, failures: &Vec<Option<JobError>>, lost_trace_events: u64, trace_buffer_size: usize| {
                 while let Some(ticket) = management_interface.get_ticket() {
                     let response =
                         match &ticket.request {
@@ -140,8 +507,8 @@ pub fn run(config: &Config, manifest: &Manifest, management_interface: &Manageme
                             },
                             Request::Query(query) => {
                                 let progress =
-                                    manifest.jobs.iter().zip(chunk_trackers).map(
-                                        |(job, chunk_tracker)| {
+                                    manifest.jobs.iter().zip(chunk_trackers).zip(failures).map(
+                                        |((job, chunk_tracker), failure)| {
                                             let chunk_count = chunk_tracker.get_chunk_count();
                                             let detail = calculate_display_detail(chunk_count, query.max_diagram_size);
                                             JobProgress {
@@ -149,6 +516,7 @@ pub fn run(config: &Config, manifest: &Manifest, management_interface: &Manageme
                                                 chunk_count,
                                                 cells: chunk_tracker.snapshot_level(detail),
                                                 chunks_per_cell: 1 << detail,
+                                                error: failure.clone(),
                                             }
                                         }
                                     ).collect();
@@ -157,9 +525,12 @@ pub fn run(config: &Config, manifest: &Manifest, management_interface: &Manageme
                                     manifest: manifest.clone(),
                                     progress,
                                     paused: *paused,
+                                    lost_trace_events,
+                                    change_detection,
+                                    trace_buffer_size,
                                 };
 
-                                Response::Query(Status::Running(run_status))
+                                Response::Query(Box::new(Status::Running(run_status)))
                             },
                         };
                     ticket.respond(response);
@@ -168,121 +539,331 @@ pub fn run(config: &Config, manifest: &Manifest, management_interface: &Manageme
 
         let mut last_progress_update = Instant::now();
         let mut total_writes = 0;
+        // Chunks which were discarded or zeroed rather than copied.
+        let mut total_discards = 0;
         let mut first_go = true;
+        // Chunks being read, which mustn't be handed out again until they
+        // have been read, or their writes could be reordered. Runs being
+        // read stop short of them.
+        let mut reading: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); number_of_devices];
+        let mut pool_loads: Vec<usize> = vec![0; pool_count];
+        // Chunks waiting for room in the write queue.
+        let mut pending_writes: VecDeque<(usize, Chunk)> = VecDeque::new();
 
         // Only stop when we've done an (optional) sync whilst locked without any events occuring after it.
         let mut consistent = false;
+        let mut writes_held = false;
         'consistency_loop: while !consistent {
             let locked = !first_go && auto_locker.check() == crate::lock::AutoLockerStatus::Locked;
             let should_sync = first_go || (locked && manifest.do_sync);
+            // Writes we serve are held for as long as we're locked, so that
+            // the next pass can catch up with them.
+            if serves_writes && locked != writes_held {
+                if locked {
+                    let barrier = Arc::new(Barrier::new(2));
+                    control_produce.send(Control::HoldWrites(Arc::clone(&barrier))).expect("Change logger thread died before it was relieved");
+                    barrier.wait();
+                    update_chunk_trackers(&mut chunk_trackers, &mut lost_trace_events, &mut trace_buffer_size);
+                } else {
+                    control_produce.send(Control::ReleaseWrites).expect("Change logger thread died before it was relieved");
+                }
+                writes_held = locked;
+            }
+            barrier_failed.set(false);
             if should_sync {
-                // Everything in libc is unsafe. :P
-                unsafe {libc::sync()};
+                sync_sources(manifest.sync_method, &sources, &devices, &failures);
 
                 // Make sure all the sync write events are captured.
                 let barrier = Arc::new(Barrier::new(2));
-                sync_barrier_produce.send(Arc::clone(&barrier)).expect("Change logger thread died before it was relieved");
+                control_produce.send(Control::Sync(Arc::clone(&barrier))).expect("Change logger thread died before it was relieved");
                 barrier.wait();
 
-                update_chunk_trackers(&mut chunk_trackers);
+                update_chunk_trackers(&mut chunk_trackers, &mut lost_trace_events, &mut trace_buffer_size);
+            }
+            // Any growth leaves unprocessed chunks, so prevents this pass
+            // from being considered consistent.
+            match check_geometry(&mut sources, &mut geometries, &mut extent_maps, &mut chunk_trackers, &mut failures) {
+                Ok(added_chunks) => {
+                    if added_chunks > 0 {
+                        total_chunk_count += added_chunks;
+                        display_detail = get_display_detail(total_chunk_count);
+                    }
+                },
+                Err(e) => {
+                    run_error = Some(e);
+                    break 'consistency_loop;
+                },
             }
-            consistent = locked;
+            if hashing {
+                // Nothing reports changes, so look for them in everything
+                // which has been copied.
+                for device_number in 0..number_of_devices {
+                    if !hashed[device_number] || failures[device_number].is_some() {
+                        continue;
+                    }
+                    let chunk_size = manifest.jobs[device_number].chunk_size;
+                    for index in 0..chunk_trackers[device_number].get_chunk_count() {
+                        handle_management_tickets(&mut cancelled, &mut paused, &chunk_trackers, &failures, lost_trace_events, trace_buffer_size);
+                        while paused && !cancelled {
+                            std::thread::sleep(Duration::from_millis(10));
+                            handle_management_tickets(&mut cancelled, &mut paused, &chunk_trackers, &failures, lost_trace_events, trace_buffer_size);
+                        }
+                        if cancelled {
+                            break 'consistency_loop;
+                        }
+                        if chunk_trackers[device_number].is_pending(index) {
+                            continue;
+                        }
+                        match sources[device_number].get_chunk(index as u64 * chunk_size as u64, chunk_size) {
+                            Ok(Chunk {content: ChunkContent::Data(data), ..}) => {
+                                if chunk_hashes[device_number].changed(index, &data) {
+                                    chunk_trackers[device_number].mark_chunk(index);
+                                }
+                            },
+                            Ok(_) => {},
+                            Err(e) => {
+                                if let Some(e) = fail_job(manifest, &mut failures, device_number, read_error(e, &devices[device_number])) {
+                                    run_error = Some(e);
+                                    break 'consistency_loop;
+                                }
+                                break;
+                            },
+                        }
+                    }
+                }
+            }
+            consistent = locked && !barrier_failed.get();
 
             let mut still_copying = true;
             while still_copying {
                 still_copying = false;
-                for device_number in 0..number_of_devices {
-                    let mut find_index: Option<usize> = None;
-                    'device_copy_loop: loop {
-                        if paused {
-                            std::thread::sleep(Duration::from_millis(10));
-                            update_chunk_trackers(&mut chunk_trackers);
-                        } else {
-                            // Find next dirty index
-                            match find_index {
-                                None => {
-                                    find_index = chunk_trackers[device_number].find_next(0);
-                                },
-                                Some(index) => {
-                                    find_index = chunk_trackers[device_number].find_next(index);
-                                },
+                // Where each source's search for pending chunks has got to in
+                // this sweep, or None once it has reached the end.
+                let mut cursors: Vec<Option<usize>> = vec![Some(0); number_of_devices];
+                'copy_loop: loop {
+                    if paused {
+                        std::thread::sleep(Duration::from_millis(10));
+                        update_chunk_trackers(&mut chunk_trackers, &mut lost_trace_events, &mut trace_buffer_size);
+                    } else {
+                        let mut progressed = false;
+                        // Hand out pending chunks for as long as there's room.
+                        for device_number in 0..number_of_devices {
+                            if failures[device_number].is_some() {
+                                cursors[device_number] = None;
+                                continue;
                             }
+                            let pool = pool_of(device_number);
+                            while let Some(start) = cursors[device_number] {
+                                if pool_loads[pool] >= pool_capacity || pending_writes.len() >= MAX_PENDING_WRITES {
+                                    break;
+                                }
+                                let mut find_index = chunk_trackers[device_number].find_next(start);
+                                while let Some(index) = find_index {
+                                    if !reading[device_number].contains(&index) {
+                                        break;
+                                    }
+                                    find_index = chunk_trackers[device_number].find_next(index + 1);
+                                }
+                                let index =
+                                    match find_index {
+                                        None => {
+                                            cursors[device_number] = None;
+                                            break;
+                                        },
+                                        Some(index) => index,
+                                    };
+                                let mut end = chunk_trackers[device_number].find_run_end(index, index + max_run_lengths[device_number]);
+                                if let Some(next_reading) = reading[device_number].range(index..end).next() {
+                                    end = *next_reading;
+                                }
+                                let chunk_count = end - index;
+                                cursors[device_number] = Some(end);
+                                progressed = true;
+                                still_copying = true;
+                                consistent = false;
 
-                            // Act on index (or end if none)
-                            match find_index {
-                                None => {
-                                    break 'device_copy_loop;
-                                },
-                                Some(index) => {
-                                    still_copying = true;
-                                    consistent = false;
-
-                                    // Clear here, so it has a chance to get re-marked as
-                                    // dirty in case it's written to whilst we read it.
-                                    chunk_trackers[device_number].clear_chunk(index);
-
-                                    let chunk = sources[device_number].get_chunk(index as u64 * manifest.jobs[device_number].chunk_size as u64, manifest.jobs[device_number].chunk_size);
-                                    let mut message = Some( (device_number, chunk) );
-
-                                    'write_try_loop: loop {
-                                        update_chunk_trackers(&mut chunk_trackers);
-                                        handle_management_tickets(&mut cancelled, &mut paused, &chunk_trackers);
-                                        if cancelled {
-                                            break 'consistency_loop;
-                                        }
+                                let change = chunk_trackers[device_number].get_chunk_change(index);
+                                // Clear here, so they have a chance to get re-marked as
+                                // dirty in case they're written to whilst we read them.
+                                for run_index in index..end {
+                                    chunk_trackers[device_number].clear_chunk(run_index);
+                                }
 
-                                        match write_queue_produce.try_send( message.take().unwrap() ) {
-                                            Ok(()) => {
-                                                break 'write_try_loop;
-                                            },
-                                            Err(TrySendError::Full(bounced)) => {
-                                                // Put back the message.
-                                                message.replace(bounced);
-                                                // Might as well not hammer the CPU if
-                                                // we're waiting on something.
-                                                std::thread::sleep(Duration::from_millis(1));
-                                            },
-                                            Err(TrySendError::Disconnected(_)) => {
-                                                panic!("Writer thread died before it was relieved");
-                                            },
+                                let chunk_size = manifest.jobs[device_number].chunk_size;
+                                let offset = index as u64 * chunk_size as u64;
+                                let length = sources[device_number].get_chunk_length(offset, chunk_count * chunk_size);
+                                match change {
+                                    ChangeKind::Write => {
+                                        reading[device_number].extend(index..end);
+                                        pool_loads[pool] += 1;
+                                        read_queue_produces[pool].send(ReadRequest {device_number, index, chunk_count, offset, length}).expect("Reader threads died before they were relieved");
+                                    },
+                                    ChangeKind::Discard => {
+                                        total_writes += chunk_count;
+                                        total_discards += chunk_count;
+                                        pending_writes.push_back((device_number, Chunk {offset, content: ChunkContent::Discarded(length)}));
+                                    },
+                                    ChangeKind::WriteZeroes => {
+                                        total_writes += chunk_count;
+                                        total_discards += chunk_count;
+                                        pending_writes.push_back((device_number, Chunk {offset, content: ChunkContent::Zeroed(length)}));
+                                    },
+                                }
+                            }
+                        }
+
+                        // Collect the chunks which have been read, waiting
+                        // for one if there's nothing else to do.
+                        let mut results: Vec<ReadResult> = chunk_queue_consume.try_iter().collect();
+                        if results.is_empty() && !progressed && pending_writes.is_empty() && pool_loads.iter().any(|load| {*load > 0}) {
+                            if let Ok(result) = chunk_queue_consume.recv_timeout(Duration::from_millis(10)) {
+                                results.push(result);
+                            }
+                        }
+                        for ReadResult {device_number, index, chunk_count, chunk} in results {
+                            progressed = true;
+                            for run_index in index..(index + chunk_count) {
+                                reading[device_number].remove(&run_index);
+                            }
+                            pool_loads[pool_of(device_number)] -= 1;
+                            if failures[device_number].is_some() {
+                                continue;
+                            }
+                            match chunk {
+                                Ok(chunk) => {
+                                    if hashed[device_number] {
+                                        if let ChunkContent::Data(data) = &chunk.content {
+                                            for (run_index, chunk_data) in data.chunks(manifest.jobs[device_number].chunk_size).enumerate() {
+                                                chunk_hashes[device_number].record(index + run_index, chunk_data);
+                                            }
                                         }
                                     }
-                                    total_writes += 1;
+                                    total_writes += chunk_count;
+                                    pending_writes.push_back((device_number, chunk));
+                                },
+                                Err(e) => {
+                                    if let Some(e) = fail_job(manifest, &mut failures, device_number, read_error(e, &devices[device_number])) {
+                                        run_error = Some(e);
+                                        break 'consistency_loop;
+                                    }
                                 },
                             }
-                        } // <- if paused {...} else >>>{...}<<<
+                        }
 
-                        if let Some(progress_logging) = &config.progress_logging {
-                            if last_progress_update.elapsed() >= progress_logging.update_period {
-                                if progress_logging.exclusive {
-                                    std::io::stdout().write_all(b"\x1b[2J").unwrap();
+                        // Pass chunks on to the writer, as far as it has room.
+                        while let Some(message) = pending_writes.pop_front() {
+                            match write_queue_produce.try_send(message) {
+                                Ok(()) => {
+                                    progressed = true;
+                                },
+                                Err(TrySendError::Full(bounced)) => {
+                                    // Put back the message.
+                                    pending_writes.push_front(bounced);
+                                    break;
+                                },
+                                Err(TrySendError::Disconnected(_)) => {
+                                    panic!("Writer thread died before it was relieved");
+                                },
+                            }
+                        }
+                        update_chunk_trackers(&mut chunk_trackers, &mut lost_trace_events, &mut trace_buffer_size);
+
+                        if cursors.iter().all(Option::is_none) && pool_loads.iter().all(|load| {*load == 0}) && pending_writes.is_empty() {
+                            break 'copy_loop;
+                        }
+                        if !progressed {
+                            // Might as well not hammer the CPU if
+                            // we're waiting on something.
+                            std::thread::sleep(Duration::from_millis(1));
+                        }
+                    } // <- if paused {...} else >>>{...}<<<
+
+                    if let Some(progress_logging) = &config.progress_logging {
+                        if last_progress_update.elapsed() >= progress_logging.update_period {
+                            if progress_logging.exclusive {
+                                std::io::stdout().write_all(b"\x1b[2J").unwrap();
+                            }
+                            for i in 0..number_of_devices {
+                                if let Some(error) = &failures[i] {
+                                    println!("Copying '{}' to '{}' failed: {}", source_paths[i].display(), destination_paths[i].display(), error);
+                                    continue;
                                 }
-                                for i in 0..number_of_devices {
-                                    println!("Copying '{}' to '{}'\nProcessing as {} chunks of size {}\n{}", source_paths[i].display(), destination_paths[i].display(), chunk_trackers[i].get_chunk_count(), manifest.jobs[i].chunk_size, chunk_trackers[i].summary_report(&progress_logging, display_detail.unwrap()));
+                                if idle[i] {
+                                    println!("Source '{}' is idle and not being traced", source_paths[i].display());
                                 }
-                                println!(
-                                    "Done {}{}   Dirty {}{}   Unprocessed {}{}   UnprocessedDirty {}{}",
-                                    progress_logging.diagram_cells[0], progress_logging.diagram_cells_reset,
-                                    progress_logging.diagram_cells[1], progress_logging.diagram_cells_reset,
-                                    progress_logging.diagram_cells[2], progress_logging.diagram_cells_reset,
-                                    progress_logging.diagram_cells[3], progress_logging.diagram_cells_reset
-                                );
-                                println!("Chunk writes: {}", total_writes);
-                                last_progress_update = Instant::now();
+                                println!("Copying '{}' to '{}'\nProcessing as {} chunks of size {}\n{}", source_paths[i].display(), destination_paths[i].display(), chunk_trackers[i].get_chunk_count(), manifest.jobs[i].chunk_size, chunk_trackers[i].summary_report(&progress_logging, display_detail.unwrap()));
                             }
+                            println!(
+                                "Done {}{}   Dirty {}{}   Unprocessed {}{}   UnprocessedDirty {}{}",
+                                progress_logging.diagram_cells[0], progress_logging.diagram_cells_reset,
+                                progress_logging.diagram_cells[1], progress_logging.diagram_cells_reset,
+                                progress_logging.diagram_cells[2], progress_logging.diagram_cells_reset,
+                                progress_logging.diagram_cells[3], progress_logging.diagram_cells_reset
+                            );
+                            println!("Chunk writes: {} (of which discarded or zeroed: {})", total_writes, total_discards);
+                            if lost_trace_events > 0 {
+                                println!("Lost trace events: {}", lost_trace_events);
+                            }
+                            println!("Trace buffer size: {} KB per CPU", trace_buffer_size);
+                            last_progress_update = Instant::now();
                         }
-                        handle_management_tickets(&mut cancelled, &mut paused, &chunk_trackers);
-                        if cancelled {
-                            break 'consistency_loop;
+                    }
+                    handle_management_tickets(&mut cancelled, &mut paused, &chunk_trackers, &failures, lost_trace_events, trace_buffer_size);
+                    if cancelled {
+                        break 'consistency_loop;
+                    }
+                    if last_geometry_check.elapsed() >= GEOMETRY_CHECK_PERIOD {
+                        match check_geometry(&mut sources, &mut geometries, &mut extent_maps, &mut chunk_trackers, &mut failures) {
+                            Ok(added_chunks) => {
+                                if added_chunks > 0 {
+                                    total_chunk_count += added_chunks;
+                                    display_detail = get_display_detail(total_chunk_count);
+                                }
+                            },
+                            Err(e) => {
+                                run_error = Some(e);
+                                break 'consistency_loop;
+                            },
                         }
-                    } // <- 'device_copy_loop loop
-                } // <- for device_number in 0..number_of_devices
+                        last_geometry_check = Instant::now();
+                    }
+                } // <- 'copy_loop loop
             } // <- while still_copying
             first_go = false;
         } // <- while !consistent
-        if !cancelled {
+        if writes_held {
+            // Otherwise, they'd still be held when the server closes.
+            control_produce.send(Control::ReleaseWrites).expect("Change logger thread died before it was relieved");
+        }
+        if run_error.is_none() && !cancelled {
+            // Make sure nothing could have written to the idle sources since
+            // they were last checked.
+            for device_number in 0..number_of_devices {
+                if idle[device_number] && failures[device_number].is_none() && !source_is_idle(device_number) {
+                    if let Some(e) = fail_job(manifest, &mut failures, device_number, JobError::SourceNoLongerIdle) {
+                        run_error = Some(e);
+                        break;
+                    }
+                }
+            }
+        }
+        if let Some(e) = &run_error {
+            println!("Copying failed! {}", e);
+        } else if !cancelled {
             println!("Copying complete!");
             println!("Chunk writes: {} (efficiency is {})", total_writes, total_chunk_count as f64 / total_writes as f64);
+            println!("Chunks discarded or zeroed instead of copied: {}", total_discards);
+            if lost_trace_events > 0 {
+                println!("Lost trace events: {} (all devices were re-copied as a precaution)", lost_trace_events);
+            }
+            if hashing {
+                println!("Changes were found by hashing rather than tracing, so the backup is only consistent if locking stopped all writes");
+            }
+            for (job, failure) in manifest.jobs.iter().zip(&failures) {
+                if let Some(error) = failure {
+                    println!("Job for '{}' failed part way through: {}", job.source.display(), error);
+                }
+            }
         } else {
             println!("Copying aborted!");
         }
@@ -290,5 +871,78 @@ pub fn run(config: &Config, manifest: &Manifest, management_interface: &Manageme
 
     println!("All copier threads finished");
 
-    Ok(())
+    if let Some(e) = run_error {
+        return Err(e);
+    }
+
+    let failed_jobs = manifest.jobs.iter().zip(failures).filter_map(
+        |(job, failure)| {failure.map(|error| {JobFailure {source: job.source.clone(), error}})}
+    ).collect();
+
+    Ok(RunReport {
+        lost_trace_events,
+        change_detection,
+        failed_jobs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::test_util::{test_device,test_job,test_manifest};
+
+    fn manifest(job_count: usize, job_failure_policy: JobFailurePolicy) -> Manifest {
+        let jobs = (0..job_count).map(
+            |i| {test_job(Path::new(&format!("/dev/source{}", i)), Path::new(&format!("/backup/{}.img", i)))}
+        ).collect();
+        Manifest {job_failure_policy, ..test_manifest(jobs)}
+    }
+
+    #[test]
+    fn test_fail_job_continue_others() {
+        let manifest = manifest(3, JobFailurePolicy::ContinueOthers);
+        let mut failures = vec![None; 3];
+        assert!(fail_job(&manifest, &mut failures, 1, JobError::SourceRemoved).is_none());
+        assert!(fail_job(&manifest, &mut failures, 0, JobError::SourceNoLongerIdle).is_none());
+        assert_eq!(failures, vec![Some(JobError::SourceNoLongerIdle), Some(JobError::SourceRemoved), None]);
+        // Once every job has failed, the whole backup has.
+        match fail_job(&manifest, &mut failures, 2, JobError::ReadFailed(String::from("EIO"))) {
+            Some(RunError::Job {source, error}) => {
+                assert_eq!(source, PathBuf::from("/dev/source2"));
+                assert_eq!(error, JobError::ReadFailed(String::from("EIO")));
+            },
+            None => panic!("Backup carried on without any jobs"),
+        }
+    }
+
+    #[test]
+    fn test_fail_job_abort_all() {
+        let manifest = manifest(2, JobFailurePolicy::AbortAll);
+        let mut failures = vec![None; 2];
+        match fail_job(&manifest, &mut failures, 1, JobError::SourceRemoved) {
+            Some(RunError::Job {source, error}) => {
+                assert_eq!(source, PathBuf::from("/dev/source1"));
+                assert_eq!(error, JobError::SourceRemoved);
+            },
+            None => panic!("Backup carried on despite AbortAll"),
+        }
+        assert_eq!(failures, vec![None, Some(JobError::SourceRemoved)]);
+    }
+
+    #[test]
+    fn test_read_error() {
+        // Present, as far as is_present() can tell.
+        let device = Device {sys_dev_path: std::env::temp_dir(), ..test_device(8, 0, 100)};
+        let os_error = |errno| {std::io::Error::from_raw_os_error(errno)};
+        assert!(is_removal_error(&os_error(libc::ENODEV)));
+        assert!(is_removal_error(&os_error(libc::ENXIO)));
+        assert!(!is_removal_error(&os_error(libc::EIO)));
+        assert_eq!(read_error(os_error(libc::ENODEV), &device), JobError::SourceRemoved);
+        assert_eq!(read_error(os_error(libc::ENXIO), &device), JobError::SourceRemoved);
+        assert!(matches!(read_error(os_error(libc::EIO), &device), JobError::ReadFailed(_)));
+        // Any error from a device which has gone means it was removed.
+        let removed = Device {sys_dev_path: PathBuf::from("/nonexistent/trackup/device"), ..device};
+        assert_eq!(read_error(os_error(libc::EIO), &removed), JobError::SourceRemoved);
+    }
 }
@@ -1,12 +1,28 @@
 use std::path::{Path,PathBuf};
-use std::fs::File;
-use std::io::{Read,Seek,SeekFrom};
+use std::fs::{File,OpenOptions};
+use std::io::{Seek,SeekFrom};
 use std::ffi::CString;
-use libc::{c_uint,dev_t};
+use std::os::unix::fs::{MetadataExt,OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use libc::{c_uint,c_ulong,dev_t};
 use crate::chunk::Chunk;
-use crate::control::Config;
+use crate::control::{Config,JobError,ReadMode,TraceLayer};
 use crate::quick_io::{slurp_file_at_path,slurp_and_parse_file_at_path};
 
+mod stack;
+mod file;
+mod read;
+pub use stack::Segment;
+pub use file::{Extent,SourceFile,changed_ranges};
+
+/// The unit the kernel uses for sector numbers (including in trace events),
+/// regardless of the logical block size of the device.
+pub const SECTOR_SIZE: u64 = 512;
+
+// _IOR(0x12, 114, size_t) from linux/fs.h (not exported by the libc crate).
+const BLKGETSIZE64: c_ulong = 0x80081272;
+
+#[derive(Clone)]
 pub struct Device {
     pub dev: dev_t,
     pub event_dev: u32,
@@ -16,7 +32,15 @@ pub struct Device {
     pub sector_count: u64,
     pub start_sector: u64,
     pub end_sector: u64,
+    /// The smallest unit the device can be addressed in (e.g. 4096 for 4Kn
+    /// disks). I/O, including O_DIRECT, must be aligned to this.
+    pub logical_block_size: u64,
+    /// The smallest unit the device can write without a read-modify-write.
+    pub physical_block_size: u64,
     pub parent: Option<Box<Device>>, // If our device is a partition, this will represent the whole-disk.
+    /// Set if this is really a regular file, on the filesystem which lives on
+    /// the parent device. Sizes and sectors are then those of the file.
+    pub file: Option<SourceFile>,
 }
 
 pub struct DeviceFile {
@@ -24,11 +48,83 @@ pub struct DeviceFile {
     size: u64,
     file: File,
     // fd: RawFd,
+    read_mode: ReadMode,
+    /// The logical block size, which O_DIRECT reads are aligned to.
+    block_size: u64,
+}
+
+/// Reads chunks from a source on another thread, through its own handle.
+pub struct SourceReader {
+    file: File,
+    read_mode: ReadMode,
+    block_size: u64,
+}
+
+impl SourceReader {
+    /// Read exactly the given length, which must already be capped to the
+    /// size of the source.
+    pub fn get_chunk(&self, offset: u64, length: usize) -> std::io::Result<Chunk> {
+        read::read_chunk(&self.file, offset, length, self.read_mode, self.block_size)
+    }
 }
 
 impl Device {
     pub fn from_file(config: &Config, device_file: &DeviceFile) -> Result<Self, String> {
-        let cpath = CString::new(device_file.path.to_str().unwrap()).unwrap();
+        // Files are described through the handle we read from, so that the
+        // extents are still those of the file being read if another file
+        // has since been put in its place.
+        if device_file.file.metadata().is_ok_and(|metadata| {metadata.file_type().is_file()}) {
+            return Self::from_regular_file(config, &device_file.path, &device_file.file);
+        }
+        Self::from_path(config, &device_file.path)
+    }
+
+    /// Like from_file, for sources whose changes don't come from tracing,
+    /// so only their size matters. Regular files aren't mapped onto the
+    /// device their filesystem lives on, so can be on any filesystem (e.g.
+    /// btrfs or tmpfs), and have no extents.
+    pub fn from_file_untraced(config: &Config, device_file: &DeviceFile) -> Result<Self, String> {
+        let path = &device_file.path;
+        let metadata = match device_file.file.metadata() {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Could not stat '{}': {}", path.display(), e));
+            },
+        };
+        if !metadata.file_type().is_file() {
+            return Self::from_path(config, path);
+        }
+        let (major, minor) = (libc::major(metadata.dev() as dev_t), libc::minor(metadata.dev() as dev_t));
+        // Only used for the block sizes, so it doesn't matter if there isn't
+        // one.
+        let backing = if major == 0 {None} else {Self::from_major_minor(config, major, minor).ok()};
+        let (logical_block_size, physical_block_size) = backing.as_ref().map(
+            |backing| {(backing.logical_block_size, backing.physical_block_size)}
+        ).unwrap_or((SECTOR_SIZE, SECTOR_SIZE));
+        let size = metadata.len();
+        let sector_count = size.div_ceil(SECTOR_SIZE);
+        Ok(Self {
+            dev: metadata.dev() as dev_t,
+            event_dev: (major << 20) | minor,
+            major,
+            minor,
+            sys_dev_path: PathBuf::new(),
+            sector_count,
+            start_sector: 0,
+            end_sector: sector_count,
+            logical_block_size,
+            physical_block_size,
+            parent: None,
+            file: Some(SourceFile {
+                ino: metadata.ino(),
+                size,
+                extents: Vec::new(),
+            }),
+        })
+    }
+
+    pub fn from_path(config: &Config, path: &Path) -> Result<Self, String> {
+        let cpath = CString::new(path.to_str().unwrap()).unwrap();
         let stat_result = unsafe {
             let mut stat_result: libc::stat = ::std::mem::uninitialized();
             if libc::stat(cpath.as_ptr(), &mut stat_result as *mut libc::stat) < 0 {
@@ -37,6 +133,16 @@ impl Device {
             stat_result
         };
 
+        if stat_result.st_mode & libc::S_IFMT == libc::S_IFREG {
+            let file = match File::open(path) {
+                Ok(x) => x,
+                Err(e) => {
+                    return Err(format!("Could not open '{}': {}", path.display(), e));
+                },
+            };
+            return Self::from_regular_file(config, path, &file);
+        }
+
         let major = unsafe{libc::major(stat_result.st_rdev)};
         let minor = unsafe{libc::minor(stat_result.st_rdev)};
 
@@ -69,12 +175,13 @@ impl Device {
         let dev: dev_t = unsafe {libc::makedev(major, minor)};
         let event_dev: u32 = (major << 20) | minor;
         let sys_dev_path = config.sys_path.join("dev/block").join(&format!("{}:{}", major, minor));
-        let sector_count = slurp_and_parse_file_at_path(&sys_dev_path.join("size")).unwrap();
+        // The device may have just been removed.
+        let sector_count = slurp_and_parse_file_at_path(&sys_dev_path.join("size"))?;
         let is_partition = sys_dev_path.join("partition").exists();
         let start_sector: u64 =
             if is_partition {
                 // This device doesn't cover the entire lba space (i.e. a partition)
-                slurp_and_parse_file_at_path(&sys_dev_path.join("start")).unwrap()
+                slurp_and_parse_file_at_path(&sys_dev_path.join("start"))?
             } else {
                 // In theory, this is a device which covers an entire lba space
                 0
@@ -94,6 +201,19 @@ impl Device {
             } else {
                 None
             };
+        // Partitions don't have their own queue.
+        let (logical_block_size, physical_block_size) =
+            match &parent {
+                Some(parent) => {
+                    (parent.logical_block_size, parent.physical_block_size)
+                },
+                None => {
+                    (
+                        slurp_and_parse_file_at_path(&sys_dev_path.join("queue/logical_block_size")).unwrap_or(SECTOR_SIZE),
+                        slurp_and_parse_file_at_path(&sys_dev_path.join("queue/physical_block_size")).unwrap_or(SECTOR_SIZE),
+                    )
+                },
+            };
 
         Ok(Self{
             dev,
@@ -104,10 +224,112 @@ impl Device {
             sector_count,
             start_sector,
             end_sector,
+            logical_block_size,
+            physical_block_size,
             parent,
+            file: None,
+        })
+    }
+
+    /// Treat a regular file as a device, by mapping its extents onto the
+    /// device its filesystem lives on.
+    fn from_regular_file(config: &Config, path: &Path, file: &File) -> Result<Self,String> {
+        let metadata = match file.metadata() {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Could not stat '{}': {}", path.display(), e));
+            },
+        };
+        let (major, minor) = (libc::major(metadata.dev() as dev_t), libc::minor(metadata.dev() as dev_t));
+        if major == 0 {
+            // e.g. btrfs, whose extents aren't on any one device.
+            return Err(format!("'{}' is on a filesystem which doesn't live on a single block device", path.display()));
+        }
+        let backing = Self::from_major_minor(config, major, minor)?;
+        let extents = file::read_extents(file)?;
+        let size = metadata.len();
+        let sector_count = size.div_ceil(SECTOR_SIZE);
+        Ok(Self {
+            dev: backing.dev,
+            event_dev: backing.event_dev,
+            major,
+            minor,
+            sys_dev_path: backing.sys_dev_path.clone(),
+            sector_count,
+            start_sector: 0,
+            end_sector: sector_count,
+            logical_block_size: backing.logical_block_size,
+            physical_block_size: backing.physical_block_size,
+            parent: Some(Box::new(backing)),
+            file: Some(SourceFile {
+                ino: metadata.ino(),
+                size,
+                extents,
+            }),
         })
     }
 
+    /// The size in bytes of the device (or file).
+    pub fn get_size(&self) -> u64 {
+        match &self.file {
+            Some(file) => file.size,
+            None => self.sector_count * SECTOR_SIZE,
+        }
+    }
+
+    /// Check that chunks of the given size can be read and written without
+    /// straddling logical blocks. Chunks which don't fill physical blocks
+    /// are allowed, but slower.
+    pub fn check_chunk_size(&self, chunk_size: usize) -> Result<(),JobError> {
+        if !(chunk_size as u64).is_multiple_of(self.logical_block_size) {
+            return Err(JobError::InvalidChunkSize {
+                chunk_size,
+                logical_block_size: self.logical_block_size,
+            });
+        }
+        if !(chunk_size as u64).is_multiple_of(self.physical_block_size) {
+            eprintln!("Warning: chunk size {} is not a multiple of the physical block size ({}) of device {}:{}", chunk_size, self.physical_block_size, self.major, self.minor);
+        }
+        Ok(())
+    }
+
+    /// Work out which whole disk devices to trace to see writes to this
+    /// device, and how to map them back. Fails with JobError::Untraceable
+    /// if the device is stacked in a way we can't map.
+    pub fn trace_segments(&self, config: &Config, layer: TraceLayer) -> Result<Vec<Segment>,JobError> {
+        if let Some(file) = &self.file {
+            let backing = match &self.parent {
+                Some(x) => x,
+                None => {
+                    return Err(JobError::Untraceable(String::from("file sources can only be traced on a filesystem which lives on a single block device")));
+                },
+            };
+            let transform = file::extents_transform(&file.extents);
+            let mut segments = backing.trace_segments(config, layer)?;
+            for segment in &mut segments {
+                segment.transforms.push(transform.clone());
+            }
+            return Ok(segments);
+        }
+        // Writes through holders are remapped onto this device, so are seen
+        // either way.
+        match layer {
+            TraceLayer::Top => Ok(stack::top_segments(self)),
+            TraceLayer::Underlying => stack::underlying_segments(config, self).map_err(JobError::Untraceable),
+        }
+    }
+
+    /// Whether the device still exists. Its sysfs directory goes away as
+    /// soon as it is removed, even if something still holds it open.
+    pub fn is_present(&self) -> bool {
+        match (&self.file, &self.parent) {
+            // Not mapped onto a device. Its filesystem can't go away whilst
+            // we hold it open.
+            (Some(_), None) => true,
+            _ => self.sys_dev_path.exists(),
+        }
+    }
+
     /// Return the ultimate ancestor (i.e. the device representing the whole disk)
     pub fn get_base_device<'s>(&'s self) -> &'s Device {
         // Will there ever be more than one level?
@@ -122,15 +344,22 @@ impl Device {
     }
 }
 
+impl Device {
+    // Files on the same device are told apart by their inode.
+    fn identity(&self) -> (dev_t, Option<u64>) {
+        (self.dev, self.file.as_ref().map(|file| {file.ino}))
+    }
+}
+
 impl std::cmp::PartialEq for Device {
     fn eq(&self, other: &Self) -> bool {
-        self.dev == other.dev
+        self.identity() == other.identity()
     }
 }
 impl std::cmp::Eq for Device {}
 impl std::cmp::Ord for Device {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.dev.cmp(&other.dev)
+        self.identity().cmp(&other.identity())
     }
 }
 impl std::cmp::PartialOrd for Device {
@@ -140,10 +369,15 @@ impl std::cmp::PartialOrd for Device {
 }
 impl std::hash::Hash for Device {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.dev.hash(state);
+        self.identity().hash(state);
     }
 }
 
+/// Whether an I/O error means the device has been removed.
+pub fn is_removal_error(error: &std::io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::ENODEV) | Some(libc::ENXIO))
+}
+
 impl DeviceFile {
     pub fn from_path(path: &Path) -> Result<Self, String> {
         let mut file = match File::open(path) {
@@ -162,26 +396,88 @@ impl DeviceFile {
             size,
             file,
             // fd,
+            read_mode: ReadMode::Cached,
+            block_size: SECTOR_SIZE,
         })
     }
 
-    pub fn get_chunk(&mut self, offset: u64, size: usize) -> Chunk {
+    /// The size of a chunk, which may be smaller than requested at the end
+    /// of the device.
+    pub fn get_chunk_length(&self, offset: u64, size: usize) -> usize {
         if offset >= self.size {
             panic!("Offset is out of bounds for device");
         }
-        let capped_size: usize =
-            if offset + (size as u64) > self.size {
-                (self.size - offset) as usize
-            } else {
-                size
-            };
-        let mut data: Vec<u8> = Vec::with_capacity(capped_size);
-        unsafe{data.set_len(capped_size)};
-        self.file.seek(SeekFrom::Start(offset)).expect("Could not seek device file");
-        self.file.read_exact(&mut data).expect("Error reading from device file");
-        Chunk {
-            offset,
-            data,
+        if offset + (size as u64) > self.size {
+            (self.size - offset) as usize
+        } else {
+            size
+        }
+    }
+
+    pub fn get_chunk(&self, offset: u64, size: usize) -> std::io::Result<Chunk> {
+        read::read_chunk(&self.file, offset, self.get_chunk_length(offset, size), self.read_mode, self.block_size)
+    }
+
+    /// A handle for reading from another thread. It shares the open file
+    /// (including any exclusive claim and read mode), so should be made
+    /// after those are set.
+    pub fn reader(&self) -> Result<SourceReader,String> {
+        match self.file.try_clone() {
+            Ok(file) => Ok(SourceReader {file, read_mode: self.read_mode, block_size: self.block_size}),
+            Err(e) => Err(format!("Could not duplicate '{}' for reading: {}", self.path.display(), e)),
+        }
+    }
+
+    fn set_direct(&self, direct: bool) -> std::io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        let flags = unsafe {libc::fcntl(fd, libc::F_GETFL)};
+        if flags < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let flags = if direct {flags | libc::O_DIRECT} else {flags & !libc::O_DIRECT};
+        if unsafe {libc::fcntl(fd, libc::F_SETFL, flags)} < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Choose how chunks are read, given the source's logical block size.
+    /// If O_DIRECT can't be used, ReadMode::DropBehind is used instead, and
+    /// the reason is returned.
+    pub fn set_read_mode(&mut self, read_mode: ReadMode, block_size: u64) -> Result<(),String> {
+        self.block_size = block_size;
+        if read_mode != ReadMode::Direct {
+            self.read_mode = read_mode;
+            return Ok(());
+        }
+        self.read_mode = ReadMode::Direct;
+        let result = self.set_direct(true).and_then(
+            |()| {
+                // Some filesystems accept the flag, but fail the reads.
+                if self.size > 0 {
+                    self.get_chunk(0, block_size as usize)?;
+                }
+                Ok(())
+            }
+        );
+        if let Err(e) = result {
+            let _ = self.set_direct(false);
+            self.read_mode = ReadMode::DropBehind;
+            return Err(format!("Could not read '{}' with O_DIRECT: {}", self.path.display(), e));
+        }
+        Ok(())
+    }
+
+    /// Reopen the device exclusively (O_EXCL), which stops it being mounted
+    /// or claimed by device-mapper, md and the like until we're done. Fails
+    /// if something has already claimed it (e.g. a read-only mount).
+    pub fn claim_exclusive(&mut self) -> Result<(),String> {
+        match OpenOptions::new().read(true).custom_flags(libc::O_EXCL).open(&self.path) {
+            Ok(file) => {
+                self.file = file;
+                Ok(())
+            },
+            Err(e) => Err(format!("Could not open '{}' exclusively: {}", self.path.display(), e)),
         }
     }
 
@@ -192,6 +488,30 @@ impl DeviceFile {
     pub fn get_size(&self) -> u64 {
         self.size
     }
+
+    /// Flush anything written to the device through its page cache.
+    pub fn sync(&self) -> Result<(),String> {
+        match self.file.sync_all() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Could not sync '{}': {}", self.path.display(), e)),
+        }
+    }
+
+    /// Ask the kernel for the current size, in case the device has been
+    /// resized since it was opened.
+    pub fn refresh_size(&mut self) -> u64 {
+        let mut size: u64 = 0;
+        let result = unsafe {
+            libc::ioctl(self.file.as_raw_fd(), BLKGETSIZE64 as _, &mut size as *mut u64)
+        };
+        if result == 0 {
+            self.size = size;
+        } else {
+            // Not a block device (e.g. a plain file being backed up).
+            self.size = self.file.seek(SeekFrom::End(0)).expect("Could not determine device size");
+        }
+        self.size
+    }
 }
 
 // impl Drop for Device {
@@ -199,3 +519,34 @@ impl DeviceFile {
 //         self.set_trace_enabled(false);
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::interface::Internalize;
+
+    #[test]
+    fn test_replaced_file() {
+        let dir = crate::test_util::test_dir("replaced-file");
+        let path = dir.join("source");
+        std::fs::write(&path, vec![1; 8192]).unwrap();
+        let source = DeviceFile::from_path(&path).unwrap();
+        let config = crate::control::interface::Config::default().internalize().unwrap();
+        let original = match Device::from_file(&config, &source) {
+            Ok(x) => x,
+            Err(e) => {
+                // e.g. on tmpfs, where files can't be traced.
+                eprintln!("Skipping, as the test file can't be traced: {}", e);
+                std::fs::remove_dir_all(&dir).unwrap();
+                return;
+            },
+        };
+        std::fs::write(dir.join("replacement"), vec![2; 4096]).unwrap();
+        std::fs::rename(dir.join("replacement"), &path).unwrap();
+        // Still the file being read from.
+        let device = Device::from_file(&config, &source).unwrap();
+        assert_eq!(device.file.as_ref().unwrap().ino, original.file.as_ref().unwrap().ino);
+        assert_eq!(device.get_size(), 8192);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+// Lets a regular file (e.g. a VM disk image) be used as a source, by mapping
+// it onto the block device its filesystem lives on and tracing that.
+//
+// The file's extents are read with the FIEMAP ioctl. They change whenever the
+// filesystem allocates blocks for the file (e.g. filling in a hole, or delayed
+// allocation at writeback) or moves them (e.g. reflinking or defragmenting).
+// Writes to blocks we don't know about can't be traced, so the copier re-reads
+// the extents regularly, and re-copies any part of the file whose mapping has
+// changed.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use libc::c_ulong;
+use super::SECTOR_SIZE;
+use super::stack::Transform;
+
+// _IOWR('f', 11, struct fiemap) and flags from linux/fiemap.h.
+const FS_IOC_FIEMAP: c_ulong = 0xC020660B;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
+const FIEMAP_EXTENT_ENCODED: u32 = 0x8;
+const FIEMAP_EXTENT_NOT_ALIGNED: u32 = 0x100;
+const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+
+const EXTENTS_PER_CALL: usize = 256;
+
+#[repr(C)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapExtent; EXTENTS_PER_CALL],
+}
+
+/// Where part of a file lives on its filesystem's device. All values are
+/// in bytes.
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+pub struct Extent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+}
+
+/// A regular file being used as a source.
+#[derive(Clone)]
+pub struct SourceFile {
+    pub ino: u64,
+    /// In bytes.
+    pub size: u64,
+    /// Only those with a known place on disk, in file order.
+    pub extents: Vec<Extent>,
+}
+
+/// Read where a file's data currently lives. Parts which haven't been
+/// allocated yet (holes and delayed allocations) are left out.
+pub fn read_extents(file: &File) -> Result<Vec<Extent>,String> {
+    let mut extents = Vec::new();
+    let mut fiemap: Box<Fiemap> = Box::new(unsafe {std::mem::zeroed()});
+    let mut next_start = 0;
+    loop {
+        fiemap.fm_start = next_start;
+        fiemap.fm_length = u64::MAX;
+        fiemap.fm_extent_count = EXTENTS_PER_CALL as u32;
+        if unsafe {libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut *fiemap as *mut Fiemap)} != 0 {
+            return Err(format!("Could not read the file's extents: {}", std::io::Error::last_os_error()));
+        }
+        let mapped = &fiemap.fm_extents[..fiemap.fm_mapped_extents as usize];
+        if mapped.is_empty() {
+            return Ok(extents);
+        }
+        for fiemap_extent in mapped {
+            if fiemap_extent.fe_flags & (FIEMAP_EXTENT_ENCODED | FIEMAP_EXTENT_NOT_ALIGNED | FIEMAP_EXTENT_DATA_INLINE) != 0 {
+                return Err(format!("Part of the file at offset {} is not stored as plain blocks on disk (e.g. it is compressed or inline)", fiemap_extent.fe_logical));
+            }
+            // Extents without a place on disk yet will turn up as new ones
+            // once they're written out.
+            if fiemap_extent.fe_flags & (FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DELALLOC) == 0 {
+                extents.push(Extent {
+                    logical: fiemap_extent.fe_logical,
+                    physical: fiemap_extent.fe_physical,
+                    length: fiemap_extent.fe_length,
+                });
+            }
+            next_start = fiemap_extent.fe_logical + fiemap_extent.fe_length;
+            if fiemap_extent.fe_flags & FIEMAP_EXTENT_LAST != 0 {
+                return Ok(extents);
+            }
+        }
+    }
+}
+
+/// How sectors of the filesystem's device map onto sectors of the file.
+pub fn extents_transform(extents: &[Extent]) -> Transform {
+    Transform::extents(extents.iter().map(
+        |extent| {(extent.physical / SECTOR_SIZE, extent.logical / SECTOR_SIZE, extent.length.div_ceil(SECTOR_SIZE))}
+    ).collect())
+}
+
+/// The byte ranges of a file whose mapping differs between two sets of
+/// extents, so may have been written to without being traced. Overlapping
+/// and neighbouring ranges are merged.
+pub fn changed_ranges(old: &[Extent], new: &[Extent]) -> Vec<(u64, u64)> {
+    let old_set: HashSet<&Extent> = old.iter().collect();
+    let new_set: HashSet<&Extent> = new.iter().collect();
+    let mut changed: Vec<(u64, u64)> =
+        old_set.difference(&new_set)
+        .chain(new_set.difference(&old_set))
+        .map(|extent| {(extent.logical, extent.logical + extent.length)})
+        .collect();
+    changed.sort();
+    let mut ranges: Vec<(u64, u64)> = Vec::with_capacity(changed.len());
+    for (start, end) in changed {
+        match ranges.last_mut() {
+            Some(last) if start <= last.1 => {
+                last.1 = last.1.max(end);
+            },
+            _ => {
+                ranges.push((start, end));
+            },
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_ranges() {
+        let extent = |logical: u64, physical: u64, length: u64| {Extent {logical, physical, length}};
+        let old = vec![extent(0, 40960, 4096), extent(8192, 81920, 8192)];
+        // A hole at 4096 has been filled, and the last extent was moved.
+        let new = vec![extent(0, 40960, 4096), extent(4096, 65536, 4096), extent(8192, 122880, 8192)];
+        assert_eq!(changed_ranges(&old, &new), vec![(4096, 16384)]);
+        assert!(changed_ranges(&new, &new).is_empty());
+        // Ranges with a gap between them stay apart.
+        let moved = vec![extent(0, 0, 4096), extent(4096, 65536, 4096), extent(8192, 122880, 8192)];
+        assert_eq!(changed_ranges(&new, &moved), vec![(0, 4096)]);
+        assert_eq!(changed_ranges(&old, &moved), vec![(0, 16384)]);
+        let grown = vec![extent(0, 40960, 4096), extent(8192, 81920, 8192), extent(32768, 8192, 4096)];
+        assert_eq!(changed_ranges(&old, &grown), vec![(32768, 36864)]);
+    }
+}
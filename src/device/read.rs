@@ -0,0 +1,128 @@
+// Reads chunks from sources in the way their jobs ask for. Reading through
+// the page cache as normal would push out whatever else is cached (e.g. the
+// working set of whatever uses the source) to make room for data we'll never
+// read again.
+//
+// O_DIRECT reads bypass the cache, but need buffers, offsets and lengths
+// aligned to the logical block size. Chunks always start on a block boundary,
+// but the last one may end part way through a block of a file source, so
+// whole blocks are read and the excess dropped.
+
+use std::alloc::{Layout,alloc,dealloc,handle_alloc_error};
+use std::fs::File;
+use std::io::{Error,ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use crate::chunk::{Chunk,ChunkContent};
+use crate::control::ReadMode;
+
+/// Memory aligned to suit O_DIRECT.
+struct AlignedBuffer {
+    pointer: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(size: usize, alignment: usize) -> Self {
+        let layout = Layout::from_size_align(size, alignment).expect("Bad buffer alignment");
+        let pointer = unsafe {alloc(layout)};
+        if pointer.is_null() {
+            handle_alloc_error(layout);
+        }
+        Self {
+            pointer,
+            layout,
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe {std::slice::from_raw_parts(self.pointer, self.layout.size())}
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe {std::slice::from_raw_parts_mut(self.pointer, self.layout.size())}
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe {dealloc(self.pointer, self.layout)};
+    }
+}
+
+fn read_direct(file: &File, offset: u64, length: usize, block_size: usize) -> std::io::Result<Vec<u8>> {
+    let mut buffer = AlignedBuffer::new(length.div_ceil(block_size) * block_size, block_size);
+    let mut filled = 0;
+    while filled < length {
+        match file.read_at(&mut buffer.as_mut_slice()[filled..], offset + filled as u64) {
+            Ok(0) => {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Source ended part way through a chunk"));
+            },
+            Ok(read) => {
+                filled += read;
+            },
+            Err(e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => {
+                return Err(e);
+            },
+        }
+    }
+    Ok(buffer.as_slice()[..length].to_vec())
+}
+
+/// Tell the kernel that nobody needs what was just read to be cached.
+fn drop_cached(file: &File, offset: u64, length: usize) {
+    // This is only advice, so failing doesn't matter.
+    unsafe {libc::posix_fadvise(file.as_raw_fd(), offset as libc::off_t, length as libc::off_t, libc::POSIX_FADV_DONTNEED)};
+}
+
+/// Read exactly the given length, which must already be capped to the size
+/// of the source. With ReadMode::Direct, the file must have O_DIRECT set.
+pub fn read_chunk(file: &File, offset: u64, length: usize, read_mode: ReadMode, block_size: u64) -> std::io::Result<Chunk> {
+    let data =
+        if read_mode == ReadMode::Direct {
+            read_direct(file, offset, length, block_size as usize)?
+        } else {
+            let mut data: Vec<u8> = Vec::with_capacity(length);
+            unsafe{data.set_len(length)};
+            file.read_exact_at(&mut data, offset)?;
+            if read_mode == ReadMode::DropBehind {
+                drop_cached(file, offset, length);
+            }
+            data
+        };
+    Ok(Chunk {
+        offset,
+        content: ChunkContent::Data(data),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceFile;
+
+    #[test]
+    fn test_read_modes() {
+        let dir = crate::test_util::test_dir("read");
+        let path = dir.join("source");
+        let data: Vec<u8> = (0..1000).map(|i| {i as u8}).collect();
+        std::fs::write(&path, &data).unwrap();
+        for read_mode in [ReadMode::Cached, ReadMode::Direct, ReadMode::DropBehind] {
+            let mut source = DeviceFile::from_path(&path).unwrap();
+            // Not every filesystem supports O_DIRECT, but falling back
+            // shouldn't make any difference to what's read.
+            let _ = source.set_read_mode(read_mode, 512);
+            for chunk in [source.get_chunk(512, 1024).unwrap(), source.reader().unwrap().get_chunk(0, 512).unwrap()] {
+                match chunk.content {
+                    ChunkContent::Data(chunk_data) => {
+                        let offset = chunk.offset as usize;
+                        assert_eq!(chunk_data, &data[offset..(offset + 512).min(1000)]);
+                    },
+                    _ => panic!("Expected data"),
+                }
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
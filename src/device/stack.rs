@@ -0,0 +1,349 @@
+// Maps writes seen on traced devices back into a job's address space.
+//
+// A job's source may sit on top of other block devices (partitions, LVM,
+// dm-crypt, striped dm tables). Writes can be traced either on the source
+// itself (the "top" layer), or on the physical devices beneath it. In the
+// latter case, each traced device gets one or more segments, which chain
+// together the transforms needed to get from a sector on the traced device to
+// a sector on the source.
+//
+// Device-mapper tables are read using dmsetup, as the format printed there is
+// the same as the one used to create them.
+
+use std::process::Command;
+use libc::c_uint;
+use crate::control::Config;
+use super::Device;
+
+/// Maps a range of sectors on a lower device to the device above it. All
+/// values are in kernel sectors (SECTOR_SIZE).
+#[derive(Clone,Debug,PartialEq)]
+pub enum Transform {
+    /// [lower_start, lower_start + sectors) maps to upper_start onwards.
+    Linear {
+        lower_start: u64,
+        upper_start: u64,
+        sectors: u64,
+    },
+    /// One leg of a striped mapping. [lower_start, lower_start + sectors)
+    /// holds every `stripes`th chunk (starting at `stripe`) of the region
+    /// starting at upper_start.
+    Striped {
+        lower_start: u64,
+        upper_start: u64,
+        sectors: u64,
+        stripes: u64,
+        chunk_sectors: u64,
+        stripe: u64,
+    },
+    /// Many linear mappings, e.g. a file's extents. Each is (lower_start,
+    /// upper_start, sectors), sorted by lower_start. They may overlap where
+    /// blocks are shared.
+    Extents {
+        extents: Vec<(u64, u64, u64)>,
+        // The most sectors any one extent covers.
+        longest: u64,
+    },
+}
+
+impl Transform {
+    pub fn extents(mut extents: Vec<(u64, u64, u64)>) -> Self {
+        extents.sort();
+        let longest = extents.iter().map(|(_, _, sectors)| {*sectors}).max().unwrap_or(0);
+        Transform::Extents {extents, longest}
+    }
+
+    /// Map a half-open sector range into zero or more ranges on the upper
+    /// device. Anything outside this transform's window is dropped.
+    pub fn apply(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        match self {
+            Transform::Linear {lower_start, upper_start, sectors} => {
+                match clip(start, end, *lower_start, *sectors) {
+                    Some((start, end)) => vec![(start - lower_start + upper_start, end - lower_start + upper_start)],
+                    None => Vec::new(),
+                }
+            },
+            Transform::Striped {lower_start, upper_start, sectors, stripes, chunk_sectors, stripe} => {
+                let (start, end) = match clip(start, end, *lower_start, *sectors) {
+                    Some(x) => x,
+                    None => return Vec::new(),
+                };
+                let mut ranges = Vec::new();
+                let mut relative = start - lower_start;
+                let relative_end = end - lower_start;
+                while relative < relative_end {
+                    let chunk = relative / chunk_sectors;
+                    let piece_end = std::cmp::min(relative_end, (chunk + 1) * chunk_sectors);
+                    let upper = upper_start + (chunk * stripes + stripe) * chunk_sectors + relative % chunk_sectors;
+                    ranges.push((upper, upper + piece_end - relative));
+                    relative = piece_end;
+                }
+                ranges
+            },
+            Transform::Extents {extents, longest} => {
+                // Nothing starting before this can reach the range.
+                let first = extents.partition_point(|(lower_start, _, _)| {lower_start + longest <= start});
+                extents[first..].iter().take_while(|(lower_start, _, _)| {*lower_start < end}).flat_map(
+                    |(lower_start, upper_start, sectors)| {
+                        Transform::Linear {lower_start: *lower_start, upper_start: *upper_start, sectors: *sectors}.apply(start, end)
+                    }
+                ).collect()
+            },
+        }
+    }
+}
+
+/// The part of [start, end) within the window of sectors starting at
+/// lower_start, if any.
+fn clip(start: u64, end: u64, lower_start: u64, sectors: u64) -> Option<(u64, u64)> {
+    let start = std::cmp::max(start, lower_start);
+    let end = std::cmp::min(end, lower_start + sectors);
+    if start < end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// A traced whole disk device, and how its sectors map onto a job's source.
+#[derive(Clone)]
+pub struct Segment {
+    /// The whole disk device to trace. Events on it have absolute sectors.
+    pub traced: Device,
+    /// Applied in order, starting from the traced device's sectors.
+    pub transforms: Vec<Transform>,
+}
+
+impl Segment {
+    /// Map a half-open range of absolute sectors on the traced device to
+    /// ranges of sectors on the job's source.
+    pub fn map_range(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut ranges = vec![(start, end)];
+        for transform in &self.transforms {
+            ranges = ranges.iter().flat_map(|(start, end)| {transform.apply(*start, *end)}).collect();
+        }
+        ranges
+    }
+}
+
+/// A single target line from a device-mapper table.
+#[derive(Debug,PartialEq)]
+pub enum DmTarget {
+    /// linear and crypt targets: a single device at an offset.
+    Linear {
+        start: u64,
+        sectors: u64,
+        device: (c_uint, c_uint),
+        offset: u64,
+    },
+    Striped {
+        start: u64,
+        sectors: u64,
+        chunk_sectors: u64,
+        devices: Vec<((c_uint, c_uint), u64)>,
+    },
+}
+
+fn parse_major_minor(s: &str) -> Result<(c_uint, c_uint),String> {
+    let mut parts = s.split(':');
+    match (parts.next().map(str::parse), parts.next().map(str::parse), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor)), None) => Ok((major, minor)),
+        _ => Err(format!("Expected a major:minor device in device-mapper table, got '{}'", s)),
+    }
+}
+
+fn parse_number(s: Option<&str>, line: &str) -> Result<u64,String> {
+    match s.map(str::parse) {
+        Some(Ok(n)) => Ok(n),
+        _ => Err(format!("Could not parse device-mapper table line '{}'", line)),
+    }
+}
+
+/// Parse the output of `dmsetup table` for a single device.
+pub fn parse_dm_table(table: &str) -> Result<Vec<DmTarget>,String> {
+    let mut targets = Vec::new();
+    for line in table.lines() {
+        let mut fields = line.split_whitespace();
+        let start = match fields.next() {
+            Some(start) => parse_number(Some(start), line)?,
+            None => continue,
+        };
+        let sectors = parse_number(fields.next(), line)?;
+        let target_type = fields.next().unwrap_or("");
+        let args: Vec<&str> = fields.collect();
+        match target_type {
+            "linear" if args.len() >= 2 => {
+                targets.push(DmTarget::Linear {
+                    start,
+                    sectors,
+                    device: parse_major_minor(args[0])?,
+                    offset: parse_number(Some(args[1]), line)?,
+                });
+            },
+            // Encryption doesn't move data around, so a crypt target is
+            // mapped just like a linear one.
+            "crypt" if args.len() >= 5 => {
+                targets.push(DmTarget::Linear {
+                    start,
+                    sectors,
+                    device: parse_major_minor(args[3])?,
+                    offset: parse_number(Some(args[4]), line)?,
+                });
+            },
+            "striped" if args.len() >= 2 => {
+                let stripes = parse_number(Some(args[0]), line)? as usize;
+                let chunk_sectors = parse_number(Some(args[1]), line)?;
+                if stripes == 0 || chunk_sectors == 0 || args.len() < 2 + stripes * 2 || sectors % stripes as u64 != 0 {
+                    return Err(format!("Unexpected striped device-mapper table line '{}'", line));
+                }
+                let mut devices = Vec::with_capacity(stripes);
+                for stripe in 0..stripes {
+                    devices.push((
+                        parse_major_minor(args[2 + stripe * 2])?,
+                        parse_number(Some(args[3 + stripe * 2]), line)?,
+                    ));
+                }
+                targets.push(DmTarget::Striped {
+                    start,
+                    sectors,
+                    chunk_sectors,
+                    devices,
+                });
+            },
+            _ => {
+                return Err(format!("Tracing underlying devices through device-mapper '{}' targets is not supported", target_type));
+            },
+        }
+    }
+    Ok(targets)
+}
+
+fn read_dm_table(device: &Device) -> Result<Vec<DmTarget>,String> {
+    let output = match Command::new("dmsetup").arg("table").arg("-j").arg(device.major.to_string()).arg("-m").arg(device.minor.to_string()).output() {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(format!("Could not run dmsetup: {}", e));
+        },
+    };
+    if !output.status.success() {
+        return Err(format!("dmsetup could not read the table for device {}:{}", device.major, device.minor));
+    }
+    parse_dm_table(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// List the devices in a device's holders/ or slaves/ sysfs directory.
+fn read_stack_links(device: &Device, link_dir: &str) -> Vec<String> {
+    let mut names: Vec<String> = match std::fs::read_dir(device.sys_dev_path.join(link_dir)) {
+        Ok(entries) => entries.flatten().map(|entry| {entry.file_name().to_string_lossy().into_owned()}).collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+/// Segments for tracing writes to a device on the device itself.
+pub fn top_segments(device: &Device) -> Vec<Segment> {
+    vec![Segment {
+        traced: device.get_base_device().clone(),
+        transforms: vec![Transform::Linear {
+            lower_start: device.start_sector,
+            upper_start: 0,
+            sectors: device.sector_count,
+        }],
+    }]
+}
+
+/// Segments for tracing writes to a device on the lowest devices beneath it.
+pub fn underlying_segments(config: &Config, device: &Device) -> Result<Vec<Segment>,String> {
+    if device.sys_dev_path.join("dm").exists() {
+        let mut segments = Vec::new();
+        let lower_segments = |major_minor: (c_uint, c_uint)| -> Result<Vec<Segment>,String> {
+            let lower = Device::from_major_minor(config, major_minor.0, major_minor.1)?;
+            underlying_segments(config, &lower)
+        };
+        for target in read_dm_table(device)? {
+            match target {
+                DmTarget::Linear {start, sectors, device, offset} => {
+                    for mut segment in lower_segments(device)? {
+                        segment.transforms.push(Transform::Linear {
+                            lower_start: offset,
+                            upper_start: start,
+                            sectors,
+                        });
+                        segments.push(segment);
+                    }
+                },
+                DmTarget::Striped {start, sectors, chunk_sectors, devices} => {
+                    let stripes = devices.len() as u64;
+                    for (stripe, (device, offset)) in devices.into_iter().enumerate() {
+                        for mut segment in lower_segments(device)? {
+                            segment.transforms.push(Transform::Striped {
+                                lower_start: offset,
+                                upper_start: start,
+                                sectors: sectors / stripes,
+                                stripes,
+                                chunk_sectors,
+                                stripe: stripe as u64,
+                            });
+                            segments.push(segment);
+                        }
+                    }
+                },
+            }
+        }
+        Ok(segments)
+    } else if device.sys_dev_path.join("md").exists() {
+        // md RAID layouts (parity, mirrors, reshapes) can't be mapped back
+        // with simple transforms.
+        Err(format!("Tracing the underlying devices of md RAID device {}:{} is not supported", device.major, device.minor))
+    } else if !read_stack_links(device, "slaves").is_empty() {
+        Err(format!("Tracing the underlying devices of {}:{} is not supported", device.major, device.minor))
+    } else {
+        Ok(top_segments(device))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dm_table() {
+        let table = "0 2048 linear 8:2 4096\n2048 4096 striped 2 128 8:16 0 8:32 1024\n6144 100 crypt aes-xts-plain64 :64:logon:key 0 253:1 32768 1 allow_discards\n";
+        assert_eq!(parse_dm_table(table).unwrap(), vec![
+            DmTarget::Linear {start: 0, sectors: 2048, device: (8, 2), offset: 4096},
+            DmTarget::Striped {start: 2048, sectors: 4096, chunk_sectors: 128, devices: vec![((8, 16), 0), ((8, 32), 1024)]},
+            DmTarget::Linear {start: 6144, sectors: 100, device: (253, 1), offset: 32768},
+        ]);
+        assert!(parse_dm_table("0 100 thin-pool 8:1 8:2 128 0").is_err());
+        assert!(parse_dm_table("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_linear_transform() {
+        let transform = Transform::Linear {lower_start: 100, upper_start: 10, sectors: 50};
+        assert_eq!(transform.apply(0, 100), vec![]);
+        assert_eq!(transform.apply(90, 110), vec![(10, 20)]);
+        assert_eq!(transform.apply(140, 200), vec![(50, 60)]);
+        assert_eq!(transform.apply(150, 200), vec![]);
+    }
+
+    #[test]
+    fn test_striped_transform() {
+        // Second of three stripes, with 8 sector chunks.
+        let transform = Transform::Striped {lower_start: 1000, upper_start: 0, sectors: 64, stripes: 3, chunk_sectors: 8, stripe: 1};
+        assert_eq!(transform.apply(1000, 1004), vec![(8, 12)]);
+        assert_eq!(transform.apply(1006, 1018), vec![(14, 16), (32, 40), (56, 58)]);
+        assert_eq!(transform.apply(1064, 1100), vec![]);
+    }
+
+    #[test]
+    fn test_extents_transform() {
+        // The last extent shares its blocks with part of the first.
+        let transform = Transform::extents(vec![(5000, 0, 100), (2000, 100, 8), (5040, 200, 8)]);
+        assert_eq!(transform.apply(0, 2000), vec![]);
+        assert_eq!(transform.apply(2004, 2010), vec![(104, 108)]);
+        assert_eq!(transform.apply(5044, 5046), vec![(44, 46), (204, 206)]);
+        assert_eq!(transform.apply(5090, 6000), vec![(90, 100)]);
+    }
+}
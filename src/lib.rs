@@ -8,11 +8,19 @@ mod chunk;
 mod device;
 mod backup_file;
 mod chunk_tracker;
+mod chunk_hashes;
 mod change_logger;
+mod reader;
 mod writer;
 pub mod copier;
 mod quick_io;
+mod mounts;
+mod nbd;
+mod qmp;
 pub mod control;
 pub mod server;
 pub mod cli;
 pub mod lock;
+pub mod trace_state;
+#[cfg(test)]
+mod test_util;
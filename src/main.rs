@@ -3,7 +3,7 @@ extern crate clap;
 
 use std::path::{Path,PathBuf};
 use std::time::Duration;
-use trackup::control::{Job,ManagementInterface,Manifest};
+use trackup::control::{ChangeDetection,ChangeFilter,Job,JobFailurePolicy,ManagementInterface,Manifest,ReadMode,ReaderPool,SyncMethod,TraceLayer};
 use trackup::control::interface::Internalize;
 
 fn main() {
@@ -27,9 +27,37 @@ fn main() {
     if let Some(sys_path) = matches.value_of("sys-path") {
         config.sys_path = PathBuf::from(sys_path);
     }
+    if let Some(state_path) = matches.value_of("state-path") {
+        config.state_path = PathBuf::from(state_path);
+    }
     if let Some(trace_buffer_size) = matches.value_of("trace-buffer-size") {
         config.trace_buffer_size = trace_buffer_size.parse().expect("Could not parse trace-buffer-size as usize integer");
     }
+    if let Some(change_detection) = matches.value_of("change-detection") {
+        config.change_detection = match change_detection {
+            "auto" => ChangeDetection::Auto,
+            "tracing" => ChangeDetection::Tracing,
+            _ => ChangeDetection::Hashing,
+        };
+    }
+    if let Some(reader_threads) = matches.value_of("reader-threads") {
+        config.reader_threads = reader_threads.parse().expect("Could not parse reader-threads as usize integer");
+        if config.reader_threads == 0 {
+            panic!("reader-threads must be positive");
+        }
+    }
+    if let Some(max_io_size) = matches.value_of("max-io-size") {
+        config.max_io_size = max_io_size.parse().expect("Could not parse max-io-size as usize integer");
+        if config.max_io_size == 0 {
+            panic!("max-io-size must be positive");
+        }
+    }
+    if let Some(reader_pool) = matches.value_of("reader-pool") {
+        config.reader_pool = match reader_pool {
+            "shared" => ReaderPool::Shared,
+            _ => ReaderPool::PerDevice,
+        };
+    }
     if matches.is_present("progress-period")
         || matches.is_present("max-diagram-size")
         || matches.is_present("exclusive-progress-updates")
@@ -58,6 +86,18 @@ fn main() {
         }
     }
 
+    if matches.is_present("recover") {
+        match trackup::trace_state::recover(&config) {
+            Ok(recovered) => {
+                eprintln!("Recovered {} stale tracing session(s)", recovered);
+                return;
+            },
+            Err(e) => {
+                panic!("Failed to recover tracing settings: {}", e);
+            },
+        }
+    }
+
     let manifest = if let Some(manifest_path) = matches.value_of("manifest") {
         match trackup::control::interface::read_manifest_file(Path::new(manifest_path)) {
             Ok(manifest) => manifest,
@@ -77,6 +117,11 @@ fn main() {
                     destination: PathBuf::from(destination),
                     chunk_size,
                     reuse_output,
+                    trace_layer: TraceLayer::Top,
+                    untraced_when_idle: true,
+                    qemu: None,
+                    nbd_server: None,
+                    read_mode: ReadMode::Cached,
                 });
             }
         }
@@ -84,7 +129,10 @@ fn main() {
         Manifest {
             jobs,
             do_sync: true,
+            sync_method: SyncMethod::Filesystems,
+            job_failure_policy: JobFailurePolicy::AbortAll,
             locking: None,
+            change_filter: ChangeFilter::default(),
         }
     };
 
@@ -112,8 +160,15 @@ fn main() {
         trackup::server::task_loop(&config, &management_interface, optional_manifest);
     } else {
         eprintln!("Starting backup");
-        if let Err(e) = trackup::copier::run(&config, &manifest, &management_interface) {
-            eprintln!("Backup failed: {:?}", e);
+        match trackup::copier::run(&config, &manifest, &management_interface) {
+            Ok(report) => {
+                for failure in &report.failed_jobs {
+                    eprintln!("Job for '{}' failed: {}", failure.source.display(), failure.error);
+                }
+            },
+            Err(e) => {
+                eprintln!("Backup failed: {}", e);
+            },
         }
     }
 }
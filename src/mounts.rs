@@ -0,0 +1,234 @@
+// Finds the mounted filesystems which live on a job's source, so that only
+// they need syncing before a consistency check, rather than every filesystem
+// on the machine. The same information tells us whether anything could be
+// writing to a source at all.
+//
+// A filesystem lives on a source if its device is the source, one of the
+// source's partitions, or anything stacked on top of those (e.g. LVM or
+// dm-crypt), found by following holders/ in sysfs. Filesystems such as btrfs
+// report an anonymous device in mountinfo, so for those the mount source is
+// looked up instead.
+
+use std::collections::BTreeSet;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt,MetadataExt};
+use std::path::{Path,PathBuf};
+use libc::c_uint;
+use crate::device::Device;
+
+/// A line of /proc/self/mountinfo.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Mount {
+    /// The device reported for the mount, which is also the st_dev of files
+    /// within it.
+    pub dev: (c_uint, c_uint),
+    pub mount_point: PathBuf,
+    /// What was mounted, e.g. /dev/sda1.
+    pub source: String,
+    /// The filesystem (not just this mount of it) is read-only.
+    pub read_only: bool,
+}
+
+/// Undo the octal escaping mountinfo uses for spaces and the like.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i+1..i+4].iter().all(|b| {(b'0'..=b'7').contains(b)}) {
+            unescaped.push(u8::from_str_radix(&field[i+1..i+4], 8).unwrap_or(b'?'));
+            i += 4;
+        } else {
+            unescaped.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+fn parse_major_minor(s: &str) -> Option<(c_uint, c_uint)> {
+    let mut parts = s.trim().split(':');
+    match (parts.next()?.parse(), parts.next()?.parse(), parts.next()) {
+        (Ok(major), Ok(minor), None) => Some((major, minor)),
+        _ => None,
+    }
+}
+
+/// Parse the contents of /proc/self/mountinfo. Malformed lines are skipped.
+pub fn parse_mountinfo(mountinfo: &str) -> Vec<Mount> {
+    mountinfo.lines().filter_map(
+        |line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            // Optional fields come between the mount point and the separator.
+            let separator = fields.iter().position(|field| {*field == "-"})?;
+            if separator < 6 || fields.len() < separator + 4 {
+                return None;
+            }
+            Some(Mount {
+                dev: parse_major_minor(fields[2])?,
+                mount_point: PathBuf::from(unescape(fields[4])),
+                source: unescape(fields[separator + 2]),
+                read_only: fields[separator + 3].split(',').any(|option| {option == "ro"}),
+            })
+        }
+    ).collect()
+}
+
+impl Mount {
+    /// The block device the filesystem lives on, if there is one.
+    fn backing_device(&self) -> Option<(c_uint, c_uint)> {
+        if self.dev.0 != 0 {
+            return Some(self.dev);
+        }
+        let metadata = std::fs::metadata(&self.source).ok()?;
+        if !metadata.file_type().is_block_device() {
+            return None;
+        }
+        Some((libc::major(metadata.rdev()), libc::minor(metadata.rdev())))
+    }
+
+    /// Flush the filesystem's dirty data to its device.
+    pub fn syncfs(&self) -> Result<(),String> {
+        let path = CString::new(self.mount_point.as_os_str().as_bytes()).unwrap();
+        let fd = unsafe {libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC)};
+        if fd < 0 {
+            return Err(format!("Could not open mount point '{}'", self.mount_point.display()));
+        }
+        let result = {
+            let mut stat_result: libc::stat = unsafe {std::mem::zeroed()};
+            if unsafe {libc::fstat(fd, &mut stat_result)} != 0 {
+                Err(format!("Could not stat mount point '{}'", self.mount_point.display()))
+            } else if (libc::major(stat_result.st_dev), libc::minor(stat_result.st_dev)) != self.dev {
+                // Something else has been mounted over it.
+                Err(format!("Mount point '{}' is hidden by another mount", self.mount_point.display()))
+            } else if unsafe {libc::syncfs(fd)} != 0 {
+                Err(format!("Could not sync filesystem at '{}'", self.mount_point.display()))
+            } else {
+                Ok(())
+            }
+        };
+        unsafe {libc::close(fd)};
+        result
+    }
+}
+
+fn read_sys_dev(sys_dev_path: &Path) -> Result<(c_uint, c_uint),String> {
+    let dev_path = sys_dev_path.join("dev");
+    match std::fs::read_to_string(&dev_path).ok().as_deref().and_then(parse_major_minor) {
+        Some(dev) => Ok(dev),
+        None => Err(format!("Could not read device number from '{}'", dev_path.display())),
+    }
+}
+
+fn list_dir(path: &Path) -> Result<Vec<PathBuf>,String> {
+    match std::fs::read_dir(path) {
+        Ok(entries) => Ok(entries.flatten().map(|entry| {entry.path()}).collect()),
+        Err(e) => Err(format!("Could not list '{}': {}", path.display(), e)),
+    }
+}
+
+/// The device numbers of a device, its partitions, and everything stacked
+/// on top of any of those.
+pub fn devices_above(device: &Device) -> Result<BTreeSet<(c_uint, c_uint)>,String> {
+    let mut found = BTreeSet::new();
+    let mut pending: Vec<PathBuf> = vec![device.sys_dev_path.clone()];
+    while let Some(sys_dev_path) = pending.pop() {
+        let sys_dev_path = match sys_dev_path.canonicalize() {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Could not resolve '{}': {}", sys_dev_path.display(), e));
+            },
+        };
+        if !found.insert(read_sys_dev(&sys_dev_path)?) {
+            continue;
+        }
+        for entry in list_dir(&sys_dev_path)? {
+            if entry.join("partition").exists() {
+                pending.push(entry);
+            }
+        }
+        let holders_path = sys_dev_path.join("holders");
+        if holders_path.exists() {
+            pending.extend(list_dir(&holders_path)?);
+        }
+    }
+    Ok(found)
+}
+
+/// The mounted filesystems which live on a device.
+pub fn mounts_on(device: &Device) -> Result<Vec<Mount>,String> {
+    let mountinfo = match std::fs::read_to_string("/proc/self/mountinfo") {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(format!("Could not read mountinfo: {}", e));
+        },
+    };
+    if device.file.is_some() && device.parent.is_none() {
+        // A file which isn't mapped onto a device. Its own filesystem is
+        // the one reporting its st_dev.
+        return Ok(parse_mountinfo(&mountinfo).into_iter().filter(
+            |mount| {mount.dev == (device.major, device.minor)}
+        ).collect());
+    }
+    let devices = devices_above(device)?;
+    Ok(parse_mountinfo(&mountinfo).into_iter().filter(
+        |mount| {mount.backing_device().is_some_and(|dev| {devices.contains(&dev)})}
+    ).collect())
+}
+
+/// Whether anything (e.g. device-mapper or md) is stacked on the device or
+/// any of its partitions.
+fn has_holders(device: &Device) -> Result<bool,String> {
+    let sys_dev_path = match device.sys_dev_path.canonicalize() {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(format!("Could not resolve '{}': {}", device.sys_dev_path.display(), e));
+        },
+    };
+    let mut sys_dev_paths = vec![sys_dev_path.clone()];
+    sys_dev_paths.extend(list_dir(&sys_dev_path)?.into_iter().filter(|entry| {entry.join("partition").exists()}));
+    for sys_dev_path in sys_dev_paths {
+        let holders_path = sys_dev_path.join("holders");
+        if holders_path.exists() && !list_dir(&holders_path)?.is_empty() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether nothing can write to a device through a filesystem or a stacked
+/// device. That is, every filesystem on it (or its partitions) is read-only,
+/// and nothing is stacked on top of it. Programs writing to the device
+/// directly can't be ruled out this way.
+pub fn is_idle(device: &Device) -> Result<bool,String> {
+    if has_holders(device)? {
+        return Ok(false);
+    }
+    Ok(mounts_on(device)?.iter().all(|mount| {mount.read_only}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mountinfo = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+36 22 0:31 /home /home\\040dir rw,relatime shared:2 master:1 - btrfs /dev/mapper/home rw,space_cache=v2
+40 22 0:5 / /dev rw,nosuid - devtmpfs udev rw
+41 22 8:17 / /mnt/archive ro,relatime - ext4 /dev/sdb1 ro
+42 22 8:17 / /mnt/bound ro,relatime - ext4 /dev/sdb1 rw
+garbage
+";
+        assert_eq!(parse_mountinfo(mountinfo), vec![
+            Mount {dev: (259, 2), mount_point: PathBuf::from("/"), source: String::from("/dev/nvme0n1p2"), read_only: false},
+            Mount {dev: (0, 31), mount_point: PathBuf::from("/home dir"), source: String::from("/dev/mapper/home"), read_only: false},
+            Mount {dev: (0, 5), mount_point: PathBuf::from("/dev"), source: String::from("udev"), read_only: false},
+            Mount {dev: (8, 17), mount_point: PathBuf::from("/mnt/archive"), source: String::from("/dev/sdb1"), read_only: true},
+            // Only the mount is read-only.
+            Mount {dev: (8, 17), mount_point: PathBuf::from("/mnt/bound"), source: String::from("/dev/sdb1"), read_only: false},
+        ]);
+    }
+}
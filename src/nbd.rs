@@ -0,0 +1,383 @@
+// Just enough of the NBD protocol to serve a single export to clients such as
+// QEMU or nbd-client, and to ask a server (e.g. QEMU's) which parts of an
+// export have some status, using the block status command with a metadata
+// context such as qemu:dirty-bitmap:NAME.
+//
+// Only fixed newstyle negotiation is supported. All integers on the wire are
+// big endian. See
+// https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md
+
+use std::io::{Read,Write};
+
+pub const NBDMAGIC: u64 = 0x4e42444d41474943;
+pub const IHAVEOPT: u64 = 0x49484156454f5054;
+pub const OPTION_REPLY_MAGIC: u64 = 0x0003e889045565a9;
+pub const REQUEST_MAGIC: u32 = 0x25609513;
+pub const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+pub const STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
+
+pub const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+pub const FLAG_NO_ZEROES: u16 = 1 << 1;
+
+pub const OPT_EXPORT_NAME: u32 = 1;
+pub const OPT_ABORT: u32 = 2;
+pub const OPT_LIST: u32 = 3;
+pub const OPT_INFO: u32 = 6;
+pub const OPT_GO: u32 = 7;
+pub const OPT_STRUCTURED_REPLY: u32 = 8;
+pub const OPT_SET_META_CONTEXT: u32 = 10;
+
+pub const REP_ACK: u32 = 1;
+pub const REP_SERVER: u32 = 2;
+pub const REP_INFO: u32 = 3;
+pub const REP_META_CONTEXT: u32 = 4;
+pub const REP_FLAG_ERROR: u32 = 1 << 31;
+pub const REP_ERR_UNSUP: u32 = REP_FLAG_ERROR | 1;
+pub const REP_ERR_UNKNOWN: u32 = REP_FLAG_ERROR | 6;
+
+pub const INFO_EXPORT: u16 = 0;
+
+pub const FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub const FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const FLAG_SEND_FUA: u16 = 1 << 3;
+pub const FLAG_SEND_TRIM: u16 = 1 << 5;
+pub const FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+pub const FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+
+pub const CMD_READ: u16 = 0;
+pub const CMD_WRITE: u16 = 1;
+pub const CMD_DISC: u16 = 2;
+pub const CMD_FLUSH: u16 = 3;
+pub const CMD_TRIM: u16 = 4;
+pub const CMD_WRITE_ZEROES: u16 = 6;
+pub const CMD_BLOCK_STATUS: u16 = 7;
+
+pub const CMD_FLAG_FUA: u16 = 1 << 0;
+pub const CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+pub const REPLY_FLAG_DONE: u16 = 1 << 0;
+pub const REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+pub const REPLY_TYPE_ERROR_BIT: u16 = 1 << 15;
+
+/// In the qemu:dirty-bitmap context, the block has been written to.
+pub const STATE_DIRTY: u32 = 1 << 0;
+
+// The most to ask about in one block status request.
+const MAX_STATUS_LENGTH: u64 = 1 << 30;
+
+fn io_error(e: std::io::Error) -> String {
+    format!("NBD connection failed: {}", e)
+}
+
+pub fn read_u16(reader: &mut impl Read) -> Result<u16,String> {
+    let mut buf = [0; 2];
+    reader.read_exact(&mut buf).map_err(io_error)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+pub fn read_u32(reader: &mut impl Read) -> Result<u32,String> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf).map_err(io_error)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+pub fn read_u64(reader: &mut impl Read) -> Result<u64,String> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf).map_err(io_error)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+pub fn read_bytes(reader: &mut impl Read, length: usize) -> Result<Vec<u8>,String> {
+    let mut buf = vec![0; length];
+    reader.read_exact(&mut buf).map_err(io_error)?;
+    Ok(buf)
+}
+
+/// Length prefixed (u32) string, as used in option data.
+pub fn push_string(buf: &mut Vec<u8>, string: &str) {
+    buf.extend_from_slice(&(string.len() as u32).to_be_bytes());
+    buf.extend_from_slice(string.as_bytes());
+}
+
+/// A connection to a single export, after negotiation.
+pub struct Client<S: Read + Write> {
+    stream: S,
+    size: u64,
+    context_id: u32,
+    next_handle: u64,
+}
+
+impl<S: Read + Write> Client<S> {
+    /// Negotiate use of an export, with a single metadata context for block
+    /// status requests.
+    pub fn connect(mut stream: S, export: &str, context: &str) -> Result<Self,String> {
+        if read_u64(&mut stream)? != NBDMAGIC {
+            return Err(String::from("Not an NBD server"));
+        }
+        if read_u64(&mut stream)? != IHAVEOPT {
+            return Err(String::from("NBD server doesn't support newstyle negotiation"));
+        }
+        let server_flags = read_u16(&mut stream)?;
+        if server_flags & FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(String::from("NBD server doesn't support fixed newstyle negotiation"));
+        }
+        let client_flags = (FLAG_FIXED_NEWSTYLE | (server_flags & FLAG_NO_ZEROES)) as u32;
+        stream.write_all(&client_flags.to_be_bytes()).map_err(io_error)?;
+
+        let mut client = Self {
+            stream,
+            size: 0,
+            context_id: 0,
+            next_handle: 0,
+        };
+
+        client.send_option(OPT_STRUCTURED_REPLY, &[])?;
+        while client.read_option_reply(OPT_STRUCTURED_REPLY)?.0 != REP_ACK {}
+
+        let mut data = Vec::new();
+        push_string(&mut data, export);
+        data.extend_from_slice(&1u32.to_be_bytes());
+        push_string(&mut data, context);
+        client.send_option(OPT_SET_META_CONTEXT, &data)?;
+        let mut context_id = None;
+        loop {
+            let (reply_type, reply) = client.read_option_reply(OPT_SET_META_CONTEXT)?;
+            match reply_type {
+                REP_ACK => break,
+                REP_META_CONTEXT if reply.len() >= 4 => {
+                    context_id = Some(u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]));
+                },
+                _ => {},
+            }
+        }
+        client.context_id = match context_id {
+            Some(x) => x,
+            None => {
+                return Err(format!("NBD server doesn't offer metadata context '{}'", context));
+            },
+        };
+
+        let mut data = Vec::new();
+        push_string(&mut data, export);
+        // No particular information requests.
+        data.extend_from_slice(&0u16.to_be_bytes());
+        client.send_option(OPT_GO, &data)?;
+        loop {
+            let (reply_type, reply) = client.read_option_reply(OPT_GO)?;
+            match reply_type {
+                REP_ACK => break,
+                REP_INFO if reply.len() >= 10 && u16::from_be_bytes([reply[0], reply[1]]) == INFO_EXPORT => {
+                    let mut size = [0; 8];
+                    size.copy_from_slice(&reply[2..10]);
+                    client.size = u64::from_be_bytes(size);
+                },
+                _ => {},
+            }
+        }
+        Ok(client)
+    }
+
+    fn send_option(&mut self, option: u32, data: &[u8]) -> Result<(),String> {
+        let mut buf = Vec::with_capacity(16 + data.len());
+        buf.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        buf.extend_from_slice(&option.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf).map_err(io_error)
+    }
+
+    /// Returns the reply type and data.
+    fn read_option_reply(&mut self, option: u32) -> Result<(u32, Vec<u8>),String> {
+        if read_u64(&mut self.stream)? != OPTION_REPLY_MAGIC {
+            return Err(String::from("Bad NBD option reply"));
+        }
+        let replied_option = read_u32(&mut self.stream)?;
+        let reply_type = read_u32(&mut self.stream)?;
+        let length = read_u32(&mut self.stream)? as usize;
+        let data = read_bytes(&mut self.stream, length)?;
+        if replied_option != option {
+            return Err(format!("NBD server replied to option {} instead of {}", replied_option, option));
+        }
+        if reply_type & REP_FLAG_ERROR != 0 {
+            return Err(format!("NBD server refused option {}: {}", option, String::from_utf8_lossy(&data)));
+        }
+        Ok((reply_type, data))
+    }
+
+    /// Find the byte ranges of the whole export which have any of the given
+    /// status flags set in the negotiated context.
+    pub fn block_status(&mut self, flags: u32) -> Result<Vec<(u64, u64)>,String> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        let mut offset = 0;
+        while offset < self.size {
+            let length = std::cmp::min(self.size - offset, MAX_STATUS_LENGTH);
+            let handle = self.next_handle;
+            self.next_handle += 1;
+            self.send_request(CMD_BLOCK_STATUS, handle, offset, length as u32)?;
+            let mut covered = 0;
+            loop {
+                let (reply_flags, reply_type, payload) = self.read_structured_reply(handle)?;
+                if reply_type == REPLY_TYPE_BLOCK_STATUS && payload.len() >= 4 && payload[0..4] == self.context_id.to_be_bytes() {
+                    for descriptor in payload[4..].chunks_exact(8) {
+                        let extent_length = u32::from_be_bytes([descriptor[0], descriptor[1], descriptor[2], descriptor[3]]) as u64;
+                        let extent_flags = u32::from_be_bytes([descriptor[4], descriptor[5], descriptor[6], descriptor[7]]);
+                        // The last extent may run past what was asked for.
+                        let start = offset + covered;
+                        let end = std::cmp::min(start + extent_length, offset + length);
+                        if extent_flags & flags != 0 && start < end {
+                            match ranges.last_mut() {
+                                Some(last) if last.1 == start => {
+                                    last.1 = end;
+                                },
+                                _ => {
+                                    ranges.push((start, end));
+                                },
+                            }
+                        }
+                        covered = end - offset;
+                    }
+                }
+                if reply_flags & REPLY_FLAG_DONE != 0 {
+                    break;
+                }
+            }
+            if covered == 0 {
+                return Err(format!("NBD server gave no block status at offset {}", offset));
+            }
+            offset += covered;
+        }
+        Ok(ranges)
+    }
+
+    fn send_request(&mut self, command: u16, handle: u64, offset: u64, length: u32) -> Result<(),String> {
+        let mut buf = Vec::with_capacity(28);
+        buf.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        buf.extend_from_slice(&command.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(&length.to_be_bytes());
+        self.stream.write_all(&buf).map_err(io_error)
+    }
+
+    /// Returns the reply flags, type and payload.
+    fn read_structured_reply(&mut self, handle: u64) -> Result<(u16, u16, Vec<u8>),String> {
+        match read_u32(&mut self.stream)? {
+            STRUCTURED_REPLY_MAGIC => {},
+            SIMPLE_REPLY_MAGIC => {
+                let error = read_u32(&mut self.stream)?;
+                return Err(format!("NBD request failed with error {}", error));
+            },
+            _ => {
+                return Err(String::from("Bad NBD reply"));
+            },
+        }
+        let reply_flags = read_u16(&mut self.stream)?;
+        let reply_type = read_u16(&mut self.stream)?;
+        let replied_handle = read_u64(&mut self.stream)?;
+        let length = read_u32(&mut self.stream)? as usize;
+        let payload = read_bytes(&mut self.stream, length)?;
+        if replied_handle != handle {
+            return Err(format!("NBD server replied to request {} instead of {}", replied_handle, handle));
+        }
+        if reply_type & REPLY_TYPE_ERROR_BIT != 0 {
+            let error = if payload.len() >= 4 {u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])} else {0};
+            let message = if payload.len() > 6 {String::from_utf8_lossy(&payload[6..]).into_owned()} else {String::new()};
+            return Err(format!("NBD request failed with error {}: {}", error, message));
+        }
+        Ok((reply_flags, reply_type, payload))
+    }
+
+    /// Politely end the connection.
+    pub fn disconnect(mut self) {
+        let handle = self.next_handle;
+        // Nothing more can go wrong that we'd care about.
+        let _ = self.send_request(CMD_DISC, handle, 0, 0);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    /// Play the server side of a connection which negotiates a single
+    /// export, then answers block status requests with the given extents
+    /// (length, flags), which should cover the export.
+    pub fn mock_server(mut stream: UnixStream, size: u64, extents: Vec<(u32, u32)>) {
+        let write = |stream: &mut UnixStream, parts: &[&[u8]]| {
+            for part in parts {
+                stream.write_all(part).unwrap();
+            }
+        };
+        let reply = |stream: &mut UnixStream, option: u32, reply_type: u32, data: &[u8]| {
+            write(stream, &[&OPTION_REPLY_MAGIC.to_be_bytes(), &option.to_be_bytes(), &reply_type.to_be_bytes(), &(data.len() as u32).to_be_bytes(), data]);
+        };
+        write(&mut stream, &[&NBDMAGIC.to_be_bytes(), &IHAVEOPT.to_be_bytes(), &(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes()]);
+        assert_eq!(read_u32(&mut stream).unwrap(), (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES) as u32);
+        loop {
+            assert_eq!(read_u64(&mut stream).unwrap(), IHAVEOPT);
+            let option = read_u32(&mut stream).unwrap();
+            let length = read_u32(&mut stream).unwrap() as usize;
+            let data = read_bytes(&mut stream, length).unwrap();
+            match option {
+                OPT_STRUCTURED_REPLY => {
+                    reply(&mut stream, option, REP_ACK, &[]);
+                },
+                OPT_SET_META_CONTEXT => {
+                    // The last query is the context asked for.
+                    let mut context = 1u32.to_be_bytes().to_vec();
+                    let name_length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                    context.extend_from_slice(&data[4 + name_length + 4 + 4..]);
+                    reply(&mut stream, option, REP_META_CONTEXT, &context);
+                    reply(&mut stream, option, REP_ACK, &[]);
+                },
+                OPT_GO => {
+                    let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&size.to_be_bytes());
+                    info.extend_from_slice(&0u16.to_be_bytes());
+                    reply(&mut stream, option, REP_INFO, &info);
+                    reply(&mut stream, option, REP_ACK, &[]);
+                    break;
+                },
+                _ => panic!("Unexpected NBD option {}", option),
+            }
+        }
+        loop {
+            assert_eq!(read_u32(&mut stream).unwrap(), REQUEST_MAGIC);
+            read_u16(&mut stream).unwrap();
+            let command = read_u16(&mut stream).unwrap();
+            let handle = read_u64(&mut stream).unwrap();
+            let offset = read_u64(&mut stream).unwrap();
+            read_u32(&mut stream).unwrap();
+            match command {
+                CMD_DISC => return,
+                CMD_BLOCK_STATUS => {
+                    let mut payload = 1u32.to_be_bytes().to_vec();
+                    let mut start = 0;
+                    for (length, flags) in &extents {
+                        if start >= offset {
+                            payload.extend_from_slice(&length.to_be_bytes());
+                            payload.extend_from_slice(&flags.to_be_bytes());
+                        }
+                        start += *length as u64;
+                    }
+                    write(&mut stream, &[&STRUCTURED_REPLY_MAGIC.to_be_bytes(), &REPLY_FLAG_DONE.to_be_bytes(), &REPLY_TYPE_BLOCK_STATUS.to_be_bytes(), &handle.to_be_bytes(), &(payload.len() as u32).to_be_bytes(), &payload]);
+                },
+                _ => panic!("Unexpected NBD command {}", command),
+            }
+        }
+    }
+
+    #[test]
+    fn test_block_status() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            mock_server(server_stream, 1 << 20, vec![(4096, 1), (8192, 1), (65536, 0), (4096, 3), (1048576 - 81920, 0)]);
+        });
+        let mut client = Client::connect(client_stream, "disk", "qemu:dirty-bitmap:trackup").unwrap();
+        assert_eq!(client.block_status(STATE_DIRTY).unwrap(), vec![(0, 12288), (77824, 81920)]);
+        client.disconnect();
+        server.join().unwrap();
+    }
+}
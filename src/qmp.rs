@@ -0,0 +1,132 @@
+// A minimal client for the QEMU Machine Protocol, as spoken on a monitor's
+// unix socket. Each message is a JSON object on its own line. Asynchronous
+// events can turn up at any point, and are ignored.
+
+use std::io::{BufRead,BufReader,Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+use serde_json::{Value,json};
+
+// How long to wait for QEMU to answer before giving up on it.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Qmp {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+}
+
+impl Qmp {
+    /// Connect and leave capabilities negotiation mode, so that commands can
+    /// be run.
+    pub fn connect(path: &Path) -> Result<Self,String> {
+        let stream = match UnixStream::connect(path) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Could not connect to QMP socket '{}': {}", path.display(), e));
+            },
+        };
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let writer = stream.try_clone().unwrap();
+        let mut qmp = Self {
+            reader: BufReader::new(stream),
+            writer,
+            next_id: 0,
+        };
+        if qmp.read_message()?.get("QMP").is_none() {
+            return Err(format!("'{}' is not a QMP socket", path.display()));
+        }
+        qmp.execute("qmp_capabilities", json!({}))?;
+        Ok(qmp)
+    }
+
+    fn read_message(&mut self) -> Result<Value,String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err(String::from("QMP connection closed")),
+            Ok(_) => serde_json::from_str(&line).map_err(|e| format!("Bad QMP message: {}", e)),
+            Err(e) => Err(format!("QMP connection failed: {}", e)),
+        }
+    }
+
+    /// Run a command and return its result.
+    pub fn execute(&mut self, command: &str, arguments: Value) -> Result<Value,String> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = json!({"execute": command, "arguments": arguments, "id": id});
+        if let Err(e) = writeln!(self.writer, "{}", request) {
+            return Err(format!("QMP connection failed: {}", e));
+        }
+        loop {
+            let mut message = self.read_message()?;
+            if message.get("id") != Some(&json!(id)) {
+                // Events, or replies to commands we gave up on.
+                continue;
+            }
+            if let Some(result) = message.get_mut("return") {
+                return Ok(result.take());
+            }
+            let description = message.pointer("/error/desc").and_then(Value::as_str).unwrap_or("no reason given");
+            return Err(format!("QMP command '{}' failed: {}", command, description));
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use crate::test_util::test_dir;
+
+    // Whether a request is as expected, where "*" stands for any value.
+    fn matches(expected: &Value, actual: &Value) -> bool {
+        match (expected, actual) {
+            (Value::String(x), _) if x == "*" => true,
+            (Value::Object(x), Value::Object(y)) => x.len() == y.len() && x.iter().all(|(key, value)| {y.get(key).is_some_and(|other| {matches(value, other)})}),
+            (Value::Array(x), Value::Array(y)) => x.len() == y.len() && x.iter().zip(y).all(|(value, other)| {matches(value, other)}),
+            _ => expected == actual,
+        }
+    }
+
+    /// Play QEMU for a single connection, checking that the commands run
+    /// (after capabilities negotiation) match the given ones, and answering
+    /// each with the given return value, or error description.
+    pub fn mock_server(listener: &UnixListener, script: Vec<(Value, Result<Value,&'static str>)>) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        writeln!(writer, "{}", json!({"QMP": {"version": {}, "capabilities": []}})).unwrap();
+        let capabilities = (json!({"execute": "qmp_capabilities", "arguments": {}}), Ok(json!({})));
+        for (expected, response) in std::iter::once(capabilities).chain(script) {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut request: Value = serde_json::from_str(&line).unwrap();
+            let id = request.as_object_mut().unwrap().remove("id").unwrap();
+            assert!(matches(&expected, &request), "Expected {}, got {}", expected, request);
+            writeln!(writer, "{}", json!({"event": "NOISE", "data": {}})).unwrap();
+            match response {
+                Ok(result) => writeln!(writer, "{}", json!({"return": result, "id": id})).unwrap(),
+                Err(description) => writeln!(writer, "{}", json!({"error": {"class": "GenericError", "desc": description}, "id": id})).unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_execute() {
+        let dir = test_dir("qmp");
+        let path = dir.join("qmp.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            mock_server(&listener, vec![
+                (json!({"execute": "query-status", "arguments": {}}), Ok(json!({"running": true}))),
+                (json!({"execute": "stop", "arguments": {"x": 1}}), Err("Not allowed")),
+            ]);
+        });
+        let mut qmp = Qmp::connect(&path).unwrap();
+        assert_eq!(qmp.execute("query-status", json!({})).unwrap(), json!({"running": true}));
+        assert_eq!(qmp.execute("stop", json!({"x": 1})).unwrap_err(), "QMP command 'stop' failed: Not allowed");
+        server.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
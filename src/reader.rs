@@ -0,0 +1,91 @@
+// Reader threads take chunks to read from a queue, so that several reads
+// (possibly from several sources) can be in progress at once. The copier
+// decides what to read, and gets the chunks back in whatever order the reads
+// finish.
+
+use crossbeam::channel::{Receiver,Sender};
+use crate::chunk::Chunk;
+use crate::device::SourceReader;
+
+/// A run of neighbouring chunks to be read together.
+pub struct ReadRequest {
+    pub device_number: usize,
+    /// The first chunk of the run.
+    pub index: usize,
+    pub chunk_count: usize,
+    pub offset: u64,
+    /// Already capped to the size of the source.
+    pub length: usize,
+}
+
+pub struct ReadResult {
+    pub device_number: usize,
+    pub index: usize,
+    pub chunk_count: usize,
+    pub chunk: std::io::Result<Chunk>,
+}
+
+/// Read until the queue is closed. Sources are indexed by device number,
+/// and are None for those this thread's pool doesn't read.
+pub fn run(sources: Vec<Option<SourceReader>>, read_queue_consume: Receiver<ReadRequest>, chunk_queue_produce: Sender<ReadResult>) {
+    while let Ok(request) = read_queue_consume.recv() {
+        let source = sources[request.device_number].as_ref().expect("Chunk requested from a source outside of the reader's pool");
+        let result = ReadResult {
+            device_number: request.device_number,
+            index: request.index,
+            chunk_count: request.chunk_count,
+            chunk: source.get_chunk(request.offset, request.length),
+        };
+        if chunk_queue_produce.send(result).is_err() {
+            // The copier has given up.
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::ChunkContent;
+    use crate::device::DeviceFile;
+
+    #[test]
+    fn test_run() {
+        let dir = crate::test_util::test_dir("reader");
+        let path = dir.join("source");
+        let data: Vec<u8> = (0..1536).map(|i| {(i / 512) as u8}).collect();
+        std::fs::write(&path, &data).unwrap();
+        let source = DeviceFile::from_path(&path).unwrap();
+
+        let (read_queue_produce, read_queue_consume) = crossbeam::channel::unbounded();
+        let (chunk_queue_produce, chunk_queue_consume) = crossbeam::channel::unbounded();
+        let readers: Vec<std::thread::JoinHandle<()>> = (0..2).map(
+            |_| {
+                let sources = vec![None, Some(source.reader().unwrap())];
+                let read_queue_consume = read_queue_consume.clone();
+                let chunk_queue_produce = chunk_queue_produce.clone();
+                std::thread::spawn(move || {run(sources, read_queue_consume, chunk_queue_produce)})
+            }
+        ).collect();
+        for index in 0..3 {
+            read_queue_produce.send(ReadRequest {device_number: 1, index, chunk_count: 1, offset: index as u64 * 512, length: 512}).unwrap();
+        }
+        drop(read_queue_produce);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let mut indices = Vec::new();
+        for result in chunk_queue_consume.try_iter() {
+            assert_eq!(result.device_number, 1);
+            match result.chunk.unwrap().content {
+                ChunkContent::Data(chunk_data) => assert_eq!(chunk_data, vec![result.index as u8; 512]),
+                _ => panic!("Expected data"),
+            }
+            indices.push(result.index);
+        }
+        indices.sort();
+        assert_eq!(indices, vec![0, 1, 2]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
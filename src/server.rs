@@ -61,8 +61,15 @@ pub fn task_loop(config: &Config, management_interface: &ManagementInterface, in
     let run = |manifest| {
         eprintln!("Starting backup");
         let result = crate::copier::run(config, &manifest, management_interface);
-        if let Err(e) = result {
-            eprintln!("Backup failed: {:?}", e);
+        match &result {
+            Ok(report) => {
+                for failure in &report.failed_jobs {
+                    eprintln!("Job for '{}' failed: {}", failure.source.display(), failure.error);
+                }
+            },
+            Err(e) => {
+                eprintln!("Backup failed: {}", e);
+            },
         }
         Some(LastResult {
             manifest,
@@ -101,7 +108,7 @@ pub fn task_loop(config: &Config, management_interface: &ManagementInterface, in
                             Status::Waiting
                         },
                     };
-                ticket.respond(Response::Query(status));
+                ticket.respond(Response::Query(Box::new(status)));
             },
         }
     }
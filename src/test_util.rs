@@ -0,0 +1,59 @@
+// Fixtures shared by unit tests across the crate.
+
+use std::path::{Path,PathBuf};
+use libc::c_uint;
+use crate::control::{ChangeFilter,Job,JobFailurePolicy,Manifest,ReadMode,SyncMethod,TraceLayer};
+use crate::device::Device;
+
+/// A new, empty directory for a test's files, named after the test.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("trackup-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A whole disk device, which doesn't exist beyond this description.
+pub fn test_device(major: c_uint, minor: c_uint, sector_count: u64) -> Device {
+    Device {
+        dev: 0,
+        event_dev: (major << 20) | minor,
+        major,
+        minor,
+        sys_dev_path: PathBuf::new(),
+        sector_count,
+        start_sector: 0,
+        end_sector: sector_count,
+        logical_block_size: 512,
+        physical_block_size: 512,
+        parent: None,
+        file: None,
+    }
+}
+
+/// A job using the defaults for everything but its paths.
+pub fn test_job(source: &Path, destination: &Path) -> Job {
+    Job {
+        source: source.to_path_buf(),
+        destination: destination.to_path_buf(),
+        chunk_size: 4096,
+        reuse_output: false,
+        trace_layer: TraceLayer::Top,
+        untraced_when_idle: true,
+        qemu: None,
+        nbd_server: None,
+        read_mode: ReadMode::Cached,
+    }
+}
+
+/// A manifest using the defaults for everything but its jobs.
+pub fn test_manifest(jobs: Vec<Job>) -> Manifest {
+    Manifest {
+        jobs,
+        do_sync: true,
+        sync_method: SyncMethod::Filesystems,
+        job_failure_policy: JobFailurePolicy::AbortAll,
+        locking: None,
+        change_filter: ChangeFilter::default(),
+    }
+}
@@ -0,0 +1,292 @@
+// Keeps track of the tracing settings which trackup has changed, so that
+// they can be put back if trackup dies without cleaning up after itself.
+//
+// Every session holds a shared or exclusive flock on the system lock file,
+// depending on whether it changes global tracing settings or only works in
+// its own instance. Each session also journals the original value of every
+// setting it changes (and every instance it creates) to its own state file,
+// which it keeps flocked for as long as it's alive. A state file which can be
+// locked by anyone else therefore belongs to a dead session, and is replayed
+// to restore the original settings.
+
+use std::cell::RefCell;
+use std::fs::{File,OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path,PathBuf};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use nix::fcntl::FlockArg;
+use serde::{Serialize,Deserialize};
+use crate::control::Config;
+use crate::quick_io::append_to_file_at_path;
+
+const LOCK_FILE_NAME: &str = "trackup.lock";
+const SESSION_FILE_PREFIX: &str = "session-";
+// Session state files which aren't in place yet.
+const NEW_FILE_PREFIX: &str = "new-";
+
+// Distinguishes sessions started by successive runs in the same process.
+static NEXT_SESSION_NUMBER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+struct Setting {
+    path: PathBuf,
+    original: String,
+}
+
+#[derive(Clone,Debug,Default,Serialize,Deserialize)]
+struct SessionState {
+    pid: u32,
+    /// In the order they were changed.
+    settings: Vec<Setting>,
+    instances: Vec<PathBuf>,
+}
+
+/// The order to restore settings in. This is the reverse of the order they
+/// were changed in, except that blk trace enables go last, as writing any
+/// other per-device trace attribute sets up a new trace when disabled.
+fn restore_order(settings: &[Setting]) -> Vec<&Setting> {
+    let is_enable = |setting: &&Setting| {setting.path.ends_with("trace/enable")};
+    let mut order: Vec<&Setting> = settings.iter().rev().filter(|setting| {!is_enable(setting)}).collect();
+    order.extend(settings.iter().rev().filter(is_enable));
+    order
+}
+
+fn restore(state: &SessionState) {
+    for setting in restore_order(&state.settings) {
+        if let Err(e) = append_to_file_at_path(&setting.path, setting.original.as_bytes()) {
+            eprintln!("Warning: could not restore tracing setting: {}", e);
+        }
+    }
+    for instance in state.instances.iter().rev() {
+        if instance.exists() {
+            if let Err(e) = std::fs::remove_dir(instance) {
+                eprintln!("Warning: could not remove tracing instance '{}': {}", instance.display(), e);
+            }
+        }
+    }
+}
+
+fn open_state_dir(config: &Config) -> Result<(),String> {
+    if let Err(e) = std::fs::create_dir_all(&config.state_path) {
+        return Err(format!("Could not create state directory '{}': {}", config.state_path.display(), e));
+    }
+    Ok(())
+}
+
+/// Restore the tracing settings left behind by any dead sessions. Returns
+/// how many sessions were recovered.
+pub fn recover(config: &Config) -> Result<usize,String> {
+    open_state_dir(config)?;
+    let entries = match std::fs::read_dir(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(format!("Could not list state directory '{}': {}", config.state_path.display(), e));
+        },
+    };
+    let mut recovered = 0;
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let is_new = file_name.starts_with(NEW_FILE_PREFIX);
+        if !is_new && !file_name.starts_with(SESSION_FILE_PREFIX) {
+            continue;
+        }
+        let path = entry.path();
+        let file = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if nix::fcntl::flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err() {
+            // Still in use by a live session.
+            continue;
+        }
+        if is_new {
+            // Left by a session which died before it could change anything.
+            if let Err(e) = std::fs::remove_file(&path) {
+                return Err(format!("Could not remove unused session state '{}': {}", path.display(), e));
+            }
+            continue;
+        }
+        match serde_json::from_reader::<&File,SessionState>(&file) {
+            Ok(state) => {
+                eprintln!("Recovering tracing settings left by trackup process {}", state.pid);
+                restore(&state);
+            },
+            Err(e) => {
+                // Most likely died whilst first writing it, before changing
+                // anything.
+                eprintln!("Warning: discarding unreadable session state '{}': {}", path.display(), e);
+            },
+        }
+        if let Err(e) = std::fs::remove_file(&path) {
+            return Err(format!("Could not remove recovered session state '{}': {}", path.display(), e));
+        }
+        recovered += 1;
+    }
+    Ok(recovered)
+}
+
+fn write_state(file: &File, state: &SessionState) {
+    let state = serde_json::to_vec(state).unwrap();
+    // Rewritten in place, as replacing the file would lose the lock.
+    file.set_len(0).expect("Could not truncate session state");
+    file.write_all_at(&state, 0).expect("Could not write session state");
+    file.sync_data().expect("Could not sync session state");
+}
+
+/// A live tracing session. Dropping it (after all settings have been put
+/// back) forgets the journal and releases the locks.
+pub struct Session {
+    state_file_path: PathBuf,
+    state_file: File,
+    state: RefCell<SessionState>,
+    _system_lock: File,
+}
+
+impl Session {
+    /// Recover any dead sessions, then start a new one. Sessions which
+    /// change global tracing settings are exclusive.
+    pub fn begin(config: &Config, exclusive: bool) -> Result<Self,String> {
+        let recovered = recover(config)?;
+        if recovered > 0 {
+            eprintln!("Recovered {} stale tracing session(s)", recovered);
+        }
+
+        let lock_path = config.state_path.join(LOCK_FILE_NAME);
+        let system_lock = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&lock_path) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Could not open lock file '{}': {}", lock_path.display(), e));
+            },
+        };
+        let flock_arg = if exclusive {FlockArg::LockExclusiveNonblock} else {FlockArg::LockSharedNonblock};
+        if nix::fcntl::flock(system_lock.as_raw_fd(), flock_arg).is_err() {
+            return Err(String::from(
+                if exclusive {
+                    "Another trackup session is running, and global tracing needs exclusive use"
+                } else {
+                    "Another trackup session is using global tracing"
+                }
+            ));
+        }
+
+        // The state file is created and locked under another name, and only
+        // then given a name which recover() will look at. Otherwise another
+        // session's recover() could lock it first, and remove it as a dead
+        // session's.
+        let file_name = format!("{}{}-{}.json", SESSION_FILE_PREFIX, std::process::id(), NEXT_SESSION_NUMBER.fetch_add(1, Ordering::Relaxed));
+        let new_file_path = config.state_path.join(format!("{}{}", NEW_FILE_PREFIX, file_name));
+        let state_file_path = config.state_path.join(file_name);
+        let state_file = match File::create(&new_file_path) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Could not create session state '{}': {}", new_file_path.display(), e));
+            },
+        };
+        if nix::fcntl::flock(state_file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err() {
+            let _ = std::fs::remove_file(&new_file_path);
+            return Err(format!("Could not lock session state '{}'", new_file_path.display()));
+        }
+        let state = SessionState {
+            pid: std::process::id(),
+            ..SessionState::default()
+        };
+        write_state(&state_file, &state);
+        // The lock belongs to the file, so survives the rename.
+        if let Err(e) = std::fs::rename(&new_file_path, &state_file_path) {
+            let _ = std::fs::remove_file(&new_file_path);
+            return Err(format!("Could not move session state into place at '{}': {}", state_file_path.display(), e));
+        }
+        Ok(Self {
+            state_file_path,
+            state_file,
+            state: RefCell::new(state),
+            _system_lock: system_lock,
+        })
+    }
+
+    fn save(&self) {
+        write_state(&self.state_file, &self.state.borrow());
+    }
+
+    /// Call before changing a setting, with its original value.
+    pub fn record_setting(&self, path: &Path, original: &[u8]) {
+        self.state.borrow_mut().settings.push(Setting {
+            path: path.to_path_buf(),
+            original: String::from_utf8_lossy(original).into_owned(),
+        });
+        self.save();
+    }
+
+    /// Call before creating a tracing instance.
+    pub fn record_instance(&self, path: &Path) {
+        self.state.borrow_mut().instances.push(path.to_path_buf());
+        self.save();
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.state_file_path) {
+            eprintln!("Warning: could not remove session state '{}': {}", self.state_file_path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restore_order() {
+        let setting = |path: &str| {Setting {path: PathBuf::from(path), original: String::from("0\n")}};
+        let settings = vec![
+            setting("/t/current_tracer"),
+            setting("/sys/block/sda/trace/act_mask"),
+            setting("/sys/block/sda/trace/enable"),
+            setting("/sys/block/sdb/trace/act_mask"),
+            setting("/sys/block/sdb/trace/enable"),
+        ];
+        let paths: Vec<&Path> = restore_order(&settings).iter().map(|setting| {setting.path.as_path()}).collect();
+        assert_eq!(paths, vec![
+            Path::new("/sys/block/sdb/trace/act_mask"),
+            Path::new("/sys/block/sda/trace/act_mask"),
+            Path::new("/t/current_tracer"),
+            Path::new("/sys/block/sdb/trace/enable"),
+            Path::new("/sys/block/sda/trace/enable"),
+        ]);
+    }
+
+    #[test]
+    fn test_live_session_not_recovered() {
+        use crate::control::interface::Internalize;
+        let mut config = crate::control::interface::Config::default().internalize().unwrap();
+        config.state_path = crate::test_util::test_dir("session");
+        let session = Session::begin(&config, false).unwrap();
+        assert!(session.state_file_path.exists());
+        assert_eq!(recover(&config).unwrap(), 0);
+        assert!(session.state_file_path.exists());
+        let state_file_path = session.state_file_path.clone();
+        drop(session);
+        assert!(!state_file_path.exists());
+        std::fs::remove_dir_all(&config.state_path).unwrap();
+    }
+
+    #[test]
+    fn test_leftover_new_session_removed() {
+        use crate::control::interface::Internalize;
+        let mut config = crate::control::interface::Config::default().internalize().unwrap();
+        config.state_path = crate::test_util::test_dir("leftover-session");
+        let leftover_path = config.state_path.join(format!("{}{}1-0.json", NEW_FILE_PREFIX, SESSION_FILE_PREFIX));
+        File::create(&leftover_path).unwrap();
+        // One still being set up by a live session.
+        let live_path = config.state_path.join(format!("{}{}2-0.json", NEW_FILE_PREFIX, SESSION_FILE_PREFIX));
+        let live_file = File::create(&live_path).unwrap();
+        nix::fcntl::flock(live_file.as_raw_fd(), FlockArg::LockExclusiveNonblock).unwrap();
+        assert_eq!(recover(&config).unwrap(), 0);
+        assert!(!leftover_path.exists());
+        assert!(live_path.exists());
+        drop(live_file);
+        std::fs::remove_dir_all(&config.state_path).unwrap();
+    }
+}